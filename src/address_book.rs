@@ -0,0 +1,309 @@
+use bitcoin::Network;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    error::Error,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{DnsSeedManager, HandshakeStatus, PeerAddr, PeerVersion};
+
+/// Address Book Error - failed to load or save the address book file
+#[derive(Debug)]
+pub struct AddressBookError;
+
+impl fmt::Display for AddressBookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Address book error: failed to load or save the address book"
+        )
+    }
+}
+
+impl Error for AddressBookError {}
+
+/// Where the address of a peer was learned from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    /// Returned by the DNS seed with the given name
+    Seed(String),
+    /// Gossiped by the peer with the given address
+    Peer(SocketAddr),
+    /// Connected to the local node
+    Inbound,
+    /// Given by the user
+    Manual,
+}
+
+/// Fields of the last `version` message advertised by a peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdvertisedVersion {
+    /// Protocol version
+    pub version: u32,
+    /// Service bits
+    pub services: u64,
+    /// User agent, e.g. `/Satoshi:24.0.1/`
+    pub user_agent: String,
+    /// Height of the best known block
+    pub start_height: i32,
+}
+
+impl From<&PeerVersion> for AdvertisedVersion {
+    fn from(peer: &PeerVersion) -> Self {
+        Self {
+            version: peer.version,
+            services: peer.services.to_u64(),
+            user_agent: peer.user_agent.clone(),
+            start_height: peer.start_height,
+        }
+    }
+}
+
+/// Everything known about a single peer. Times are Unix timestamps in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBookEntry {
+    /// Where the address was learned from the first time
+    pub source: PeerSource,
+    /// When the address was learned
+    pub first_seen: i64,
+    /// When the last handshake was attempted
+    pub last_attempt: Option<i64>,
+    /// When the last handshake completed
+    pub last_success: Option<i64>,
+    /// Number of handshakes that failed in a row since the last completed one
+    pub failure_count: u32,
+    /// Kind of the last handshake failure since the last completed handshake, e.g. `connect_timeout`
+    pub last_error: Option<String>,
+    /// The last `version` advertised by the peer
+    pub last_version: Option<AdvertisedVersion>,
+}
+
+impl AddressBookEntry {
+    fn new(source: PeerSource) -> Self {
+        Self {
+            source,
+            first_seen: chrono::Utc::now().timestamp(),
+            last_attempt: None,
+            last_success: None,
+            failure_count: 0,
+            last_error: None,
+            last_version: None,
+        }
+    }
+}
+
+/// Content of the address book file
+#[derive(Debug, Serialize, Deserialize)]
+struct AddressBookFile {
+    network: String,
+    peers: BTreeMap<PeerAddr, AddressBookEntry>,
+}
+
+/// AddressBook - every peer ever seen on a network, persisted as a JSON file.
+///
+/// The peers are keyed by their `PeerAddr`, written as `ip:port`, `[ipv6]:port`, `<base32>.onion:port` or
+/// `<base32>.b32.i2p:port`. Host names are never kept, the address they resolve to can change.
+/// The file is loaded with `load` and written back with `save`. A missing file is an empty address book.
+#[derive(Debug)]
+pub struct AddressBook {
+    path: PathBuf,
+    network: Network,
+    peers: BTreeMap<PeerAddr, AddressBookEntry>,
+}
+
+impl AddressBook {
+    /// Returns the default path of the address book file of the given `network` in the working directory
+    pub fn default_path(network: Network) -> PathBuf {
+        PathBuf::from(format!("address_book_{network}.json"))
+    }
+
+    /// Loads the address book of the given `network` from the `path`.
+    /// Fails if the file is malformed or belongs to another network.
+    pub fn load(path: impl AsRef<Path>, network: Network) -> Result<Self, AddressBookError> {
+        let path = path.as_ref().to_path_buf();
+        let peers = match fs::read_to_string(&path) {
+            Ok(content) => {
+                let file: AddressBookFile = serde_json::from_str(&content)
+                    .into_report()
+                    .attach_printable_lazy(|| {
+                        format!("Failed to parse address book {}", path.display())
+                    })
+                    .change_context(AddressBookError)?;
+                if file.network != network.to_string() {
+                    return Err(Report::new(AddressBookError).attach_printable(format!(
+                        "Address book {} belongs to network {}, expected {network}",
+                        path.display(),
+                        file.network
+                    )));
+                }
+                file.peers
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(Report::new(e)
+                    .attach_printable(format!("Failed to read address book {}", path.display()))
+                    .change_context(AddressBookError))
+            }
+        };
+
+        Ok(Self {
+            path,
+            network,
+            peers,
+        })
+    }
+
+    /// Writes the address book into its file. The file is replaced atomically.
+    pub fn save(&self) -> Result<(), AddressBookError> {
+        let file = AddressBookFile {
+            network: self.network.to_string(),
+            peers: self.peers.clone(),
+        };
+        let content = serde_json::to_string_pretty(&file)
+            .into_report()
+            .change_context(AddressBookError)?;
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, content)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .into_report()
+            .attach_printable_lazy(|| {
+                format!("Failed to write address book {}", self.path.display())
+            })
+            .change_context(AddressBookError)
+    }
+
+    /// Returns the path of the address book file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds the `addr` learned from the `source`. An already known address keeps its original source.
+    /// Returns `true` if the address is new, `false` for host names, which are not kept.
+    pub fn add(&mut self, addr: impl Into<PeerAddr>, source: PeerSource) -> bool {
+        let addr = addr.into();
+        if let PeerAddr::Name { .. } = addr {
+            return false;
+        }
+        match self.peers.entry(addr) {
+            Entry::Vacant(entry) => {
+                entry.insert(AddressBookEntry::new(source));
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Returns the address a peer that connected from the `remote` address accepts connections on:
+    /// the `sender` address of its `version`, if it has the IP of the connection and a port other than
+    /// the source port, else the IP with the default port of the network.
+    /// The source port of an inbound connection is ephemeral and can not be dialed.
+    pub fn inbound_addr(&self, remote: SocketAddr, sender: Option<SocketAddr>) -> SocketAddr {
+        let remote_ip = remote.ip().to_canonical();
+        match sender {
+            Some(sender)
+                if sender.ip().to_canonical() == remote_ip
+                    && sender.port() != 0
+                    && sender.port() != remote.port() =>
+            {
+                SocketAddr::new(remote_ip, sender.port())
+            }
+            _ => SocketAddr::new(remote_ip, DnsSeedManager::default_port(self.network)),
+        }
+    }
+
+    /// Records the `status` of a handshake with the `addr` that was attempted now.
+    /// An unknown address is added as given by the user, host names are skipped.
+    pub fn record_status(&mut self, addr: impl Into<PeerAddr>, status: &HandshakeStatus) {
+        let addr = addr.into();
+        if let PeerAddr::Name { .. } = addr {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp();
+        let entry = self
+            .peers
+            .entry(addr)
+            .or_insert_with(|| AddressBookEntry::new(PeerSource::Manual));
+        entry.last_attempt = Some(now);
+        match status {
+            HandshakeStatus::Completed(outcome) => {
+                entry.last_success = Some(now);
+                entry.last_version = Some(AdvertisedVersion::from(&outcome.peer));
+                entry.failure_count = 0;
+                entry.last_error = None;
+            }
+            HandshakeStatus::Failed(kind) => {
+                entry.failure_count += 1;
+                entry.last_error = Some(kind.to_string());
+            }
+        }
+    }
+
+    /// Records all handshake `statuses`, e.g. the ones of `HandshakeManager::statuses`
+    pub fn record_statuses(&mut self, statuses: &HashMap<PeerAddr, HandshakeStatus>) {
+        for (addr, status) in statuses.iter() {
+            self.record_status(addr.clone(), status);
+        }
+    }
+
+    /// Returns the entry of the `addr`
+    pub fn get(&self, addr: &PeerAddr) -> Option<&AddressBookEntry> {
+        self.peers.get(addr)
+    }
+
+    /// Returns all known peers ordered by address
+    pub fn peers(&self) -> &BTreeMap<PeerAddr, AddressBookEntry> {
+        &self.peers
+    }
+
+    /// Returns the number of known peers
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns `true` if no peers are known
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Chooses up to `count` peers to connect to: the most recently successful ones first,
+    /// then the never attempted ones, then the failed ones with the fewest failures.
+    pub fn select_peers(&self, count: usize) -> Vec<PeerAddr> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(addr, entry)| {
+            (
+                std::cmp::Reverse(entry.last_success),
+                entry.last_attempt.is_some(),
+                entry.failure_count,
+                *addr,
+            )
+        });
+        peers
+            .into_iter()
+            .take(count)
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Chooses up to `count` IP peers to check again: the never attempted ones first,
+    /// then the ones attempted the longest time ago. Tor and I2P peers are not chosen, the crawler
+    /// and the DNS seeder only handle IP peers.
+    pub fn select_stale_peers(&self, count: usize) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter_map(|(addr, entry)| Some((addr.socket_addr()?, entry)))
+            .collect();
+        peers.sort_by_key(|(addr, entry)| (entry.last_attempt, *addr));
+        peers
+            .into_iter()
+            .take(count)
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+}
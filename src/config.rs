@@ -1,68 +1,389 @@
-use std::fmt;
-use std::error::Error;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::Network;
+use clap::{ArgGroup, Args, Parser, Subcommand};
 use error_stack::{IntoReport, Report, Result, ResultExt};
-use log::{info, error};
+use futures::future;
+use log::{error, info};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::TcpListener;
 
-use crate::{DnsSeedManager, HandshakeManager};
+use crate::{
+    transport::report_reason, AddressBook, AddressBookRecord, AddressRecord, CrawlLimits,
+    CrawlRecord, Crawler, DnsSeedManager, DnsSeedSettings, DnsSeeder, HandshakeError,
+    HandshakeFeatures, HandshakeManager, HandshakeOutcome, HandshakeRecord, HandshakeStatus,
+    NetworkMap, OutputFormat, PeerAddr, PeerSource, ProxyCredentials, ProxySettings, SeedChecker,
+    SeedHealthRecord, SeedRecord, SeederSettings, Target, VersionParams,
+};
 
-const CLI_COMMAND_LIST_DNS_RESOLVERS: &str = "-l";
-const CLI_COMMAND_RESOLVE_PEER_URLS: &str = "-r";
-const CLI_COMMAND_HANDSHAKE_BY_INDEX: &str = "-hbi";
-const CLI_COMMAND_HANDSHAKE_BY_URL: &str = "-hbu";
+const DEFAULT_SCAN_CONCURRENCY: usize = 32;
 
-/// CLI argument parser and command handler
-///
-/// Supported arguments:
-///
-/// `-l` - Prints a list of available DNS resolvers.
-/// 
-///        Example output:
-/// 
-///             `cargo run -- -l`
-///             
-///             0 - https://dns-resolver-url-0.com
-///             1 - https://dns-resolver-url-1.com
-///             2 - https://dns-resolver-url-2.com
+/// CLI argument parser
 ///
-/// `-r <DNS URL>` - Resolves remote peer URLs by specified DNS URL.
+/// Every command is a subcommand, the options are accepted before or after it:
 ///
-/// `-hbi <REMOTE PEER URL>` - Performs a handshake with a specified peer.
-/// 
-///       `cargo run -- -r {DNS URL}`
+/// ```text
+///     cargo run -- seeds list
+///     cargo run -- seeds resolve 0
+///     cargo run -- handshake 87.244.68.246:8333 node.example.org [2001:db8::1]
+///     cargo run -- handshake --targets-file targets.txt
+///     cargo run -- handshake --seed 0 --node 3
+///     cargo run -- scan all --concurrency 128 --output csv
+///     cargo run -- crawl all --max-depth 2 --max-peers 5000
+///     cargo run -- listen 127.0.0.1:8333
+///     cargo run -- book list
+///     cargo run -- book handshake 50
+/// ```
 ///
-/// `-hbu <DNS URL INDEX> <REMOTE PEER URL INDEX>` - Performs a handshake 
-///       with remote peer by specified URL index.
-///       Index corresponds to the URL index in the list of resolved URLs.
-///       List of resolved URLs can be obtained by running:
-///
-///           `cargo run -- -r <DNS URL>`
-#[derive(Debug)]
+/// Run with `--help` for the description of every command and option.
+#[derive(Debug, Parser)]
+#[command(name = "p2p-node-handshake", version, about = "Bitcoin P2P handshake, scan and crawl tool", long_about = None)]
 pub struct Config {
-    pub command: String,
-    pub arguments: Vec<String>,
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Bitcoin network: mainnet, testnet, signet or regtest.
+    /// Selects the DNS seeds, the default P2P port and the message magic
+    #[arg(long, global = true, default_value = "mainnet", value_parser = parse_network)]
+    pub network: Network,
+
+    /// Format of the records printed to the standard output
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Path of the address book file [default: address_book_<NETWORK>.json]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub address_book: Option<PathBuf>,
+
+    /// Maximum number of handshakes running at the same time
+    #[arg(long, global = true, value_name = "N", default_value_t = DEFAULT_SCAN_CONCURRENCY, value_parser = parse_positive)]
+    pub concurrency: usize,
+
+    #[command(flatten)]
+    pub dns: DnsArgs,
+
+    #[command(flatten)]
+    pub handshake: HandshakeArgs,
+
+    #[command(flatten)]
+    pub proxy: ProxyArgs,
 }
 
-#[derive(Debug)]
-pub struct ConfigError;
-impl fmt::Display for ConfigError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Config error")
+/// Commands of the CLI
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Lists the DNS seeds or resolves the nodes of the DNS seeds
+    #[command(subcommand)]
+    Seeds(SeedsCommand),
+    /// Performs handshakes with the given nodes
+    Handshake(HandshakeTargets),
+    /// Resolves the nodes of one or all DNS seeds, performs handshakes with them concurrently and prints a summary
+    Scan {
+        /// Index of the DNS seed, see `seeds list`, or `all`
+        seed: SeedSelection,
+    },
+    /// Crawls the network from the nodes of one or all DNS seeds by following the `getaddr` responses
+    Crawl {
+        /// Index of the DNS seed, see `seeds list`, or `all`
+        seed: SeedSelection,
+        /// Number of gossip hops followed from the seeds
+        #[arg(long, value_name = "N", default_value_t = CrawlLimits::default().max_depth)]
+        max_depth: usize,
+        /// Maximum number of handshakes of the crawl
+        #[arg(long, value_name = "N", default_value_t = CrawlLimits::default().max_peers, value_parser = parse_positive)]
+        max_peers: usize,
+    },
+    /// Listens for inbound connections and performs the inbound handshake with every peer that connects,
+    /// running at most `--concurrency` inbound handshakes at a time
+    Listen {
+        /// Address to bind to [default: 0.0.0.0 on the default port of the network]
+        bind: Option<SocketAddr>,
+    },
+    /// Shows the address book or performs handshakes with the peers chosen from it
+    #[command(subcommand)]
+    Book(BookCommand),
+    /// Runs a DNS seeder: performs handshakes with the peers of the address book in rounds
+    /// and serves the recently verified ones as the A and AAAA records of the zone
+    Seeder {
+        /// Zone served by the seeder, e.g. seed.example.org
+        zone: String,
+        /// UDP address of the DNS server
+        #[arg(long, value_name = "ADDRESS", default_value = "0.0.0.0:53")]
+        dns_bind: SocketAddr,
+        /// Seconds between the rounds of handshakes
+        #[arg(long, value_name = "SECONDS", default_value_t = 60, value_parser = parse_positive)]
+        interval: usize,
+        /// Number of peers handshaked every round, the never or least recently attempted ones first
+        #[arg(long, value_name = "N", default_value_t = 256, value_parser = parse_positive)]
+        batch: usize,
+        /// Seconds a node is served after its last completed handshake
+        #[arg(long, value_name = "SECONDS", default_value_t = 3600, value_parser = parse_positive)]
+        max_age: usize,
+    },
+}
+
+/// Subcommands of `seeds`
+#[derive(Debug, Subcommand)]
+pub enum SeedsCommand {
+    /// Prints the DNS seeds of the network with their indexes
+    List,
+    /// Resolves the node addresses of one or all DNS seeds
+    Resolve {
+        /// Index of the DNS seed, see `seeds list`, or `all`
+        seed: SeedSelection,
+    },
+    /// Resolves the DNS seeds, performs handshakes with a sample of the nodes of every seed and prints
+    /// the number of addresses, the IPv4/IPv6 split, the reachable share, the median latency and the protocol versions
+    Check {
+        /// Index of the DNS seed, see `seeds list`, or `all`
+        #[arg(default_value = "all")]
+        seed: SeedSelection,
+        /// Maximum number of nodes of every seed to perform handshakes with
+        #[arg(long, value_name = "N", default_value_t = 10, value_parser = parse_positive)]
+        sample: usize,
+    },
+}
+
+/// Subcommands of `book`
+#[derive(Debug, Subcommand)]
+pub enum BookCommand {
+    /// Prints every peer of the address book
+    List,
+    /// Performs handshakes with the best peers of the address book:
+    /// the most recently successful ones first, then the never attempted ones
+    Handshake {
+        /// Maximum number of peers
+        #[arg(value_parser = parse_positive)]
+        count: usize,
+    },
+}
+
+/// Nodes of the `handshake` command, given as targets, in a targets file or by the DNS seed and node indexes
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("target").required(true).multiple(true).args(["targets", "targets_file", "seed"])))]
+pub struct HandshakeTargets {
+    /// Nodes given as host[:port], IPv4 or IPv6 address, e.g. 87.244.68.246:8333, node.example.org or 2001:db8::1.
+    /// Tor v3 .onion and I2P .b32.i2p addresses need --proxy. The port defaults to the P2P port of the network
+    #[arg(value_name = "TARGET", value_parser = parse_target)]
+    pub targets: Vec<Target>,
+    /// File with one target per line, empty lines and lines starting with # are skipped
+    #[arg(long, value_name = "PATH")]
+    pub targets_file: Option<PathBuf>,
+    /// Index of the DNS seed, see `seeds list`
+    #[arg(long, value_name = "INDEX", requires = "node", conflicts_with_all = ["targets", "targets_file"])]
+    pub seed: Option<usize>,
+    /// Index of the node resolved from the DNS seed, see `seeds resolve`
+    #[arg(long, value_name = "INDEX", requires = "seed")]
+    pub node: Option<usize>,
+}
+
+/// DNS seeds selected by a command argument: a single seed by index, or all seeds of the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedSelection {
+    All,
+    Index(usize),
+}
+
+impl FromStr for SeedSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(SeedSelection::All),
+            _ => s
+                .parse()
+                .map(SeedSelection::Index)
+                .map_err(|_| "expected a DNS seed index or `all`".to_owned()),
+        }
     }
 }
-impl Error for ConfigError {}
 
+/// Options of the DNS seed lookups. Unset options keep the defaults of `DnsSeedSettings`.
+#[derive(Debug, Clone, Default, Args)]
+pub struct DnsArgs {
+    /// DNS seed to query instead of the default seeds of the network, may be repeated
+    #[arg(long = "dns-seed", global = true, value_name = "HOST")]
+    pub dns_seeds: Vec<String>,
+    /// File with one DNS seed per line, used instead of the default seeds of the network.
+    /// Empty lines and lines starting with # are skipped
+    #[arg(long, global = true, value_name = "PATH")]
+    pub dns_seeds_file: Option<PathBuf>,
+    /// Nameserver to send the DNS seed queries to, given as IP[:PORT] [default: system resolver]
+    #[arg(long, global = true, value_name = "IP[:PORT]", value_parser = parse_nameserver)]
+    pub nameserver: Option<SocketAddr>,
+    /// Timeout of the lookup of a single DNS seed in milliseconds [default: 5000]
+    #[arg(long, global = true, value_name = "MS", value_parser = parse_millis)]
+    pub dns_timeout: Option<Duration>,
+    /// Services the nodes returned by the DNS seeds should advertise, in the `--services` format.
+    /// Seeds are queried with x<hex flags>.<seed> names, and without the filter if a seed does not support it
+    #[arg(long, global = true, value_name = "FLAGS", value_parser = parse_services)]
+    pub seed_services: Option<ServiceFlags>,
+}
 
-/// ConfigBuild error
-#[derive(Debug)]
-pub struct ConfigBuildError;
+impl DnsArgs {
+    /// Creates the DnsSeedSettings of the `network` with the given seeds, nameserver, timeout and services
+    pub fn dns_settings(&self, network: Network) -> Result<DnsSeedSettings, ConfigError> {
+        let mut settings = DnsSeedSettings::new(network);
 
-impl fmt::Display for ConfigBuildError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Config build error")
+        let mut seeds = self.dns_seeds.clone();
+        if let Some(path) = &self.dns_seeds_file {
+            seeds.extend(DnsSeedSettings::read_seeds_file(path).change_context(ConfigError)?);
+        }
+        if !seeds.is_empty() {
+            settings.seeds = seeds;
+        }
+        settings.nameserver = self.nameserver.or(settings.nameserver);
+        settings.timeout = self.dns_timeout.unwrap_or(settings.timeout);
+        settings.services = self.seed_services.unwrap_or(settings.services);
+        Ok(settings)
+    }
+}
+
+/// Options of the SOCKS5 proxy of the outbound connections and the DNS seed lookups
+#[derive(Debug, Clone, Default, Args)]
+pub struct ProxyArgs {
+    /// SOCKS5 proxy of the outbound connections and the DNS seed lookups, given as IP[:PORT] [default port: 9050].
+    /// Seeds are resolved by the proxy, or with the --nameserver over TCP through the proxy
+    #[arg(long, global = true, value_name = "IP[:PORT]", value_parser = parse_proxy)]
+    pub proxy: Option<SocketAddr>,
+    /// Username sent to the proxy, ignored without --proxy
+    #[arg(long, global = true, value_name = "USER")]
+    pub proxy_user: Option<String>,
+    /// Password sent to the proxy, ignored without --proxy-user [default: empty]
+    #[arg(long, global = true, value_name = "PASSWORD")]
+    pub proxy_password: Option<String>,
+    /// Sends random proxy credentials with every connection, so that Tor uses a separate circuit for each of them
+    #[arg(long, global = true)]
+    pub proxy_isolate: bool,
+}
+
+impl ProxyArgs {
+    /// Creates the ProxySettings with the given credentials and stream isolation, `None` without a proxy
+    pub fn proxy_settings(&self) -> Option<ProxySettings> {
+        let mut settings = ProxySettings::new(self.proxy?);
+        settings.credentials = self.proxy_user.as_ref().map(|username| ProxyCredentials {
+            username: username.clone(),
+            password: self.proxy_password.clone().unwrap_or_default(),
+        });
+        settings.isolate_streams = self.proxy_isolate;
+        Some(settings)
     }
 }
 
-impl Error for ConfigBuildError {}
+/// Options of the outgoing handshakes: phase timeouts, `version` message fields, acceptance policy and features.
+/// Unset options keep the defaults of the `HandshakeManager`.
+#[derive(Debug, Clone, Default, Args)]
+pub struct HandshakeArgs {
+    /// Timeout of the TCP connection in milliseconds [default: 2000]
+    #[arg(long, global = true, value_name = "MS", value_parser = parse_millis)]
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of the `version` exchange in milliseconds [default: 2000]
+    #[arg(long, global = true, value_name = "MS", value_parser = parse_millis)]
+    pub version_timeout: Option<Duration>,
+    /// Timeout of the `verack` exchange in milliseconds [default: 2000]
+    #[arg(long, global = true, value_name = "MS", value_parser = parse_millis)]
+    pub verack_timeout: Option<Duration>,
+
+    /// Protocol version of the outgoing `version` message [default: 70016]
+    #[arg(long, global = true, value_name = "VERSION")]
+    pub protocol_version: Option<u32>,
+    /// Services of the outgoing `version` message: a number (decimal or 0x hex) or a comma separated list of
+    /// network, getutxo, bloom, witness, compact_filters, network_limited, or none [default: none]
+    #[arg(long, global = true, value_name = "FLAGS", value_parser = parse_services)]
+    pub services: Option<ServiceFlags>,
+    /// BIP14 user agent of the outgoing `version` message, e.g. /Satoshi:24.0.1/
+    #[arg(long, global = true, value_name = "USER AGENT", value_parser = parse_user_agent)]
+    pub user_agent: Option<String>,
+    /// Start height of the outgoing `version` message [default: 0]
+    #[arg(long, global = true, value_name = "HEIGHT")]
+    pub start_height: Option<i32>,
+    /// Relay flag of the outgoing `version` message [default: false]
+    #[arg(long, global = true)]
+    pub relay: Option<bool>,
+
+    /// The oldest protocol version accepted from the remote peer [default: 31800]
+    #[arg(long, global = true, value_name = "VERSION")]
+    pub min_protocol_version: Option<u32>,
+    /// Services the remote peer must advertise, in the `--services` format
+    #[arg(long, global = true, value_name = "FLAGS", value_parser = parse_services)]
+    pub required_services: Option<ServiceFlags>,
+    /// Maximum difference between the remote and the local clock in seconds [default: unlimited]
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub max_clock_skew: Option<u64>,
+
+    /// Comma separated list of wtxidrelay and sendaddrv2, or none, announced when both peers support protocol
+    /// version 70016 [default: sendaddrv2]
+    #[arg(long, global = true, value_parser = parse_features)]
+    pub features: Option<HandshakeFeatures>,
+    /// Offers the BIP324 encrypted v2 transport, falling back to v1 when the remote peer does not support it
+    #[arg(long, global = true)]
+    pub v2_transport: bool,
+}
+
+impl HandshakeArgs {
+    /// Creates a HandshakeManager for the `network` with the given timeouts, version message fields, policy, features
+    /// and transport
+    pub fn handshake_manager(&self, network: Network) -> HandshakeManager {
+        let mut handshake_manager = HandshakeManager::new(network);
+
+        let mut timeouts = handshake_manager.timeouts();
+        timeouts.connect = self.connect_timeout.unwrap_or(timeouts.connect);
+        timeouts.version = self.version_timeout.unwrap_or(timeouts.version);
+        timeouts.verack = self.verack_timeout.unwrap_or(timeouts.verack);
+        handshake_manager.set_timeouts(timeouts);
+
+        let mut version_params = handshake_manager.version_params().clone();
+        if let Some(protocol_version) = self.protocol_version {
+            version_params = version_params.with_protocol_version(protocol_version);
+        }
+        if let Some(services) = self.services {
+            version_params = version_params.with_services(services);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            // Validated by `parse_user_agent`
+            if let Ok(params) = version_params.clone().with_user_agent(user_agent) {
+                version_params = params;
+            }
+        }
+        if let Some(start_height) = self.start_height {
+            version_params = version_params.with_start_height(start_height);
+        }
+        if let Some(relay) = self.relay {
+            version_params = version_params.with_relay(relay);
+        }
+        handshake_manager.set_version_params(version_params);
+
+        let mut policy = handshake_manager.policy();
+        policy.min_protocol_version = self
+            .min_protocol_version
+            .unwrap_or(policy.min_protocol_version);
+        policy.required_services = self.required_services.unwrap_or(policy.required_services);
+        policy.max_clock_skew = self
+            .max_clock_skew
+            .map(Duration::from_secs)
+            .or(policy.max_clock_skew);
+        handshake_manager.set_policy(policy);
+
+        if let Some(features) = self.features {
+            handshake_manager.set_features(features);
+        }
+        handshake_manager.set_v2_transport(self.v2_transport);
+        handshake_manager
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError;
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Config error")
+    }
+}
+impl Error for ConfigError {}
 
 /// Config Run Error
 #[derive(Debug)]
@@ -76,125 +397,636 @@ impl fmt::Display for ConfigRunError {
 
 impl Error for ConfigRunError {}
 
-
 impl Config {
-    /// Collects CLI arguments and returns a Config struct
-    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, ConfigError> {
-        // skip the program name
-        args.next(); 
-
-        let command = match args.next() {
-            Some(arg) => arg,
-            None => {
-                return Err(Report::new(ConfigBuildError)
-                    .attach_printable("Not command specified")
+    /// Returns the path of the address book file, the default one of the network if not given
+    pub fn address_book_path(&self) -> PathBuf {
+        self.address_book
+            .clone()
+            .unwrap_or_else(|| AddressBook::default_path(self.network))
+    }
+
+    /// Loads the configured address book
+    fn load_address_book(&self) -> Result<AddressBook, ConfigError> {
+        AddressBook::load(self.address_book_path(), self.network).change_context(ConfigError)
+    }
+
+    /// Returns the configured DNS seed settings of the network
+    pub fn dns_settings(&self) -> Result<DnsSeedSettings, ConfigError> {
+        let mut settings = self.dns.dns_settings(self.network)?;
+        settings.proxy = self.proxy.proxy_settings();
+        Ok(settings)
+    }
+
+    /// Creates a HandshakeManager with the configured network, handshake options and proxy
+    fn handshake_manager(&self) -> HandshakeManager {
+        let mut handshake_manager = self.handshake.handshake_manager(self.network);
+        handshake_manager.set_proxy(self.proxy.proxy_settings());
+        handshake_manager
+    }
+
+    /// Resolves the `targets` given on the command line and in the targets file.
+    /// Targets that can not be resolved are logged and skipped.
+    async fn resolve_targets(
+        &self,
+        targets: &HandshakeTargets,
+    ) -> Result<Vec<PeerAddr>, ConfigError> {
+        let mut all_targets = targets.targets.clone();
+        if let Some(path) = &targets.targets_file {
+            all_targets.extend(Target::read_file(path).change_context(ConfigError)?);
+        }
+
+        let default_port = DnsSeedManager::default_port(self.network);
+        let proxy = self.proxy.proxy_settings();
+        let resolved = future::join_all(
+            all_targets
+                .iter()
+                .map(|target| target.resolve_peer(default_port, proxy.as_ref())),
+        )
+        .await;
+        let mut remotes = Vec::new();
+        for (target, result) in all_targets.iter().zip(resolved) {
+            match result {
+                Ok(remote) if !remotes.contains(&remote) => remotes.push(remote),
+                Ok(_) => {}
+                Err(e) => error!("Skip target {target}: \n{e:?}"),
+            }
+        }
+
+        if remotes.is_empty() {
+            return Err(Report::new(ConfigRunError)
+                .attach_printable("None of the targets could be resolved")
+                .change_context(ConfigError));
+        }
+        Ok(remotes)
+    }
+
+    /// Resolves the nodes of the selected DNS seed, or of all DNS seeds concurrently
+    async fn resolve_seeds(&self, seed: SeedSelection) -> Result<DnsSeedManager, ConfigError> {
+        let settings = self.dns_settings()?;
+        match seed {
+            SeedSelection::All => {
+                Ok(DnsSeedManager::new_with_all_dns(self.network, &settings).await)
+            }
+            SeedSelection::Index(dns_index) => {
+                DnsSeedManager::new_with_dns_index(self.network, &settings, dns_index)
+                    .await
                     .change_context(ConfigError)
-                );
+            }
+        }
+    }
+}
+
+/// Converts a value into a positive number
+fn parse_positive(value: &str) -> std::result::Result<usize, String> {
+    match value.parse() {
+        Ok(number) if number > 0 => Ok(number),
+        _ => Err("expected a positive number".to_owned()),
+    }
+}
+
+/// Converts a value given in milliseconds into a `Duration`
+fn parse_millis(value: &str) -> std::result::Result<Duration, String> {
+    parse_positive(value).map(|millis| Duration::from_millis(millis as u64))
+}
+
+/// Converts a number (decimal or `0x` hex) or a comma separated list of service names into `ServiceFlags`.
+/// `none` or an empty value are no services.
+fn parse_services(value: &str) -> std::result::Result<ServiceFlags, String> {
+    if is_none(value) {
+        return Ok(ServiceFlags::NONE);
+    }
+    if let Some(hex) = value.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16)
+            .map(ServiceFlags::from)
+            .map_err(|e| format!("invalid hex number: {e}"));
+    }
+    if let Ok(bits) = value.parse::<u64>() {
+        return Ok(ServiceFlags::from(bits));
+    }
+
+    value.split(',').try_fold(ServiceFlags::NONE, |services, name| {
+        let flag = match name.trim() {
+            "network" => ServiceFlags::NETWORK,
+            "getutxo" => ServiceFlags::GETUTXO,
+            "bloom" => ServiceFlags::BLOOM,
+            "witness" => ServiceFlags::WITNESS,
+            "compact_filters" => ServiceFlags::COMPACT_FILTERS,
+            "network_limited" => ServiceFlags::NETWORK_LIMITED,
+            _ => {
+                return Err(format!(
+                    "unknown service {name:?}, expected none or one of network, getutxo, bloom, witness, compact_filters, network_limited"
+                ))
             }
         };
+        Ok(services | flag)
+    })
+}
+
+/// Checks that the value is a BIP14 user agent
+fn parse_user_agent(value: &str) -> std::result::Result<String, String> {
+    VersionParams::new()
+        .with_user_agent(value)
+        .map(|_| value.to_owned())
+        .map_err(|e| report_reason(&e))
+}
 
-        let mut arguments = Vec::new();
+/// Converts a value into a handshake `Target`
+fn parse_target(value: &str) -> std::result::Result<Target, String> {
+    value.parse::<Target>().map_err(|e| report_reason(&e))
+}
+
+/// Converts an `IP[:PORT]` value into the socket address of a nameserver, the port defaults to 53
+fn parse_nameserver(value: &str) -> std::result::Result<SocketAddr, String> {
+    parse_ip_with_port(value, 53)
+}
+
+/// Converts an IP address with an optional port into the SocketAddr of a proxy, port 9050 of Tor by default
+fn parse_proxy(value: &str) -> std::result::Result<SocketAddr, String> {
+    parse_ip_with_port(value, 9050)
+}
+
+/// Converts an `IP[:PORT]` value into a SocketAddr with the `default_port` if not given
+fn parse_ip_with_port(value: &str, default_port: u16) -> std::result::Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip = value
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(value);
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| "expected an IPv4 or IPv6 address with an optional port".to_owned())
+}
 
-        while let Some(arg) = args.next() {
-            arguments.push(arg);
+/// Converts a comma separated list of feature message names into `HandshakeFeatures`
+fn parse_features(value: &str) -> std::result::Result<HandshakeFeatures, String> {
+    let mut features = HandshakeFeatures::default();
+    if is_none(value) {
+        return Ok(features);
+    }
+    for name in value.split(',') {
+        match name.trim() {
+            "wtxidrelay" => features.wtxid_relay = true,
+            "sendaddrv2" => features.addr_v2 = true,
+            _ => {
+                return Err(format!(
+                    "unknown feature {name:?}, expected none or one of wtxidrelay, sendaddrv2"
+                ))
+            }
         }
+    }
+    Ok(features)
+}
 
-        Ok(Config {
-            command,
-            arguments
-        })
+/// Returns `true` for the values of the list options that select nothing: `none` or an empty value
+fn is_none(value: &str) -> bool {
+    matches!(value.trim(), "" | "none")
+}
+
+/// Converts a network name into `bitcoin::Network`
+fn parse_network(name: &str) -> std::result::Result<Network, String> {
+    match name {
+        "mainnet" | "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" | "testnet3" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err("expected one of mainnet, testnet, signet, regtest".to_owned()),
+    }
+}
+
+/// Adds the active nodes of the `dsm` to the `address_book` with the DNS seed that returned them
+fn add_seed_nodes(address_book: &mut AddressBook, dsm: &DnsSeedManager) {
+    for node in dsm.active_nodes.iter() {
+        let seed = dsm.source(node).unwrap_or_default().to_owned();
+        address_book.add(*node, PeerSource::Seed(seed));
+    }
+}
+
+/// Adds the peers found by a crawl to the `address_book` with the peer that gossiped them
+fn add_crawled_peers(address_book: &mut AddressBook, map: &NetworkMap) {
+    for (addr, peer) in map.peers.iter() {
+        if let Some(source) = peer.source {
+            address_book.add(*addr, PeerSource::Peer(source));
+        }
     }
 }
 
-/// Converts a string representation of a config into a number
-fn argument_to_number(args: &Vec<String>, i: usize) -> Result<usize, ConfigError> {
-    let Some(dns_index) = args.get(i) else {
-        return Err(Report::new(ConfigError)
-            .attach_printable(format!("Argument at index 0 is not found")));
-    };
+/// Logs the details of a successful handshake
+fn log_handshake_outcome(outcome: &HandshakeOutcome) {
+    let peer = &outcome.peer;
+    info!(
+        "Remote peer {}: version {}, services {}, user agent {:?}, start height {}, relay {}",
+        outcome.remote_addr,
+        peer.version,
+        peer.services,
+        peer.user_agent,
+        peer.start_height,
+        peer.relay
+    );
+    info!(
+        "Negotiated version {} over {} transport, features: wtxidrelay {}, sendaddrv2 {}",
+        outcome.negotiated_version,
+        outcome.transport,
+        outcome.negotiated_features.wtxid_relay,
+        outcome.negotiated_features.addr_v2
+    );
+    info!(
+        "Timings: connect {:?}, version {:?}, verack {:?}, total {:?}",
+        outcome.timings.connect,
+        outcome.timings.version,
+        outcome.timings.verack,
+        outcome.timings.total
+    );
+}
+
+/// Prints the results of a scan, fastest completed handshakes first
+fn print_scan_summary<A: Into<PeerAddr> + Clone + Ord>(
+    output: OutputFormat,
+    results: &mut [(A, Result<HandshakeOutcome, HandshakeError>)],
+) -> Result<(), ConfigError> {
+    results.sort_by_key(|(remote, result)| match result {
+        Ok(outcome) => (false, outcome.timings.total, remote.clone()),
+        Err(_) => (true, Duration::ZERO, remote.clone()),
+    });
+    let records: Vec<_> = results
+        .iter()
+        .map(|(remote, result)| HandshakeRecord::new(remote.clone(), result))
+        .collect();
+    output.print(&records).change_context(ConfigError)?;
 
-    dns_index.parse()
-        .into_report()
-        .attach_printable_lazy(|| format!("Could not convert String to usize: {}", dns_index))
-        .change_context(ConfigError)
+    if output == OutputFormat::Text {
+        let completed = results.iter().filter(|(_, result)| result.is_ok()).count();
+        println!(
+            "Scanned {} peers: {} completed, {} failed",
+            results.len(),
+            completed,
+            results.len() - completed
+        );
+    }
+    Ok(())
+}
+
+/// Prints the peers of the address book, ordered by address
+fn print_address_book(output: OutputFormat, address_book: &AddressBook) -> Result<(), ConfigError> {
+    output
+        .print(&AddressBookRecord::list(address_book))
+        .change_context(ConfigError)?;
+
+    if output == OutputFormat::Text {
+        println!(
+            "{} peers in {}",
+            address_book.len(),
+            address_book.path().display()
+        );
+    }
+    Ok(())
+}
+
+/// Prints the peers found by a crawl, ordered by depth
+fn print_network_map(output: OutputFormat, map: &NetworkMap) -> Result<(), ConfigError> {
+    let mut peers: Vec<_> = map.peers.iter().collect();
+    peers.sort_by_key(|(addr, peer)| (peer.depth, **addr));
+    let records: Vec<_> = peers
+        .into_iter()
+        .map(|(addr, peer)| CrawlRecord::new(*addr, peer))
+        .collect();
+    output.print(&records).change_context(ConfigError)?;
+
+    if output == OutputFormat::Text {
+        let attempted = map
+            .peers
+            .values()
+            .filter(|peer| peer.status.is_some())
+            .count();
+        println!(
+            "Found {} peers: {} attempted, {} reachable",
+            map.peers.len(),
+            attempted,
+            map.reachable().len()
+        );
+    }
+    Ok(())
 }
 
-/// Runs the handshake in accordance with the provided configuration.
-/// Returns result that represents the status of the handshake.
+/// Runs the command in accordance with the provided configuration.
+/// Returns result that represents the status of the command.
 pub async fn run(config: &Config) -> Result<(), ConfigError> {
-    match config.command.as_str() {
-        CLI_COMMAND_LIST_DNS_RESOLVERS => {
+    match &config.command {
+        Command::Seeds(SeedsCommand::List) => {
             info!("DNS Resolvers:");
-            DnsSeedManager::print_default_dns_seeds();
-        },
-        CLI_COMMAND_RESOLVE_PEER_URLS => {
+            let records: Vec<_> = config
+                .dns_settings()?
+                .seeds
+                .iter()
+                .enumerate()
+                .map(|(index, seed)| SeedRecord {
+                    index,
+                    seed: seed.to_string(),
+                })
+                .collect();
+            config.output.print(&records).change_context(ConfigError)?;
+        }
+        Command::Seeds(SeedsCommand::Resolve { seed }) => {
             info!("Active IP node URLs:");
-            let dns_index = argument_to_number(&config.arguments, 0)?;
-            let dsm = DnsSeedManager::new_with_dns_index(dns_index).await
-            .change_context(ConfigError)?;
-            dsm.print_resolved_remote_urls();
-        },
-        CLI_COMMAND_HANDSHAKE_BY_INDEX => {
-            info!("Handshake by DNS seed and IP indexes...");
-            
-            let dns_url_index = argument_to_number(&config.arguments, 0)?;
-            let _dns_url = DnsSeedManager::dns_seed_at_index(dns_url_index).unwrap();
-            
-            let dsm = DnsSeedManager::new_with_dns_index(dns_url_index)
+            let dsm = config.resolve_seeds(*seed).await?;
+            let records: Vec<_> = dsm
+                .active_nodes
+                .iter()
+                .enumerate()
+                .map(|(index, address)| AddressRecord {
+                    index,
+                    address: *address,
+                    seed: dsm.sources_of(address).join(" "),
+                })
+                .collect();
+            config.output.print(&records).change_context(ConfigError)?;
+
+            let mut address_book = config.load_address_book()?;
+            add_seed_nodes(&mut address_book, &dsm);
+            address_book.save().change_context(ConfigError)?;
+        }
+        Command::Seeds(SeedsCommand::Check { seed, sample }) => {
+            info!("Check DNS seeds...");
+
+            let mut settings = config.dns_settings()?;
+            if let SeedSelection::Index(dns_index) = *seed {
+                let Some(dns) = settings.seeds.get(dns_index) else {
+                    return Err(Report::new(ConfigRunError)
+                        .attach_printable(format!("Bad DNS seed index: {dns_index}"))
+                        .change_context(ConfigError));
+                };
+                settings.seeds = vec![dns.clone()];
+            }
+            let (dsm, lookups) = DnsSeedManager::resolve_all(config.network, &settings).await;
+
+            let mut address_book = config.load_address_book()?;
+            add_seed_nodes(&mut address_book, &dsm);
+
+            let mut checker =
+                SeedChecker::new(config.handshake_manager(), *sample, config.concurrency);
+            let records: Vec<_> = checker
+                .check(&lookups)
                 .await
-                .change_context(ConfigError)?;
-                
-            let remote_peer_index = argument_to_number(&config.arguments, 1)?;
-            let mut handshake_manager = HandshakeManager::default();
-            let Some(remote) = dsm.get(remote_peer_index) else {
-                return Err(Report::new(ConfigError)
-                   .attach_printable(format!("Bad remote peer index: {:?}", remote_peer_index))
-                   .change_context(ConfigError));
-            };
+                .iter()
+                .map(SeedHealthRecord::new)
+                .collect();
+            config.output.print(&records).change_context(ConfigError)?;
 
-            let remote = remote.clone();
-            match handshake_manager.establish_handshake(remote).await {
-                Ok(_s) => {
-                    info!("Handshake with IP {:?} evaluated from DNS seed index {:?} and IP index {:?}, completed", remote, dns_url_index, remote_peer_index);
-                    handshake_manager.record_handshake(remote, _s);
+            address_book.record_statuses(checker.handshake_manager().statuses());
+            address_book.save().change_context(ConfigError)?;
+        }
+        Command::Handshake(targets) => {
+            let mut address_book = config.load_address_book()?;
+            let remotes = match (targets.seed, targets.node) {
+                (Some(dns_url_index), Some(remote_peer_index)) => {
+                    info!("Handshake by DNS seed and IP indexes...");
+                    let dsm = config
+                        .resolve_seeds(SeedSelection::Index(dns_url_index))
+                        .await?;
+                    add_seed_nodes(&mut address_book, &dsm);
+                    let Some(remote) = dsm.get(remote_peer_index) else {
+                        return Err(Report::new(ConfigRunError)
+                            .attach_printable(format!(
+                                "Bad remote peer index: {:?}",
+                                remote_peer_index
+                            ))
+                            .change_context(ConfigError));
+                    };
+                    vec![PeerAddr::from(*remote)]
                 }
-                Err(e) => {
-                    handshake_manager.record_handshake(remote, false);
-                    error!("Handshake with IP {:?} evaluated from DNS seed index {:?} and IP index {:?}, failed. Error:\n{:?}", remote, dns_url_index, remote_peer_index, e);
+                _ => {
+                    info!("Handshake by target...");
+                    let remotes = config.resolve_targets(targets).await?;
+                    for remote in remotes.iter() {
+                        address_book.add(remote.clone(), PeerSource::Manual);
+                    }
+                    remotes
                 }
+            };
+
+            let mut handshake_manager = config.handshake_manager();
+            if let [remote] = &remotes[..] {
+                let result = handshake_manager.establish_handshake(remote.clone()).await;
+                match &result {
+                    Ok(outcome) => {
+                        info!("handshake completed successfully with node: {remote}");
+                        log_handshake_outcome(outcome);
+                    }
+                    Err(e) => {
+                        error!("Handshake with remote peer {remote:?} failed with error: \n{e:?}");
+                    }
+                };
+                config
+                    .output
+                    .print(&[HandshakeRecord::new(remote.clone(), &result)])
+                    .change_context(ConfigError)?;
+            } else {
+                info!(
+                    "Handshake with {} nodes, concurrency {}",
+                    remotes.len(),
+                    config.concurrency
+                );
+                let mut results = handshake_manager
+                    .establish_handshakes(remotes, config.concurrency)
+                    .await;
+                print_scan_summary(config.output, &mut results)?;
             }
-        },
-        CLI_COMMAND_HANDSHAKE_BY_URL => {
-            info!("Handshake by IP URL...");
 
-            let mut handshake_manager = HandshakeManager::default();
+            address_book.record_statuses(handshake_manager.statuses());
+            address_book.save().change_context(ConfigError)?;
+        }
+        Command::Scan { seed } => {
+            info!("Scan DNS seed nodes...");
+
+            let dsm = config.resolve_seeds(*seed).await?;
+            info!(
+                "Handshake with {} nodes, concurrency {}",
+                dsm.active_nodes.len(),
+                config.concurrency
+            );
+
+            let mut address_book = config.load_address_book()?;
+            add_seed_nodes(&mut address_book, &dsm);
+
+            let mut handshake_manager = config.handshake_manager();
+            let mut results = handshake_manager
+                .establish_handshakes(dsm.active_nodes, config.concurrency)
+                .await;
+            print_scan_summary(config.output, &mut results)?;
+
+            address_book.record_statuses(handshake_manager.statuses());
+            address_book.save().change_context(ConfigError)?;
+        }
+        Command::Crawl {
+            seed,
+            max_depth,
+            max_peers,
+        } => {
+            info!("Crawl the network from DNS seed nodes...");
 
-            let Some(sockaddr_string) = config.arguments.get(0) else {
-                return Err(Report::new(ConfigError)
-                    .attach_printable(format!("Argument at index 0 is not found")));
+            let dsm = config.resolve_seeds(*seed).await?;
+            let crawl_limits = CrawlLimits {
+                max_depth: *max_depth,
+                max_peers: *max_peers,
+                concurrency: config.concurrency,
+                ..CrawlLimits::default()
             };
+            info!(
+                "Crawl from {} nodes, max depth {}, max peers {}",
+                dsm.active_nodes.len(),
+                crawl_limits.max_depth,
+                crawl_limits.max_peers
+            );
+
+            let mut address_book = config.load_address_book()?;
+            add_seed_nodes(&mut address_book, &dsm);
+
+            let mut crawler = Crawler::new(config.handshake_manager(), crawl_limits);
+            let map = crawler.crawl(dsm.active_nodes).await;
+            print_network_map(config.output, &map)?;
+
+            add_crawled_peers(&mut address_book, &map);
+            address_book.record_statuses(crawler.handshake_manager().statuses());
+            address_book.save().change_context(ConfigError)?;
+        }
+        Command::Book(BookCommand::List) => {
+            let address_book = config.load_address_book()?;
+            print_address_book(config.output, &address_book)?;
+        }
+        Command::Book(BookCommand::Handshake { count }) => {
+            info!("Handshake with peers from the address book...");
 
-            let remote = sockaddr_string.parse()
+            let mut address_book = config.load_address_book()?;
+            let remotes = address_book.select_peers(*count);
+            if remotes.is_empty() {
+                return Err(Report::new(ConfigRunError)
+                    .attach_printable(format!(
+                        "Address book {} is empty",
+                        address_book.path().display()
+                    ))
+                    .change_context(ConfigError));
+            }
+            info!(
+                "Handshake with {} nodes, concurrency {}",
+                remotes.len(),
+                config.concurrency
+            );
+
+            let mut handshake_manager = config.handshake_manager();
+            let mut results = handshake_manager
+                .establish_handshakes(remotes, config.concurrency)
+                .await;
+            print_scan_summary(config.output, &mut results)?;
+
+            address_book.record_statuses(handshake_manager.statuses());
+            address_book.save().change_context(ConfigError)?;
+        }
+        Command::Listen { bind } => {
+            let bind_addr = bind.unwrap_or_else(|| {
+                SocketAddr::from(([0, 0, 0, 0], DnsSeedManager::default_port(config.network)))
+            });
+
+            let listener = TcpListener::bind(bind_addr)
+                .await
                 .into_report()
-                .attach_printable_lazy(|| format!("Could not parse IP address: {sockaddr_string:?}"))
+                .attach_printable_lazy(|| format!("Could not bind to address: {bind_addr}"))
                 .change_context(ConfigError)?;
+            info!("Listening for inbound handshakes on {bind_addr}...");
 
-            let hs_status = match handshake_manager.establish_handshake(remote).await {
-                Ok(established) => {
-                    info!("handshake completed successfully with node: {remote}");
-                    established
-                }
-                Err(e) => {
-                    eprintln!("Handshake with remote peer {remote:?} failed with error: \n{e:?}");
-                    false
-                }
+            let mut address_book = config.load_address_book()?;
+            let mut handshake_manager = config.handshake_manager();
+            config
+                .output
+                .print_stream_header::<HandshakeRecord>()
+                .change_context(ConfigError)?;
+            handshake_manager
+                .accept_handshakes(&listener, config.concurrency, |remote, result| {
+                    match &result {
+                        Ok(outcome) => {
+                            info!("Inbound handshake completed successfully with node: {remote}");
+                            log_handshake_outcome(outcome);
+                        }
+                        Err(e) => {
+                            error!("Inbound handshake with remote peer {remote} failed with error: \n{e:?}");
+                        }
+                    }
+                    if let Err(e) = config
+                        .output
+                        .print_stream_record(&HandshakeRecord::new(remote, &result))
+                    {
+                        error!("Failed to print the handshake: \n{e:?}");
+                    }
+
+                    // The peer is kept with the address it listens on, not the source port of its connection
+                    let (addr, status) = match result {
+                        Ok(outcome) => (
+                            address_book.inbound_addr(remote, outcome.peer.sender),
+                            HandshakeStatus::Completed(Box::new(outcome)),
+                        ),
+                        Err(e) => (
+                            address_book.inbound_addr(remote, None),
+                            HandshakeStatus::Failed(HandshakeError::kind(&e)),
+                        ),
+                    };
+                    address_book.add(addr, PeerSource::Inbound);
+                    address_book.record_status(addr, &status);
+                    if let Err(e) = address_book.save() {
+                        error!("Failed to save the address book: \n{e:?}");
+                    }
+                })
+                .await;
+        }
+        Command::Seeder {
+            zone,
+            dns_bind,
+            interval,
+            batch,
+            max_age,
+        } => {
+            let mut address_book = config.load_address_book()?;
+            if address_book.is_empty() {
+                info!("Address book is empty, resolve the DNS seeds...");
+                let dsm = config.resolve_seeds(SeedSelection::All).await?;
+                add_seed_nodes(&mut address_book, &dsm);
+            }
+
+            let settings = SeederSettings {
+                port: Some(DnsSeedManager::default_port(config.network)),
+                max_age: Duration::from_secs(*max_age as u64),
+                ..SeederSettings::new(zone, *dns_bind)
             };
-            handshake_manager.record_handshake(remote, hs_status);
-        },
-        _ => {
-            return Err(Report::new(ConfigRunError)
-                .attach_printable(format!("Invalid command provided: {:?}", config.command)))
-                .change_context(ConfigError);
+            let seeder = DnsSeeder::start(settings)
+                .await
+                .change_context(ConfigError)?;
+            info!("Serving zone {zone} on {}...", seeder.addr());
+
+            let crawl_limits = CrawlLimits {
+                max_depth: 0,
+                max_peers: *batch,
+                concurrency: config.concurrency,
+                ..CrawlLimits::default()
+            };
+            loop {
+                let remotes = address_book.select_stale_peers(*batch);
+                info!(
+                    "Seeder round: handshake with {} of {} known peers",
+                    remotes.len(),
+                    address_book.len()
+                );
+
+                let mut crawler = Crawler::new(config.handshake_manager(), crawl_limits);
+                let map = crawler.crawl(remotes).await;
+                add_crawled_peers(&mut address_book, &map);
+                address_book.record_statuses(crawler.handshake_manager().statuses());
+                seeder.record_statuses(crawler.handshake_manager().statuses());
+                if let Err(e) = address_book.save() {
+                    error!("Failed to save the address book: \n{e:?}");
+                }
+                info!("Seeder serves {} verified nodes", seeder.good_nodes().len());
+
+                tokio::time::sleep(Duration::from_secs(*interval as u64)).await;
+            }
         }
     }
     Ok(())
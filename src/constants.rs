@@ -1,2 +1,10 @@
 /// Define base Bitcoin protocol version that current implementation conforms to.
-pub const PROTOCOL_VERSION: u32 = 70015;
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+/// Define the oldest protocol version of a remote peer the handshake is performed with,
+/// as in Bitcoin Core `MIN_PEER_PROTO_VERSION`.
+pub const MIN_PEER_PROTOCOL_VERSION: u32 = 31800;
+
+/// Define the protocol version since which the `wtxidrelay` (BIP339) and `sendaddrv2` (BIP155)
+/// messages are exchanged between `version` and `verack`, as in Bitcoin Core `WTXID_RELAY_VERSION`.
+pub const FEATURE_NEGOTIATION_VERSION: u32 = 70016;
@@ -0,0 +1,215 @@
+use bitcoin::network::message::NetworkMessage;
+use error_stack::Result;
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::time::{timeout_at, Instant};
+
+use crate::{HandshakeError, HandshakeManager, HandshakeOutcome, HandshakeStatus, PeerAddr};
+
+/// Limits of a crawl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrawlLimits {
+    /// Number of gossip hops followed from the seeds, the seeds themselves are at depth 0
+    pub max_depth: usize,
+    /// Maximum number of handshakes of the whole crawl
+    pub max_peers: usize,
+    /// Maximum number of handshakes running at the same time
+    pub concurrency: usize,
+    /// Time to wait for the `addr` or `addrv2` response to `getaddr`
+    pub addr_timeout: Duration,
+}
+
+impl Default for CrawlLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 2,
+            max_peers: 1000,
+            concurrency: 32,
+            addr_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A peer found during a crawl
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrawledPeer {
+    /// Number of gossip hops from the seeds
+    pub depth: usize,
+    /// Peer that gossiped the address, `None` for the seeds
+    pub source: Option<SocketAddr>,
+    /// Status of the handshake, `None` if the crawl limits did not allow to try it
+    pub status: Option<HandshakeStatus>,
+    /// Addresses gossiped by the peer in response to `getaddr`, including the Tor and I2P ones of `addrv2`.
+    /// Only the IP addresses are followed by the crawl.
+    pub gossiped: Vec<PeerAddr>,
+}
+
+impl CrawledPeer {
+    fn new(depth: usize, source: Option<SocketAddr>) -> Self {
+        Self {
+            depth,
+            source,
+            status: None,
+            gossiped: Vec::new(),
+        }
+    }
+}
+
+/// Map of the network reachable from the seeds of a crawl
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkMap {
+    /// Every peer found during the crawl, keyed by its address
+    pub peers: HashMap<SocketAddr, CrawledPeer>,
+}
+
+impl NetworkMap {
+    /// Returns the addresses of the peers that completed the handshake
+    pub fn reachable(&self) -> Vec<SocketAddr> {
+        let mut reachable: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.status
+                    .as_ref()
+                    .is_some_and(HandshakeStatus::is_completed)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        reachable.sort();
+        reachable
+    }
+}
+
+/// Crawler - discovers the network by following the addresses gossiped by the peers.
+///
+/// After every successful handshake the crawler sends `getaddr`, collects the `addr` or `addrv2` response
+/// and queues the newly learned addresses for the next depth, until the `CrawlLimits` are reached.
+pub struct Crawler {
+    handshake_manager: HandshakeManager,
+    limits: CrawlLimits,
+}
+
+impl Crawler {
+    /// Construct a new Crawler that performs handshakes with the given `handshake_manager`
+    pub fn new(handshake_manager: HandshakeManager, limits: CrawlLimits) -> Self {
+        Self {
+            handshake_manager,
+            limits,
+        }
+    }
+
+    /// Returns the handshake manager with the statuses of all handshakes of the crawl
+    pub fn handshake_manager(&self) -> &HandshakeManager {
+        &self.handshake_manager
+    }
+
+    /// Crawls the network starting from the `seeds` and returns the map of the found peers
+    pub async fn crawl(&mut self, seeds: Vec<SocketAddr>) -> NetworkMap {
+        let mut map = NetworkMap::default();
+        let mut frontier = Vec::new();
+        for seed in seeds {
+            if let Entry::Vacant(entry) = map.peers.entry(seed) {
+                entry.insert(CrawledPeer::new(0, None));
+                frontier.push(seed);
+            }
+        }
+
+        let mut attempted = 0;
+        for depth in 0..=self.limits.max_depth {
+            frontier.truncate(self.limits.max_peers - attempted);
+            if frontier.is_empty() {
+                break;
+            }
+            attempted += frontier.len();
+            info!(
+                "Crawl depth {depth}: handshake with {} peers",
+                frontier.len()
+            );
+
+            let manager = &self.handshake_manager;
+            let addr_timeout = self.limits.addr_timeout;
+            let results: Vec<_> =
+                stream::iter(frontier)
+                    .map(|remote| async move {
+                        (remote, crawl_peer(manager, remote, addr_timeout).await)
+                    })
+                    .buffer_unordered(self.limits.concurrency.max(1))
+                    .collect()
+                    .await;
+
+            let mut next_frontier = Vec::new();
+            for (remote, result) in results {
+                let (status, gossiped) = match result {
+                    Ok((outcome, gossiped)) => {
+                        (HandshakeStatus::Completed(Box::new(outcome)), gossiped)
+                    }
+                    Err(e) => (
+                        HandshakeStatus::Failed(HandshakeError::kind(&e)),
+                        Vec::new(),
+                    ),
+                };
+                self.handshake_manager
+                    .record_handshake(remote, status.clone());
+
+                for addr in gossiped.iter().filter_map(PeerAddr::socket_addr) {
+                    if let Entry::Vacant(entry) = map.peers.entry(addr) {
+                        entry.insert(CrawledPeer::new(depth + 1, Some(remote)));
+                        next_frontier.push(addr);
+                    }
+                }
+                if let Some(peer) = map.peers.get_mut(&remote) {
+                    peer.status = Some(status);
+                    peer.gossiped = gossiped;
+                }
+            }
+            frontier = next_frontier;
+        }
+        map
+    }
+}
+
+/// Performs a handshake with a `remote` SocketAddr, then asks it for the addresses of other peers.
+/// A missing or failed `getaddr` response leaves the gossiped addresses empty, but does not fail the handshake.
+async fn crawl_peer(
+    manager: &HandshakeManager,
+    remote: SocketAddr,
+    addr_timeout: Duration,
+) -> Result<(HandshakeOutcome, Vec<PeerAddr>), HandshakeError> {
+    let mut session = manager.try_session(&remote.into()).await?;
+    let outcome = session.outcome().clone();
+    if let Err(e) = session.send(NetworkMessage::GetAddr).await {
+        warn!("Failed to send getaddr to {remote}: {e:?}");
+        return Ok((outcome, Vec::new()));
+    }
+
+    // Nodes answer `getaddr` with a single large message, smaller ones are regular relays
+    let mut gossiped = Vec::new();
+    let deadline = Instant::now() + addr_timeout;
+    while let Ok(Ok(message)) = timeout_at(deadline, session.recv()).await {
+        let addresses: Vec<_> = match message {
+            NetworkMessage::Addr(addresses) => addresses
+                .iter()
+                .filter_map(|(_, address)| address.socket_addr().ok().map(PeerAddr::from))
+                .collect(),
+            NetworkMessage::AddrV2(addresses) => addresses
+                .iter()
+                .filter_map(PeerAddr::from_addr_v2)
+                .collect(),
+            _ => continue,
+        };
+        let is_response = addresses.len() > 1;
+        gossiped.extend(addresses);
+        if is_response {
+            break;
+        }
+    }
+    gossiped.sort();
+    gossiped.dedup();
+    info!("Peer {remote} gossiped {} addresses", gossiped.len());
+    Ok((outcome, gossiped))
+}
@@ -0,0 +1,379 @@
+//! Minimal DNS wire format (RFC 1035) for A and AAAA lookups over UDP and TCP.
+//!
+//! Only the parts needed to query a nameserver for the addresses of a DNS seed
+//! and to answer such queries are implemented: a single question per message,
+//! A and AAAA answers, and compressed names in responses.
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::dns_seed_mananger::DnsLookupError;
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+pub(crate) const RCODE_NO_ERROR: u8 = 0;
+pub(crate) const RCODE_NAME_ERROR: u8 = 3;
+pub(crate) const RCODE_REFUSED: u8 = 5;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+const HEADER_LENGTH: usize = 12;
+const MAX_LABEL_LENGTH: usize = 63;
+const MAX_NAME_LENGTH: usize = 255;
+/// Maximum size of a DNS message over UDP without EDNS
+pub(crate) const MAX_UDP_MESSAGE: usize = 512;
+
+/// Question of a DNS message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DnsQuestion {
+    pub id: u16,
+    /// Lower case name without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    pub recursion_desired: bool,
+}
+
+/// Normalises a domain `name`: lower case, without the trailing dot
+pub(crate) fn normalise_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Encodes a query for the records of `qtype` of the `name`
+pub(crate) fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, DnsLookupError> {
+    let mut message = Vec::with_capacity(MAX_UDP_MESSAGE);
+    encode_header(&mut message, id, FLAG_RECURSION_DESIRED, 1, 0);
+    encode_name(&mut message, name)?;
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Decodes the question of a query, `None` if the message is not a query with a single question
+pub(crate) fn decode_query(message: &[u8]) -> Option<DnsQuestion> {
+    let id = read_u16(message, 0)?;
+    let flags = read_u16(message, 2)?;
+    let question_count = read_u16(message, 4)?;
+    if flags & FLAG_RESPONSE != 0 || question_count != 1 {
+        return None;
+    }
+
+    let (name, offset) = read_name(message, HEADER_LENGTH)?;
+    let qtype = read_u16(message, offset)?;
+    Some(DnsQuestion {
+        id,
+        name,
+        qtype,
+        recursion_desired: flags & FLAG_RECURSION_DESIRED != 0,
+    })
+}
+
+/// Encodes an authoritative response to the `question` with the `rcode` and the `addresses`
+/// that match the question type, each with the given `ttl` in seconds.
+/// Answers that do not fit into a UDP message are left out.
+pub(crate) fn encode_response(
+    question: &DnsQuestion,
+    rcode: u8,
+    addresses: &[IpAddr],
+    ttl: u32,
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MAX_UDP_MESSAGE);
+    let mut flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE | u16::from(rcode & 0x0f);
+    if question.recursion_desired {
+        flags |= FLAG_RECURSION_DESIRED;
+    }
+    encode_header(&mut message, question.id, flags, 1, 0);
+    // The name was decoded from a valid question, so it can be encoded again
+    let _ = encode_name(&mut message, &question.name);
+    message.extend_from_slice(&question.qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let mut answer_count: u16 = 0;
+    for address in addresses {
+        let rdata = match (address, question.qtype) {
+            (IpAddr::V4(ip), TYPE_A) => ip.octets().to_vec(),
+            (IpAddr::V6(ip), TYPE_AAAA) => ip.octets().to_vec(),
+            _ => continue,
+        };
+        // Name pointer (2) + type (2) + class (2) + ttl (4) + length (2)
+        if message.len() + 12 + rdata.len() > MAX_UDP_MESSAGE {
+            break;
+        }
+        message.extend_from_slice(&(0xc000 | HEADER_LENGTH as u16).to_be_bytes());
+        message.extend_from_slice(&question.qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(&rdata);
+        answer_count += 1;
+    }
+    message[6..8].copy_from_slice(&answer_count.to_be_bytes());
+    message
+}
+
+/// Marks the response `message` as truncated, the answers did not fit into the UDP message
+pub(crate) fn set_truncated(message: &mut [u8]) {
+    if let Some(flags) = message.get_mut(2) {
+        *flags |= (FLAG_TRUNCATED >> 8) as u8;
+    }
+}
+
+/// Returns whether the response `message` is truncated and has to be queried again over TCP
+pub(crate) fn is_truncated(message: &[u8]) -> bool {
+    read_u16(message, 2).is_some_and(|flags| flags & FLAG_TRUNCATED != 0)
+}
+
+/// Decodes a response to the query with the `id`. Returns the response code and the A and AAAA answers.
+/// Returns `None` if the message is not a response to the query.
+pub(crate) fn decode_response(message: &[u8], id: u16) -> Option<(u8, Vec<IpAddr>)> {
+    let flags = read_u16(message, 2)?;
+    if read_u16(message, 0)? != id || flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let rcode = (flags & 0x0f) as u8;
+    let question_count = read_u16(message, 4)?;
+    let answer_count = read_u16(message, 6)?;
+
+    let mut offset = HEADER_LENGTH;
+    for _ in 0..question_count {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answer_count {
+        offset = read_name(message, offset)?.1;
+        let rtype = read_u16(message, offset)?;
+        let rdata_length = read_u16(message, offset + 8)? as usize;
+        let rdata = message.get(offset + 10..offset + 10 + rdata_length)?;
+        match (rtype, rdata_length) {
+            (TYPE_A, 4) => {
+                addresses.push(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(rdata).ok()?)))
+            }
+            (TYPE_AAAA, 16) => addresses.push(IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(rdata).ok()?,
+            ))),
+            _ => {}
+        }
+        offset += 10 + rdata_length;
+    }
+    Some((rcode, addresses))
+}
+
+/// Queries the `nameserver` for the A and AAAA records of the `name` over UDP and returns all addresses.
+/// A truncated response is queried again over TCP, as the nameserver could not fit all answers into the datagram.
+/// The lookup waits for both responses, the caller is expected to limit its duration.
+pub(crate) async fn lookup(
+    nameserver: SocketAddr,
+    name: &str,
+) -> Result<Vec<IpAddr>, DnsLookupError> {
+    let bind_addr: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .into_report()
+        .change_context(DnsLookupError)?;
+    socket
+        .connect(nameserver)
+        .await
+        .into_report()
+        .attach_printable_lazy(|| format!("Failed to connect to nameserver {nameserver}"))
+        .change_context(DnsLookupError)?;
+
+    let mut pending = Vec::new();
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        let id = rand::random();
+        socket
+            .send(&encode_query(id, name, qtype)?)
+            .await
+            .into_report()
+            .attach_printable_lazy(|| format!("Failed to send query to nameserver {nameserver}"))
+            .change_context(DnsLookupError)?;
+        pending.push(id);
+    }
+
+    let mut addresses = Vec::new();
+    let mut buffer = [0u8; MAX_UDP_MESSAGE];
+    while !pending.is_empty() {
+        let length = socket
+            .recv(&mut buffer)
+            .await
+            .into_report()
+            .attach_printable_lazy(|| {
+                format!("Failed to receive response from nameserver {nameserver}")
+            })
+            .change_context(DnsLookupError)?;
+        let message = &buffer[..length];
+        // Stray datagrams and responses to other queries are ignored
+        let Some((index, (rcode, answers))) = pending.iter().enumerate().find_map(|(index, id)| {
+            decode_response(message, *id).map(|response| (index, response))
+        }) else {
+            continue;
+        };
+        if is_truncated(message) {
+            return lookup_tcp_at(nameserver, name).await;
+        }
+        pending.swap_remove(index);
+        if rcode != RCODE_NO_ERROR {
+            return Err(Report::new(DnsLookupError).attach_printable(format!(
+                "Nameserver {nameserver} answered {name:?} with response code {rcode}"
+            )));
+        }
+        addresses.extend(answers);
+    }
+    Ok(addresses)
+}
+
+/// Connects to the `nameserver` and resolves the A and AAAA records of the `name` over TCP
+async fn lookup_tcp_at(nameserver: SocketAddr, name: &str) -> Result<Vec<IpAddr>, DnsLookupError> {
+    let stream = TcpStream::connect(nameserver)
+        .await
+        .into_report()
+        .attach_printable_lazy(|| {
+            format!("Failed to connect to nameserver {nameserver} after a truncated response")
+        })
+        .change_context(DnsLookupError)?;
+    lookup_tcp(stream, &nameserver.to_string(), name).await
+}
+
+/// Resolves the A and AAAA records of the `name` over the TCP `stream` connected to the `nameserver`,
+/// e.g. through a proxy that does not relay UDP.
+pub(crate) async fn lookup_tcp<S>(
+    mut stream: S,
+    nameserver: &str,
+    name: &str,
+) -> Result<Vec<IpAddr>, DnsLookupError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pending = Vec::new();
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        let id = rand::random();
+        write_tcp_message(&mut stream, &encode_query(id, name, qtype)?)
+            .await
+            .into_report()
+            .attach_printable_lazy(|| format!("Failed to send query to nameserver {nameserver}"))
+            .change_context(DnsLookupError)?;
+        pending.push(id);
+    }
+
+    let mut addresses = Vec::new();
+    while !pending.is_empty() {
+        let message = read_tcp_message(&mut stream)
+            .await
+            .into_report()
+            .attach_printable_lazy(|| {
+                format!("Failed to receive response from nameserver {nameserver}")
+            })
+            .change_context(DnsLookupError)?;
+        let Some((index, (rcode, answers))) = pending.iter().enumerate().find_map(|(index, id)| {
+            decode_response(&message, *id).map(|response| (index, response))
+        }) else {
+            return Err(Report::new(DnsLookupError).attach_printable(format!(
+                "Nameserver {nameserver} sent an unexpected response"
+            )));
+        };
+        pending.swap_remove(index);
+        if rcode != RCODE_NO_ERROR {
+            return Err(Report::new(DnsLookupError).attach_printable(format!(
+                "Nameserver {nameserver} answered {name:?} with response code {rcode}"
+            )));
+        }
+        addresses.extend(answers);
+    }
+    Ok(addresses)
+}
+
+/// Reads a DNS message prefixed with its two byte length, as sent over TCP
+pub(crate) async fn read_tcp_message<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let length = stream.read_u16().await?;
+    let mut message = vec![0u8; length as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Writes a DNS message prefixed with its two byte length, as sent over TCP
+pub(crate) async fn write_tcp_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &[u8],
+) -> io::Result<()> {
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await
+}
+
+fn encode_header(
+    message: &mut Vec<u8>,
+    id: u16,
+    flags: u16,
+    question_count: u16,
+    answer_count: u16,
+) {
+    for field in [id, flags, question_count, answer_count, 0, 0] {
+        message.extend_from_slice(&field.to_be_bytes());
+    }
+}
+
+fn encode_name(message: &mut Vec<u8>, name: &str) -> Result<(), DnsLookupError> {
+    let name = name.trim_end_matches('.');
+    if name.len() > MAX_NAME_LENGTH - 2 {
+        return Err(Report::new(DnsLookupError)
+            .attach_printable(format!("Domain name is too long: {name:?}")));
+    }
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(Report::new(DnsLookupError)
+                .attach_printable(format!("Domain name label is too long: {label:?}")));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    Ok(())
+}
+
+/// Reads the name at the `offset`, following compression pointers.
+/// Returns the normalised name and the offset right after the name.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer must go backwards, so the number of jumps is bounded by the message length
+    let mut jumps = 0;
+    loop {
+        let length = *message.get(offset)? as usize;
+        if length & 0xc0 == 0xc0 {
+            let pointer = (read_u16(message, offset)? & 0x3fff) as usize;
+            end.get_or_insert(offset + 2);
+            jumps += 1;
+            if pointer >= offset || jumps > message.len() {
+                return None;
+            }
+            offset = pointer;
+            continue;
+        }
+        if length == 0 {
+            let end = end.unwrap_or(offset + 1);
+            return Some((normalise_name(&labels.join(".")), end));
+        }
+        if length > MAX_LABEL_LENGTH {
+            return None;
+        }
+        let label = message.get(offset + 1..offset + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + length;
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
@@ -1,25 +1,56 @@
-/// DNS Seeds
-///
-/// Predefined DNS seed taken from:
-///     https://github.com/bitcoin/bitcoin/blob/v24.0.1/src/chainparams.cpp#L123
-///
-///     "seed.bitcoin.sipa.be."          
-///     "dnsseed.bluematt.me."           
-///     "dnsseed.bitcoin.dashjr.org."    
-///     "seed.bitcoinstats.com."         
-///     "seed.bitcoin.jonasschnelli.ch."
-///     "seed.btc.petertodd.org."        
-///     "seed.bitcoin.sprovoost.nl."     
-///     "dnsseed.emzy.de."               
-///     "seed.bitcoin.wiz.biz."          
-use std::net;
+//! DNS Seeds
+//!
+//! Predefined DNS seed taken from:
+//!     <https://github.com/bitcoin/bitcoin/blob/v24.0.1/src/chainparams.cpp#L123>
+//!
+//! ```text
+//!     "seed.bitcoin.sipa.be."
+//!     "dnsseed.bluematt.me."
+//!     "dnsseed.bitcoin.dashjr.org."
+//!     "seed.bitcoinstats.com."
+//!     "seed.bitcoin.jonasschnelli.ch."
+//!     "seed.btc.petertodd.org."
+//!     "seed.bitcoin.sprovoost.nl."
+//!     "dnsseed.emzy.de."
+//!     "seed.bitcoin.wiz.biz."
+//! ```
+//!
+//! Testnet and signet seeds are taken from the same file, regtest has no DNS seeds.
+//! Other seeds and a nameserver to query them can be given with `DnsSeedSettings`.
+//! Seeds may be asked for the nodes with given services only, with the `x<hex flags>.<seed>` query names.
+//! With a SOCKS5 proxy, the nameserver is queried over TCP through the proxy, or the proxy resolves the seeds itself.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    net::{self, IpAddr},
+    path::Path,
+    time::{Duration, Instant},
+};
 
+use bitcoin::{network::constants::ServiceFlags, Network};
 use error_stack::{IntoReport, Report, Result, ResultExt};
+use futures::future;
+use log::{info, warn};
 
-type VecSocketAddr = Vec<std::net::SocketAddr>;
+use crate::{
+    dns,
+    proxy::{ProxySettings, ProxyTarget},
+};
 
 const DEFAULT_PORT_MAINNET: u16 = 8333;
-const DEFAULT_DNS_SEEDS: &'static [&'static str] = &[
+const DEFAULT_PORT_TESTNET: u16 = 18333;
+const DEFAULT_PORT_SIGNET: u16 = 38333;
+const DEFAULT_PORT_REGTEST: u16 = 18444;
+
+const DEFAULT_DNS_SEEDS_TESTNET: &[&str] = &[
+    "testnet-seed.bitcoin.jonasschnelli.ch.",
+    "seed.tbtc.petertodd.org.",
+    "seed.testnet.bitcoin.sprovoost.nl.",
+    "testnet-seed.bluematt.me.",
+];
+const DEFAULT_DNS_SEEDS_SIGNET: &[&str] = &["seed.signet.bitcoin.sprovoost.nl.", "178.128.221.177"];
+const DEFAULT_DNS_SEEDS_REGTEST: &[&str] = &[];
+const DEFAULT_DNS_SEEDS: &[&str] = &[
     "seed.bitcoin.sipa.be.",
     "dnsseed.bluematt.me.",
     "dnsseed.bitcoin.dashjr.org.",
@@ -43,20 +74,79 @@ impl std::fmt::Display for DnsLookupError {
 
 impl std::error::Error for DnsLookupError {}
 
+/// Settings of the DNS seed lookups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsSeedSettings {
+    /// DNS seeds to query, host names or IP addresses
+    pub seeds: Vec<String>,
+    /// Nameserver queried directly over UDP, or over TCP through the proxy. The system resolver is used if `None`
+    pub nameserver: Option<net::SocketAddr>,
+    /// Maximum duration of the lookup of a single seed
+    pub timeout: Duration,
+    /// Services the resolved nodes should advertise, no filtering if `ServiceFlags::NONE`
+    pub services: ServiceFlags,
+    /// SOCKS5 proxy of the lookups, the seeds are resolved directly if `None`
+    pub proxy: Option<ProxySettings>,
+}
+
+impl DnsSeedSettings {
+    /// Default lookup timeout of a single seed
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Returns the settings with the default DNS seeds of the `network`, the system resolver and the default timeout
+    pub fn new(network: Network) -> Self {
+        Self {
+            seeds: DnsSeedManager::default_dns_seeds(network)
+                .iter()
+                .map(|seed| seed.to_string())
+                .collect(),
+            nameserver: None,
+            timeout: Self::DEFAULT_TIMEOUT,
+            services: ServiceFlags::NONE,
+            proxy: None,
+        }
+    }
+
+    /// Reads the DNS seeds from a file with one seed per line. Empty lines and lines starting with `#` are skipped.
+    pub fn read_seeds_file(path: impl AsRef<Path>) -> Result<Vec<String>, DnsLookupError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Failed to read DNS seeds file {}", path.display()))
+            .change_context(DnsLookupError)?;
+
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect())
+    }
+}
+
+/// Result of the lookup of a single DNS seed
+#[derive(Debug)]
+pub struct SeedLookup {
+    /// DNS seed as given in the settings
+    pub seed: String,
+    /// Duration of the lookup, including the unfiltered fallback query
+    pub latency: Duration,
+    /// Nodes returned by the seed, or the reason of the failure
+    pub result: Result<Vec<net::SocketAddr>, DnsLookupError>,
+}
+
 /// DnsSeedManager contains a list of resolved IP addresses of active nodes
 #[derive(Debug)]
 pub struct DnsSeedManager {
     pub active_nodes: Vec<std::net::SocketAddr>,
+    /// DNS seeds that returned each of the active nodes, in the order of the seeds
+    pub sources: HashMap<std::net::SocketAddr, Vec<String>>,
 }
 
+/// An empty DnsSeedManager, use `DnsSeedManager::resolve_all` or `DnsSeedManager::new_with_all_dns` to resolve the seeds
 impl Default for DnsSeedManager {
     fn default() -> Self {
-        Self {
-            active_nodes: DnsSeedManager::lookup_active_nodes(
-                &DEFAULT_DNS_SEEDS,
-                DEFAULT_PORT_MAINNET,
-            ),
-        }
+        Self::new()
     }
 }
 
@@ -65,58 +155,118 @@ impl DnsSeedManager {
     pub fn new() -> Self {
         Self {
             active_nodes: Vec::new(),
+            sources: HashMap::new(),
         }
     }
 
-    /// Construct a new DnsSeedManager based on index of DNS seed URL
-    pub async fn new_with_dns_index(i: usize) -> Result<Self, DnsLookupError> {
-        let Some(dns_url) = DnsSeedManager::dns_seed_at_index(i) else {
-            return Err(Report::from(DnsLookupError).attach_printable(format!("Bad DNS seed index: {}", i)));
+    /// Construct a new DnsSeedManager based on index of DNS seed URL in the `settings`
+    pub async fn new_with_dns_index(
+        network: Network,
+        settings: &DnsSeedSettings,
+        i: usize,
+    ) -> Result<Self, DnsLookupError> {
+        let Some(dns_url) = settings.seeds.get(i) else {
+            return Err(Report::from(DnsLookupError)
+                .attach_printable(format!("Bad DNS seed index for {network}: {i}")));
         };
-        DnsSeedManager::new_with_dns(&dns_url).await
+        DnsSeedManager::new_with_dns(network, settings, dns_url).await
     }
 
-    /// Construct a new DnsSeedManager based on DNS seed URL represented as `&str`
-    pub async fn new_with_dns(dns: &str) -> Result<Self, DnsLookupError> {
+    /// Construct a new DnsSeedManager based on DNS seed URL represented as `&str`.
+    /// Resolved addresses use the default P2P port of the given `network`.
+    /// If the `settings` ask for services, the filtered `x<hex flags>.<seed>` name is queried first,
+    /// and the seed itself if the filtered name fails or returns no nodes.
+    /// Fails if a lookup takes longer than the timeout of the `settings`.
+    pub async fn new_with_dns(
+        network: Network,
+        settings: &DnsSeedSettings,
+        dns: &str,
+    ) -> Result<Self, DnsLookupError> {
         let mut dsm = DnsSeedManager::new();
-        let dns_seed_addr = (dns, DEFAULT_PORT_MAINNET);
-
-        let seeds = tokio::net::lookup_host(dns_seed_addr)
-            .await
-            .into_report()
-            .attach_printable_lazy(|| {
-                format!("Failed to lookup dns seeds by URL {:?}", dns_seed_addr)
-            })
-            .change_context(DnsLookupError)?;
-
-        dsm.active_nodes
-            .extend(seeds.collect::<Vec<std::net::SocketAddr>>());
+        for node in lookup_nodes(dns, DnsSeedManager::default_port(network), settings).await? {
+            dsm.add_node(node, dns);
+        }
         Ok(dsm)
     }
 
-    /// Return the list of internal DNS seed URLs
-    pub fn default_dns_seeds() -> &'static [&'static str] {
-        DEFAULT_DNS_SEEDS
+    /// Construct a new DnsSeedManager based on all DNS seed URLs of the `settings`.
+    /// Seeds that fail to resolve or time out are logged and skipped, duplicate addresses are kept once.
+    pub async fn new_with_all_dns(network: Network, settings: &DnsSeedSettings) -> Self {
+        let (dsm, lookups) = DnsSeedManager::resolve_all(network, settings).await;
+        for lookup in lookups.iter() {
+            match &lookup.result {
+                Ok(nodes) => info!(
+                    "DNS seed {} returned {} nodes in {:?}",
+                    lookup.seed,
+                    nodes.len(),
+                    lookup.latency
+                ),
+                Err(e) => warn!(
+                    "Skip DNS seed {} after {:?}: {e:?}",
+                    lookup.seed, lookup.latency
+                ),
+            }
+        }
+        dsm
+    }
+
+    /// Resolves all DNS seeds of the `settings` concurrently.
+    /// Returns the deduplicated nodes with the seeds that returned each of them,
+    /// and the result and the latency of every seed lookup in the order of the seeds.
+    pub async fn resolve_all(
+        network: Network,
+        settings: &DnsSeedSettings,
+    ) -> (Self, Vec<SeedLookup>) {
+        let port = DnsSeedManager::default_port(network);
+        let lookups = future::join_all(settings.seeds.iter().map(|seed| async move {
+            let started = Instant::now();
+            let result = lookup_nodes(seed, port, settings).await;
+            SeedLookup {
+                seed: seed.clone(),
+                latency: started.elapsed(),
+                result,
+            }
+        }))
+        .await;
+
+        let mut dsm = DnsSeedManager::new();
+        for lookup in lookups.iter() {
+            if let Ok(nodes) = &lookup.result {
+                for node in nodes {
+                    dsm.add_node(*node, &lookup.seed);
+                }
+            }
+        }
+        (dsm, lookups)
     }
 
-    /// Prints the list of internal DNS seed URLs
-    pub fn print_default_dns_seeds() {
-        for (i, s) in DnsSeedManager::default_dns_seeds().iter().enumerate() {
-            println!("{}: {}", i, s);
+    /// Returns the `x<hex flags>.<seed>` query name that asks the `seed` for the nodes with the `services`.
+    /// Returns `None` if no services are given or the seed is an IP address.
+    pub fn filtered_query_name(seed: &str, services: ServiceFlags) -> Option<String> {
+        if services == ServiceFlags::NONE || seed.parse::<IpAddr>().is_ok() {
+            return None;
         }
+        Some(format!("x{services:x}.{seed}"))
     }
 
-    /// Prints the list of IP addresses of active nodes
-    pub fn print_resolved_remote_urls(&self) {
-        for (i, s) in self.active_nodes.iter().enumerate() {
-            println!("{}: {}", i, s);
+    /// Return the list of internal DNS seed URLs of the given `network`
+    pub fn default_dns_seeds(network: Network) -> &'static [&'static str] {
+        match network {
+            Network::Bitcoin => DEFAULT_DNS_SEEDS,
+            Network::Testnet => DEFAULT_DNS_SEEDS_TESTNET,
+            Network::Signet => DEFAULT_DNS_SEEDS_SIGNET,
+            Network::Regtest => DEFAULT_DNS_SEEDS_REGTEST,
         }
     }
 
-    /// Return DNS seed URL by given index
-    pub fn dns_seed_at_index(i: usize) -> Option<&'static &'static str> {
-        let o = DEFAULT_DNS_SEEDS.get(i);
-        o
+    /// Return the default P2P port of the given `network`
+    pub fn default_port(network: Network) -> u16 {
+        match network {
+            Network::Bitcoin => DEFAULT_PORT_MAINNET,
+            Network::Testnet => DEFAULT_PORT_TESTNET,
+            Network::Signet => DEFAULT_PORT_SIGNET,
+            Network::Regtest => DEFAULT_PORT_REGTEST,
+        }
     }
 
     /// Returns IP address of active node by given index
@@ -124,16 +274,130 @@ impl DnsSeedManager {
         self.active_nodes.get(i)
     }
 
-    /// Accepts a list of DNS look servers. Returns a vec or resolved IP addresses.
-    fn lookup_active_nodes(dns: &[&str], port: u16) -> VecSocketAddr {
-        let mut v: Vec<std::net::SocketAddr> = Vec::new();
-        for d in dns.iter() {
-            let t = (*d, port);
-            let sa = net::ToSocketAddrs::to_socket_addrs(&t);
-            if sa.is_ok() {
-                v.extend(sa.unwrap());
+    /// Returns the first DNS seed that returned the active `node`
+    pub fn source(&self, node: &net::SocketAddr) -> Option<&str> {
+        self.sources_of(node).first().map(String::as_str)
+    }
+
+    /// Returns all DNS seeds that returned the active `node`
+    pub fn sources_of(&self, node: &net::SocketAddr) -> &[String] {
+        self.sources
+            .get(node)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Adds an active `node` returned by the `dns` seed. A known node only gets the `dns` seed added to its sources.
+    fn add_node(&mut self, node: net::SocketAddr, dns: &str) {
+        match self.sources.entry(node) {
+            Entry::Occupied(mut entry) => {
+                if !entry.get().iter().any(|seed| seed == dns) {
+                    entry.get_mut().push(dns.to_owned());
+                }
             }
+            Entry::Vacant(entry) => {
+                entry.insert(vec![dns.to_owned()]);
+                self.active_nodes.push(node);
+            }
+        }
+    }
+}
+
+/// Resolves the nodes of the `dns` seed with the `settings`.
+/// If the settings ask for services, the filtered `x<hex flags>.<seed>` name is queried first,
+/// and the seed itself if the filtered name fails or returns no nodes.
+async fn lookup_nodes(
+    dns: &str,
+    port: u16,
+    settings: &DnsSeedSettings,
+) -> Result<Vec<net::SocketAddr>, DnsLookupError> {
+    if let Some(name) = DnsSeedManager::filtered_query_name(dns, settings.services) {
+        match lookup_seed_with_timeout(&name, port, settings).await {
+            Ok(nodes) if !nodes.is_empty() => return Ok(nodes),
+            Ok(_) => info!(
+                "DNS seed {dns} returned no nodes for {name}, fall back to the unfiltered query"
+            ),
+            Err(e) => info!(
+                "DNS seed {dns} does not support {name}, fall back to the unfiltered query: {e:?}"
+            ),
         }
-        v
     }
+    lookup_seed_with_timeout(dns, port, settings).await
+}
+
+/// Resolves the `dns` seed with the nameserver of the `settings` within their timeout
+async fn lookup_seed_with_timeout(
+    dns: &str,
+    port: u16,
+    settings: &DnsSeedSettings,
+) -> Result<Vec<net::SocketAddr>, DnsLookupError> {
+    tokio::time::timeout(settings.timeout, lookup_seed(dns, port, settings))
+        .await
+        .into_report()
+        .attach_printable_lazy(|| {
+            format!(
+                "DNS seed {dns:?} lookup timed out after {}ms",
+                settings.timeout.as_millis()
+            )
+        })
+        .change_context(DnsLookupError)?
+}
+
+/// Resolves the `dns` seed with the nameserver of the `settings`, or with the system resolver if `None`.
+/// With a proxy, the nameserver is queried over TCP through the proxy, or the proxy resolves the seed
+/// if there is no nameserver. A seed given as an IP address is returned as is.
+async fn lookup_seed(
+    dns: &str,
+    port: u16,
+    settings: &DnsSeedSettings,
+) -> Result<Vec<net::SocketAddr>, DnsLookupError> {
+    if let Ok(ip) = dns.parse::<IpAddr>() {
+        return Ok(vec![net::SocketAddr::new(ip, port)]);
+    }
+
+    let addresses = match (settings.nameserver, &settings.proxy) {
+        (Some(nameserver), None) => {
+            dns::lookup(nameserver, dns)
+                .await
+                .attach_printable_lazy(|| {
+                    format!("Failed to lookup dns seeds by URL {dns:?} at {nameserver}")
+                })?
+        }
+        (Some(nameserver), Some(proxy)) => {
+            let stream = proxy
+                .connect(&ProxyTarget::Addr(nameserver))
+                .await
+                .change_context(DnsLookupError)
+                .attach_printable_lazy(|| {
+                    format!("Failed to connect to nameserver {nameserver} through the proxy")
+                })?;
+            dns::lookup_tcp(stream, &nameserver.to_string(), dns)
+                .await
+                .attach_printable_lazy(|| {
+                    format!("Failed to lookup dns seeds by URL {dns:?} at {nameserver}")
+                })?
+        }
+        // Tor answers a single address of the name
+        (None, Some(proxy)) => vec![proxy
+            .resolve(dns)
+            .await
+            .change_context(DnsLookupError)
+            .attach_printable_lazy(|| {
+                format!("Failed to lookup dns seeds by URL {dns:?} through the proxy")
+            })?],
+        (None, None) => {
+            return Ok(tokio::net::lookup_host((dns, port))
+                .await
+                .into_report()
+                .attach_printable_lazy(|| {
+                    format!("Failed to lookup dns seeds by URL {:?}", (dns, port))
+                })
+                .change_context(DnsLookupError)?
+                .collect())
+        }
+    };
+    Ok(addresses
+        .into_iter()
+        .map(|ip| net::SocketAddr::new(ip, port))
+        .collect())
 }
@@ -0,0 +1,57 @@
+//! ElligatorSwift encoded ephemeral keys and the x-only ECDH of
+//! [BIP324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki), provided by libsecp256k1
+//! through the `secp256k1` crate.
+//!
+//! A public key is sent as 64 bytes `u || t` that are indistinguishable from random. See the ElligatorSwift paper
+//! (<https://eprint.iacr.org/2022/759>).
+use rand::Rng;
+use secp256k1::{
+    ellswift::{ElligatorSwift, ElligatorSwiftParty},
+    Secp256k1, SecretKey,
+};
+
+/// Size of an encoded public key
+pub(crate) const ENCODING_SIZE: usize = 64;
+
+/// Ephemeral secp256k1 key of a v2 connection with the ElligatorSwift encoding of its public key
+pub(crate) struct EllSwiftKey {
+    secret: SecretKey,
+    encoding: [u8; ENCODING_SIZE],
+}
+
+impl EllSwiftKey {
+    /// Generates a random key and encodes its public key
+    pub(crate) fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let secret = SecretKey::new(&mut rng);
+        let encoding = ElligatorSwift::from_seckey(&Secp256k1::new(), secret, Some(rng.gen()));
+        Self {
+            secret,
+            encoding: encoding.to_array(),
+        }
+    }
+
+    /// Returns the encoded public key
+    pub(crate) fn encoding(&self) -> &[u8; ENCODING_SIZE] {
+        &self.encoding
+    }
+
+    /// Returns the BIP324 shared secret with the `remote_encoding`: the tagged hash of both encodings,
+    /// the initiator's first, and the x coordinate of the ECDH point
+    pub(crate) fn shared_secret(
+        &self,
+        remote_encoding: &[u8; ENCODING_SIZE],
+        initiator: bool,
+    ) -> [u8; 32] {
+        let (local, remote) = (
+            ElligatorSwift::from_array(self.encoding),
+            ElligatorSwift::from_array(*remote_encoding),
+        );
+        let (ellswift_a, ellswift_b, party) = match initiator {
+            true => (local, remote, ElligatorSwiftParty::A),
+            false => (remote, local, ElligatorSwiftParty::B),
+        };
+        ElligatorSwift::shared_secret(ellswift_a, ellswift_b, self.secret, party, None)
+            .to_secret_bytes()
+    }
+}
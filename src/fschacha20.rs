@@ -0,0 +1,123 @@
+//! The forward secure ciphers `FSChaCha20` and `FSChaCha20Poly1305` of
+//! [BIP324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki) that rekey after every 224 messages,
+//! built on ChaCha20 and the ChaCha20-Poly1305 AEAD of [RFC 8439](https://www.rfc-editor.org/rfc/rfc8439)
+//! of the `chacha20` and `chacha20poly1305` crates.
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+
+/// Size of the ChaCha20 key
+pub(crate) const KEY_SIZE: usize = 32;
+/// Size of the Poly1305 tag appended to the AEAD ciphertext
+pub(crate) const TAG_SIZE: usize = 16;
+/// Number of messages encrypted with the same key, as in BIP324 `REKEY_INTERVAL`
+const REKEY_INTERVAL: u32 = 224;
+
+/// Returns the 96 bit nonce made of a 32 bit and a 64 bit little-endian counter
+fn nonce(low: u32, high: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&low.to_le_bytes());
+    nonce[4..].copy_from_slice(&high.to_le_bytes());
+    nonce
+}
+
+/// Stream cipher of the BIP324 packet lengths: a continuous ChaCha20 keystream,
+/// rekeyed with its own output after every 224 encrypted chunks
+pub(crate) struct FSChaCha20 {
+    cipher: ChaCha20,
+    chunk_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FSChaCha20 {
+    pub(crate) fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self::with_rekey_counter(key, 0)
+    }
+
+    fn with_rekey_counter(key: [u8; KEY_SIZE], rekey_counter: u64) -> Self {
+        Self {
+            cipher: ChaCha20::new(&key.into(), &nonce(0, rekey_counter).into()),
+            chunk_counter: 0,
+            rekey_counter,
+        }
+    }
+
+    /// Encrypts or decrypts the `chunk` in place
+    pub(crate) fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+
+        self.chunk_counter += 1;
+        if self.chunk_counter == REKEY_INTERVAL {
+            let mut key = [0u8; KEY_SIZE];
+            self.cipher.apply_keystream(&mut key);
+            // The rest of the keystream of the old key is discarded
+            *self = Self::with_rekey_counter(key, self.rekey_counter + 1);
+        }
+    }
+}
+
+/// AEAD of the BIP324 packets: ChaCha20-Poly1305 with the packet counter as nonce,
+/// rekeyed after every 224 packets
+pub(crate) struct FSChaCha20Poly1305 {
+    cipher: ChaCha20Poly1305,
+    packet_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FSChaCha20Poly1305 {
+    pub(crate) fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            packet_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    /// Encrypts the next packet, returns the ciphertext followed by the tag.
+    /// `None` if the `plaintext` exceeds the limit of the AEAD, 256 GiB.
+    pub(crate) fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+        let mut ciphertext = plaintext.to_vec();
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&self.nonce().into(), aad, &mut ciphertext)
+            .ok();
+        self.next_packet();
+        ciphertext.extend_from_slice(&tag?);
+        Some(ciphertext)
+    }
+
+    /// Decrypts the next packet, `None` if it is not authentic
+    pub(crate) fn decrypt(&mut self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len().checked_sub(TAG_SIZE)?);
+        let mut plaintext = ciphertext.to_vec();
+        let authentic = self
+            .cipher
+            .decrypt_in_place_detached(&self.nonce().into(), aad, &mut plaintext, tag.into())
+            .is_ok();
+        self.next_packet();
+        authentic.then_some(plaintext)
+    }
+
+    fn nonce(&self) -> [u8; 12] {
+        nonce(self.packet_counter, self.rekey_counter)
+    }
+
+    fn next_packet(&mut self) {
+        self.packet_counter += 1;
+        if self.packet_counter == REKEY_INTERVAL {
+            // The new key is the keystream that encrypts 32 zero bytes with the reserved packet counter 0xffffffff,
+            // 32 bytes never exceed the limit of the AEAD
+            let mut key = [0u8; KEY_SIZE];
+            let _ = self.cipher.encrypt_in_place_detached(
+                &nonce(u32::MAX, self.rekey_counter).into(),
+                &[],
+                &mut key,
+            );
+            self.cipher = ChaCha20Poly1305::new(&key.into());
+            self.packet_counter = 0;
+            self.rekey_counter += 1;
+        }
+    }
+}
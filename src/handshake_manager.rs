@@ -1,19 +1,37 @@
-use bitcoin::{
-    consensus::Decodable,
-    network::message::{NetworkMessage, RawNetworkMessage},
-};
+use bitcoin::{network::message::NetworkMessage, Network};
 use error_stack::{IntoReport, Report, Result, ResultExt};
+use futures::{stream, StreamExt};
 use log::{error, info};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fmt,
-    io::{BufReader, Write},
-    net::{SocketAddr, TcpStream},
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+    time::timeout,
 };
-use tokio::time::timeout;
 
-use crate::network_messages;
+use crate::{
+    constants,
+    handshake_outcome::{
+        ConnectionDirection, HandshakeFeatures, HandshakeOutcome, HandshakeStatus,
+        HandshakeTimings, PeerVersion,
+    },
+    handshake_policy::HandshakePolicy,
+    network_messages::{self, VersionParams},
+    peer_addr::PeerAddr,
+    peer_session::PeerSession,
+    proxy::ProxySettings,
+    transport::Connection,
+    v2_transport::V2Unsupported,
+};
 
 /// Top level handshake error - i.e. general error
 #[derive(Debug)]
@@ -27,32 +45,100 @@ impl fmt::Display for HandshakeError {
 
 impl Error for HandshakeError {}
 
-/// Handshake Timeout Error
-#[derive(Debug)]
-struct HandshakeTimeoutError;
+impl HandshakeError {
+    /// Returns the kind of failure attached to a failed handshake `report`
+    pub fn kind(report: &Report<HandshakeError>) -> HandshakeErrorKind {
+        report
+            .downcast_ref::<HandshakeErrorKind>()
+            .copied()
+            .unwrap_or(HandshakeErrorKind::Other)
+    }
+}
 
-impl fmt::Display for HandshakeTimeoutError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Handshake error: Main handshake function failed")
+/// Kind of a handshake failure, attached to every `HandshakeError` report.
+///
+/// Use `HandshakeError::kind` or `Report::downcast_ref::<HandshakeErrorKind>` to get it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum HandshakeErrorKind {
+    /// The remote peer refused the TCP connection
+    ConnectRefused,
+    /// The TCP connection failed for any other reason, e.g. the network is unreachable
+    ConnectFailed,
+    /// The TCP connection was not established in time
+    ConnectTimeout,
+    /// The SOCKS5 proxy could not be reached, rejected the credentials or failed the request
+    ProxyFailed,
+    /// The remote peer did not respond in time
+    ReadTimeout,
+    /// The remote peer sent a message that is not expected at this step of the handshake
+    UnexpectedMessage,
+    /// The remote peer sent a message with the magic of another network
+    BadMagic,
+    /// The checksum of a received message does not match its payload
+    ChecksumMismatch,
+    /// A received message could not be decoded
+    MalformedMessage,
+    /// The remote peer closed the connection
+    PeerDisconnected,
+    /// The remote peer advertised a protocol version that is too old
+    VersionTooOld,
+    /// The remote peer does not advertise the required services
+    MissingServices,
+    /// The remote timestamp differs from the local clock by more than allowed
+    ClockSkew,
+    /// The local peer connected to itself
+    SelfConnection,
+    /// Any other I/O error on an established connection
+    Io,
+    /// The failure was not classified
+    Other,
+}
+
+impl HandshakeErrorKind {
+    /// Returns a stable snake_case name of the kind
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HandshakeErrorKind::ConnectRefused => "connect_refused",
+            HandshakeErrorKind::ConnectFailed => "connect_failed",
+            HandshakeErrorKind::ConnectTimeout => "connect_timeout",
+            HandshakeErrorKind::ProxyFailed => "proxy_failed",
+            HandshakeErrorKind::ReadTimeout => "read_timeout",
+            HandshakeErrorKind::UnexpectedMessage => "unexpected_message",
+            HandshakeErrorKind::BadMagic => "bad_magic",
+            HandshakeErrorKind::ChecksumMismatch => "checksum_mismatch",
+            HandshakeErrorKind::MalformedMessage => "malformed_message",
+            HandshakeErrorKind::PeerDisconnected => "peer_disconnected",
+            HandshakeErrorKind::VersionTooOld => "version_too_old",
+            HandshakeErrorKind::MissingServices => "missing_services",
+            HandshakeErrorKind::ClockSkew => "clock_skew",
+            HandshakeErrorKind::SelfConnection => "self_connection",
+            HandshakeErrorKind::Io => "io",
+            HandshakeErrorKind::Other => "other",
+        }
     }
 }
 
-impl Error for HandshakeTimeoutError {}
+impl fmt::Display for HandshakeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
-/// Handshake Thread Error
+/// Handshake Timeout Error
 #[derive(Debug)]
-struct HandshakeThreadError;
+struct HandshakeTimeoutError;
 
-impl fmt::Display for HandshakeThreadError {
+impl fmt::Display for HandshakeTimeoutError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Handshake thread error: Main handshake function failed to join working thread"
+            "Handshake timeout error: Main handshake function did not complete in time"
         )
     }
 }
 
-impl Error for HandshakeThreadError {}
+impl Error for HandshakeTimeoutError {}
 
 /// Handshake Message Exchange Error
 #[derive(Debug)]
@@ -96,192 +182,956 @@ impl fmt::Display for HandshakeMessageVerAckError {
 
 impl Error for HandshakeMessageVerAckError {}
 
-/// HandshakeManager - provides handshake functionality. Tracks the status of a handshake by `remote` SocketAddr.
+/// Timeouts of the handshake phases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimeouts {
+    /// Time allowed to establish the TCP connection
+    pub connect: Duration,
+    /// Time allowed to exchange the `version` messages
+    pub version: Duration,
+    /// Time allowed to exchange the `verack` messages
+    pub verack: Duration,
+}
+
+impl Default for HandshakeTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_millis(2000),
+            version: Duration::from_millis(2000),
+            verack: Duration::from_millis(2000),
+        }
+    }
+}
+
+/// Default interval between the `ping` messages of a `PeerSession`, as in Bitcoin Core `PING_INTERVAL`
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Default number of received messages buffered by a `PeerSession` until they are taken with `recv`
+const DEFAULT_SESSION_BUFFER_SIZE: usize = 1024;
+
+/// Default features announced to the remote peers: `sendaddrv2`, so that the `addrv2` gossip is received
+const DEFAULT_FEATURES: HandshakeFeatures = HandshakeFeatures {
+    wtxid_relay: false,
+    addr_v2: true,
+};
+
+/// Phase of the handshake bounded by its own timeout
+#[derive(Debug, Clone, Copy)]
+enum HandshakePhase {
+    Connect,
+    Version,
+    Verack,
+}
+
+impl HandshakePhase {
+    /// Returns the timeout of the phase
+    fn timeout(&self, timeouts: &HandshakeTimeouts) -> Duration {
+        match self {
+            HandshakePhase::Connect => timeouts.connect,
+            HandshakePhase::Version => timeouts.version,
+            HandshakePhase::Verack => timeouts.verack,
+        }
+    }
+
+    /// Returns the kind of failure reported when the phase times out
+    fn timeout_kind(&self) -> HandshakeErrorKind {
+        match self {
+            HandshakePhase::Connect => HandshakeErrorKind::ConnectTimeout,
+            HandshakePhase::Version | HandshakePhase::Verack => HandshakeErrorKind::ReadTimeout,
+        }
+    }
+}
+
+/// Nonces of the local `version` messages sent on the outbound connections whose handshake is in flight,
+/// as Bitcoin Core `CheckIncomingNonce`: a remote `version` that carries one of them comes from the local node
+#[derive(Debug, Clone, Default)]
+struct LocalNonces(Arc<Mutex<HashSet<u64>>>);
+
+impl LocalNonces {
+    /// Keeps the `nonce` of an outbound handshake until the returned guard is dropped
+    fn register(&self, nonce: u64) -> LocalNonceGuard {
+        if let Ok(mut nonces) = self.0.lock() {
+            nonces.insert(nonce);
+        }
+        LocalNonceGuard {
+            nonces: self.clone(),
+            nonce,
+        }
+    }
+}
+
+/// Nonce of an outbound handshake in flight, forgotten when the handshake ends, or is cancelled
+struct LocalNonceGuard {
+    nonces: LocalNonces,
+    nonce: u64,
+}
+
+impl Drop for LocalNonceGuard {
+    fn drop(&mut self) {
+        if let Ok(mut nonces) = self.nonces.0.lock() {
+            nonces.remove(&self.nonce);
+        }
+    }
+}
+
+/// Settings shared by every handshake of a `HandshakeManager`
+#[derive(Debug, Clone)]
+struct HandshakeSettings {
+    network: Network,
+    timeouts: HandshakeTimeouts,
+    version_params: VersionParams,
+    policy: HandshakePolicy,
+    features: HandshakeFeatures,
+    ping_interval: Duration,
+    session_buffer_size: usize,
+    proxy: Option<ProxySettings>,
+    v2_transport: bool,
+    local_nonces: LocalNonces,
+}
+
+/// HandshakeManager - provides handshake functionality. Tracks the status of a handshake by `remote` PeerAddr.
+///
+/// The remotes are given as `SocketAddr` or as `PeerAddr`. Tor v3 and I2P peers are reached through the proxy
+/// set with `HandshakeManager::set_proxy`, and `sendaddrv2` is always announced to them.
+///
+/// A clone shares the nonces of the outbound handshakes in flight with the manager it was cloned from,
+/// so that a clone accepting inbound connections rejects the connections the other one makes to itself.
+#[derive(Clone)]
 pub struct HandshakeManager {
-    timeout_ms: u64,
-    statuses: HashMap<SocketAddr, bool>,
+    settings: HandshakeSettings,
+    statuses: HashMap<PeerAddr, HandshakeStatus>,
 }
 
 /// Default trait implementation for `HandshakeManager`
 impl Default for HandshakeManager {
     fn default() -> Self {
+        Self::new(Network::Bitcoin)
+    }
+}
+
+impl HandshakeManager {
+    /// Construct a new HandshakeManager that performs handshakes on the given `network`
+    pub fn new(network: Network) -> Self {
         Self {
-            timeout_ms: 2000,
+            settings: HandshakeSettings {
+                network,
+                timeouts: HandshakeTimeouts::default(),
+                version_params: VersionParams::default(),
+                policy: HandshakePolicy::default(),
+                features: DEFAULT_FEATURES,
+                ping_interval: DEFAULT_PING_INTERVAL,
+                session_buffer_size: DEFAULT_SESSION_BUFFER_SIZE,
+                proxy: None,
+                v2_transport: false,
+                local_nonces: LocalNonces::default(),
+            },
             statuses: HashMap::new(),
         }
     }
-}
 
-impl HandshakeManager {
-    /// Perform a handshake with a `remote` SocketAddr.
-    /// Returns `true` if the handshake was successful, `false` otherwise.
+    /// Perform a handshake with a `remote` SocketAddr or PeerAddr.
+    /// Returns the `HandshakeOutcome` if the handshake was successful, an error otherwise.
+    /// Either way the status of the handshake is recorded in the manager.
     pub async fn establish_handshake(
         &mut self,
-        remote: SocketAddr,
-    ) -> Result<bool, HandshakeError> {
-        // 1. Spawn a new task the performs the message exchange
-        let handshake_jh = tokio::spawn(async move { exec_handshake(remote).await });
-
-        // 2. Expect the handshake to be completed in specified timeout
-        let timeout_result = timeout(
-            std::time::Duration::from_millis(self.timeout_ms),
-            handshake_jh,
-        )
-        .await;
+        remote: impl Into<PeerAddr>,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        let remote = remote.into();
+        let result = self
+            .try_handshake(&remote)
+            .await
+            .map(|(outcome, _)| outcome);
+        self.record_result(remote, result.as_ref());
+        result
+    }
+
+    /// Perform a handshake with a `remote` SocketAddr or PeerAddr and keep the connection open.
+    /// Returns the `PeerSession` that owns the connection if the handshake was successful, an error otherwise.
+    /// Either way the status of the handshake is recorded in the manager.
+    pub async fn establish_session(
+        &mut self,
+        remote: impl Into<PeerAddr>,
+    ) -> Result<PeerSession, HandshakeError> {
+        let remote = remote.into();
+        let result = self.try_session(&remote).await;
+        self.record_result(remote, result.as_ref().map(PeerSession::outcome));
+        result
+    }
+
+    /// Accept a single inbound connection on the `listener` and perform the inbound handshake.
+    /// The remote peer is expected to send its `version` first.
+    /// Either way the status of the handshake is recorded in the manager.
+    pub async fn accept_handshake(
+        &mut self,
+        listener: &TcpListener,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        let (stream, remote) = accept(listener).await?;
 
-        // Handle Timeout result
-        let jh_result = timeout_result
-            .into_report()
+        let result = inbound_handshake(stream, &self.settings)
+            .await
+            .map(|(outcome, _)| outcome);
+        self.record_result(remote.into(), result.as_ref());
+        result
+    }
+
+    /// Accept a single inbound connection on the `listener`, perform the inbound handshake and keep the connection open.
+    /// Either way the status of the handshake is recorded in the manager.
+    pub async fn accept_session(
+        &mut self,
+        listener: &TcpListener,
+    ) -> Result<PeerSession, HandshakeError> {
+        let (stream, remote) = accept(listener).await?;
+        let result = inbound_handshake(stream, &self.settings)
+            .await
+            .map(|(outcome, connection)| self.start_session(connection, outcome));
+        self.record_result(remote.into(), result.as_ref().map(PeerSession::outcome));
+        result
+    }
+
+    /// Accept inbound connections on the `listener` and perform the inbound handshakes concurrently,
+    /// running at most `concurrency` handshakes at a time, so a stalled peer does not hold up the others.
+    /// Every result is passed to `on_result` with the address of the remote peer as soon as it completes
+    /// and the statuses of the handshakes are recorded in the manager. A failure to accept a connection is logged.
+    /// Runs until the future is dropped.
+    pub async fn accept_handshakes<F>(
+        &mut self,
+        listener: &TcpListener,
+        concurrency: usize,
+        mut on_result: F,
+    ) where
+        F: FnMut(SocketAddr, Result<HandshakeOutcome, HandshakeError>),
+    {
+        let concurrency = concurrency.max(1);
+        let mut handshakes: JoinSet<(SocketAddr, Result<HandshakeOutcome, HandshakeError>)> =
+            JoinSet::new();
+        loop {
+            tokio::select! {
+                Some(joined) = handshakes.join_next() => {
+                    let (remote, result) = match joined {
+                        Ok(joined) => joined,
+                        Err(e) => {
+                            error!("Inbound handshake task failed: {e}");
+                            continue;
+                        }
+                    };
+                    self.record_result(remote.into(), result.as_ref());
+                    on_result(remote, result);
+                }
+                accepted = accept(listener), if handshakes.len() < concurrency => {
+                    match accepted {
+                        Ok((stream, remote)) => {
+                            let settings = self.settings.clone();
+                            handshakes.spawn(async move {
+                                let result = inbound_handshake(stream, &settings)
+                                    .await
+                                    .map(|(outcome, _)| outcome);
+                                (remote, result)
+                            });
+                        }
+                        Err(report) => error!("Inbound connection failed: \n{report:?}"),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Perform handshakes with all `remotes` concurrently, running at most `concurrency` handshakes at a time.
+    /// Returns the results in the order of completion. Every status is recorded in the manager.
+    pub async fn establish_handshakes<A: Into<PeerAddr> + Clone>(
+        &mut self,
+        remotes: Vec<A>,
+        concurrency: usize,
+    ) -> Vec<(A, Result<HandshakeOutcome, HandshakeError>)> {
+        let manager = &*self;
+        let results: Vec<_> = stream::iter(remotes)
+            .map(|remote| async move {
+                let result = manager
+                    .try_handshake(&remote.clone().into())
+                    .await
+                    .map(|(outcome, _)| outcome);
+                (remote, result)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+
+        for (remote, result) in results.iter() {
+            self.record_result(remote.clone().into(), result.as_ref());
+        }
+        results
+    }
+
+    /// Runs the message exchange with a `remote` PeerAddr bounded by the handshake timeouts
+    async fn try_handshake(
+        &self,
+        remote: &PeerAddr,
+    ) -> Result<(HandshakeOutcome, Connection), HandshakeError> {
+        exec_handshake(remote, &self.settings)
+            .await
+            .attach_printable("Handshake message exchange failed")
             .change_context(HandshakeError)
-            .attach_printable_lazy(|| format!("Handshake timed out after {}ms", self.timeout_ms))?;
+    }
 
-        // Handle JoinHandle result
-        let hs_result = jh_result
-            .into_report()
-            .change_context(HandshakeThreadError)
-            .attach_printable_lazy(|| format!("Handshake thread failed to join"))
-            .change_context(HandshakeError)?;
+    /// Runs the message exchange with a `remote` PeerAddr and starts a session without recording the status
+    pub(crate) async fn try_session(
+        &self,
+        remote: &PeerAddr,
+    ) -> Result<PeerSession, HandshakeError> {
+        self.try_handshake(remote)
+            .await
+            .map(|(outcome, connection)| self.start_session(connection, outcome))
+    }
 
-        // Handle Handshake result
-        let hs_status = hs_result
-            .change_context(HandshakeMessageExchangeError)
-            .attach_printable_lazy(|| format!("Handshake message exchange failed"))
-            .change_context(HandshakeError)?;
+    /// Starts a session on the `connection` of a completed handshake
+    fn start_session(&self, connection: Connection, outcome: HandshakeOutcome) -> PeerSession {
+        PeerSession::start(
+            connection,
+            outcome,
+            self.settings.network,
+            self.settings.ping_interval,
+            self.settings.session_buffer_size,
+        )
+    }
+
+    /// Returns the timeouts of the handshake phases
+    pub fn timeouts(&self) -> HandshakeTimeouts {
+        self.settings.timeouts
+    }
+
+    /// Sets the timeouts of the handshake phases
+    pub fn set_timeouts(&mut self, timeouts: HandshakeTimeouts) {
+        self.settings.timeouts = timeouts;
+    }
+
+    /// Returns the fields of the `version` message sent to the remote peers
+    pub fn version_params(&self) -> &VersionParams {
+        &self.settings.version_params
+    }
 
-        Ok(hs_status)
+    /// Sets the fields of the `version` message sent to the remote peers
+    pub fn set_version_params(&mut self, version_params: VersionParams) {
+        self.settings.version_params = version_params;
+    }
+
+    /// Returns the acceptance policy applied to the remote `version` messages
+    pub fn policy(&self) -> HandshakePolicy {
+        self.settings.policy
+    }
+
+    /// Sets the acceptance policy applied to the remote `version` messages
+    pub fn set_policy(&mut self, policy: HandshakePolicy) {
+        self.settings.policy = policy;
+    }
+
+    /// Returns the optional features announced to the remote peers
+    pub fn features(&self) -> HandshakeFeatures {
+        self.settings.features
+    }
+
+    /// Sets the optional features announced to the remote peers, `sendaddrv2` by default.
+    /// They are announced only when both peers support protocol version 70016 or newer.
+    pub fn set_features(&mut self, features: HandshakeFeatures) {
+        self.settings.features = features;
+    }
+
+    /// Returns the interval between the `ping` messages sent by the sessions
+    pub fn ping_interval(&self) -> Duration {
+        self.settings.ping_interval
+    }
+
+    /// Sets the interval between the `ping` messages sent by the sessions
+    pub fn set_ping_interval(&mut self, ping_interval: Duration) {
+        self.settings.ping_interval = ping_interval;
+    }
+
+    /// Returns the number of received messages a session buffers until they are taken with `recv`
+    pub fn session_buffer_size(&self) -> usize {
+        self.settings.session_buffer_size
+    }
+
+    /// Sets the number of received messages a session buffers until they are taken with `recv`.
+    /// A session with a full buffer stops reading from its connection, and pinging, until `recv` makes room.
+    pub fn set_session_buffer_size(&mut self, session_buffer_size: usize) {
+        self.settings.session_buffer_size = session_buffer_size;
+    }
+
+    /// Returns the SOCKS5 proxy of the outbound connections, `None` if they are direct
+    pub fn proxy(&self) -> Option<&ProxySettings> {
+        self.settings.proxy.as_ref()
+    }
+
+    /// Sets the SOCKS5 proxy of the outbound connections, `None` to connect directly.
+    /// The local address is not announced in the `version` message of a proxied connection.
+    pub fn set_proxy(&mut self, proxy: Option<ProxySettings>) {
+        self.settings.proxy = proxy;
+    }
+
+    /// Returns `true` if the BIP324 v2 transport is offered to the remote peers
+    pub fn v2_transport(&self) -> bool {
+        self.settings.v2_transport
+    }
+
+    /// Sets whether the BIP324 v2 transport is offered to the remote peers.
+    /// Outbound connections fall back to the v1 transport when the remote peer does not support v2,
+    /// inbound connections accept both transports.
+    pub fn set_v2_transport(&mut self, v2_transport: bool) {
+        self.settings.v2_transport = v2_transport;
+    }
+
+    /// Records the status of a handshake `result` with a `remote` PeerAddr
+    fn record_result(
+        &mut self,
+        remote: PeerAddr,
+        result: std::result::Result<&HandshakeOutcome, &Report<HandshakeError>>,
+    ) {
+        let status = match result {
+            Ok(outcome) => HandshakeStatus::Completed(Box::new(outcome.clone())),
+            Err(e) => HandshakeStatus::Failed(HandshakeError::kind(e)),
+        };
+        self.record_handshake(remote, status);
     }
 
     /// Adde record entry to the handshake statuses
-    pub fn record_handshake(&mut self, remote: SocketAddr, status: bool) {
-        self.statuses.insert(remote, status);
+    pub fn record_handshake(&mut self, remote: impl Into<PeerAddr>, status: HandshakeStatus) {
+        self.statuses.insert(remote.into(), status);
+    }
+
+    /// Returns the recorded handshake status of a `remote` SocketAddr or PeerAddr
+    pub fn status<A: Into<PeerAddr> + Copy>(&self, remote: &A) -> Option<&HandshakeStatus> {
+        self.statuses.get(&(*remote).into())
+    }
+
+    /// Returns all recorded handshake statuses
+    pub fn statuses(&self) -> &HashMap<PeerAddr, HandshakeStatus> {
+        &self.statuses
     }
 
     /// Print all recorded handshake statuses into the terminal
     pub fn _print_statuses(&self) {
         for (addr, status) in self.statuses.iter() {
-            info!("Remote peer: {}, handshake status: {}", addr, status);
+            match status {
+                HandshakeStatus::Completed(outcome) => info!(
+                    "Remote peer: {}, handshake completed, version: {}, user agent: {}, total: {:?}",
+                    addr, outcome.negotiated_version, outcome.peer.user_agent, outcome.timings.total
+                ),
+                HandshakeStatus::Failed(kind) => {
+                    info!("Remote peer: {}, handshake failed: {}", addr, kind)
+                }
+            }
         }
     }
 }
 
 /// Implements version handshake protocol as follows:
 ///
+/// ```text
 /// =============================================================================
 ///
 ///     L -> R: Send version message with the local peer's version
 ///     R -> L: Send version message back
+///     R -> L: Send wtxidrelay and sendaddrv2 messages, if any, in any order
 ///     R -> L: Send verack message
 ///     R:      Sets version to the minimum of the 2 versions
+///     L -> R: Send wtxidrelay and sendaddrv2 messages, if enabled
 ///     L -> R: Send verack message after receiving version message from R
 ///     L:      Sets version to the minimum of the 2 versions
 ///
 /// =============================================================================
+/// ```
 ///
-/// Returns result that indicates if the handshake was successful or not.
-/// Failed message exchange error represented by `HandshakeMessageExchangeError`.
-async fn exec_handshake(remote: SocketAddr) -> Result<bool, HandshakeMessageExchangeError> {
-    match TcpStream::connect(remote) {
-        Ok(mut stream) => {
-            let read_stream = stream
-                .try_clone()
-                .into_report()
-                .attach_printable_lazy(|| format!("Failed to clone handshake stream"))
-                .change_context(HandshakeMessageExchangeError)?;
-            let mut stream_reader = BufReader::new(read_stream);
-            let local_peer: SocketAddr = stream
-                .local_addr()
-                .into_report()
-                .attach_printable_lazy(|| {
-                    format!("Failed to return local half of the TCP connection")
-                })
-                .change_context(HandshakeMessageExchangeError)?;
-            let remote_peer: SocketAddr = stream
-                .peer_addr()
-                .into_report()
-                .attach_printable_lazy(|| {
-                    format!("Failed to return remote half of the TCP connection")
-                })
-                .change_context(HandshakeMessageExchangeError)?;
+/// The remote `version` is checked against the `HandshakePolicy` before the local `verack` is sent.
+/// Tor and I2P peers are always sent `sendaddrv2`, announcing at least protocol version 70016 for it.
+/// If the v2 transport is enabled, the BIP324 handshake is performed in the connect phase,
+/// a remote peer that closes the connection instead is connected again with the v1 transport.
+/// Each of the connect, version and verack phases must complete in its own timeout.
+/// Dropping the pending I/O on timeout cancels it.
+///
+/// Returns the `HandshakeOutcome` with the remote version and phase timings, and the connection
+/// if the handshake was successful.
+/// Failed message exchange error represented by `HandshakeMessageExchangeError`
+/// with the `HandshakeErrorKind` attached.
+async fn exec_handshake(
+    remote: &PeerAddr,
+    settings: &HandshakeSettings,
+) -> Result<(HandshakeOutcome, Connection), HandshakeMessageExchangeError> {
+    let (network, timeouts) = (settings.network, &settings.timeouts);
+    let handshake_start = Instant::now();
+    let mut connection = within_phase(
+        HandshakePhase::Connect,
+        timeouts,
+        open_connection(remote, settings),
+    )
+    .await?;
+    let connect_duration = handshake_start.elapsed();
+    let (local_addr, connected_addr) = connection_addrs(&connection)?;
+    let (local_peer, remote_peer) = match settings.proxy {
+        // The connection ends at the proxy: announce the target and keep the local address private
+        Some(_) => (SocketAddr::from(([0, 0, 0, 0], 0)), remote.clone()),
+        None => (local_addr, connected_addr.into()),
+    };
+    // Tor and I2P peers gossip their own kind of addresses only in `addrv2`
+    let mut features = settings.features;
+    features.addr_v2 |= remote_peer.is_overlay();
 
-            // Make and send Version message
-            let (protocol_version_local, version_message_bytes) =
-                network_messages::new_version_message_serialised(local_peer, remote_peer);
-            info!("Send version message {protocol_version_local} to {remote}");
-            stream
-                .write_all(version_message_bytes.as_slice())
-                .into_report()
-                .attach_printable_lazy(|| format!("Failed to send Version message"))
-                .change_context(HandshakeMessageExchangeError)?;
+    // Make and send Version message, then wait for the version message from the remote peer
+    let version_start = Instant::now();
+    // The local nonce is kept until the handshake is over, to detect a connection to the local listener
+    let (protocol_version_local, peer_version, _nonce_guard) =
+        within_phase(HandshakePhase::Version, timeouts, async {
+            let (protocol_version_local, nonce_guard) = send_version(
+                &mut connection,
+                settings,
+                local_peer,
+                &remote_peer,
+                Some(&settings.local_nonces),
+            )
+            .await?;
+            let peer_version = recv_version(&mut connection, network, &remote_peer).await?;
+            apply_policy(&mut connection, settings, &peer_version, &remote_peer).await?;
+            Ok((protocol_version_local, peer_version, nonce_guard))
+        })
+        .await?;
+    let version_duration = version_start.elapsed();
 
-            // Wait for the version message from the remote peer
-            let message_version_remote = RawNetworkMessage::consensus_decode(&mut stream_reader)
-                .into_report()
-                .attach_printable_lazy(|| {
-                    format!("Failed to receive and decode Version message from the remote peer")
-                })
-                .change_context(HandshakeMessageExchangeError)?;
-            let message_version_remote = message_version_remote.payload;
-
-            let protocol_version_remote = match message_version_remote {
-                NetworkMessage::Version(protocol_version_remote) => protocol_version_remote.version,
-                _ => {
-                    return Err(
-                        Report::new(HandshakeMessageWrongProtocolError).attach_printable(format!(
-                            "Received unexpected protocol version: {:?}",
-                            message_version_remote
-                        )),
-                    )
-                    .change_context(HandshakeMessageExchangeError)
-                }
-            };
-            info!("Recv version message {protocol_version_remote} from {remote}");
-
-            // Make and send VerAck message to the remote peer
-            let message_verack_bytes = network_messages::make_verack_message_serialised();
-            stream
-                .write_all(message_verack_bytes.as_slice())
-                .into_report()
-                .attach_printable_lazy(|| {
-                    format!("Failed to send VerAck message to the remote peer")
-                })
-                .change_context(HandshakeMessageExchangeError)?;
-            info!("Sent VerAck message to {remote}");
-
-            // Wait for the VerAck message from the remote peer
-            let message_verack_remote = RawNetworkMessage::consensus_decode(&mut stream_reader)
-                .into_report()
-                .attach_printable_lazy(|| {
-                    format!("Failed to receive and decode VerAck message from the remote peer")
-                })
+    // Make and send the feature and VerAck messages, then wait for the VerAck message from the remote peer
+    let negotiated_version = protocol_version_local.min(peer_version.version);
+    let verack_start = Instant::now();
+    let (local_features, peer_features) = within_phase(HandshakePhase::Verack, timeouts, async {
+        let local_features = send_features(
+            &mut connection,
+            settings.network,
+            features,
+            negotiated_version,
+            &remote_peer,
+        )
+        .await?;
+        send_verack(&mut connection, network, &remote_peer).await?;
+        let peer_features = recv_verack(&mut connection, network, &remote_peer).await?;
+        Ok((local_features, peer_features))
+    })
+    .await?;
+    let verack_duration = verack_start.elapsed();
+
+    let outcome = HandshakeOutcome {
+        local_addr,
+        remote_addr: remote_peer,
+        direction: ConnectionDirection::Outbound,
+        transport: connection.transport(),
+        local_version: protocol_version_local,
+        negotiated_version,
+        local_features,
+        peer_features,
+        negotiated_features: local_features.intersection(&peer_features),
+        peer: peer_version,
+        timings: HandshakeTimings {
+            connect: connect_duration,
+            version: version_duration,
+            verack: verack_duration,
+            total: handshake_start.elapsed(),
+        },
+    };
+    Ok((outcome, connection))
+}
+
+/// Runs the inbound message exchange on an accepted `stream` bounded by the handshake timeouts
+async fn inbound_handshake(
+    stream: TcpStream,
+    settings: &HandshakeSettings,
+) -> Result<(HandshakeOutcome, Connection), HandshakeError> {
+    exec_inbound_handshake(stream, settings)
+        .await
+        .attach_printable("Inbound handshake message exchange failed")
+        .change_context(HandshakeError)
+}
+
+/// Implements the inbound side of the version handshake protocol, where the remote peer `R`
+/// initiated the connection to the local peer `L`:
+///
+/// ```text
+/// =============================================================================
+///
+///     R -> L: Send version message with the remote peer's version
+///     L -> R: Send version message back
+///     L -> R: Send wtxidrelay and sendaddrv2 messages, if enabled
+///     L -> R: Send verack message
+///     L:      Sets version to the minimum of the 2 versions
+///     R -> L: Send wtxidrelay and sendaddrv2 messages, if any, in any order
+///     R -> L: Send verack message after receiving version message from L
+///
+/// =============================================================================
+/// ```
+///
+/// The remote `version` is checked against the `HandshakePolicy` before the local messages are sent.
+/// The version phase (waiting for the remote `version`) and the verack phase (replying and
+/// waiting for the remote `verack`) must each complete in its own timeout.
+/// If the v2 transport is enabled, it is detected from the first bytes sent by the remote peer
+/// and the BIP324 handshake is performed in the version phase.
+/// The connect phase of the returned timings is always zero, the version phase measures
+/// the wait for the remote `version` message after the connection was accepted.
+async fn exec_inbound_handshake(
+    stream: TcpStream,
+    settings: &HandshakeSettings,
+) -> Result<(HandshakeOutcome, Connection), HandshakeMessageExchangeError> {
+    let (network, timeouts) = (settings.network, &settings.timeouts);
+    let handshake_start = Instant::now();
+
+    // The remote peer must speak first
+    let (mut connection, local_peer, remote_peer, peer_version) =
+        within_phase(HandshakePhase::Version, timeouts, async {
+            let mut connection = Connection::accept(stream, network, settings.v2_transport)
+                .await
+                .attach_printable("Failed to detect the transport of the inbound connection")
                 .change_context(HandshakeMessageExchangeError)?;
+            let (local_peer, remote_peer) = connection_addrs(&connection)?;
+            let remote_peer = PeerAddr::from(remote_peer);
+            let peer_version = recv_version(&mut connection, network, &remote_peer).await?;
+            apply_policy(&mut connection, settings, &peer_version, &remote_peer).await?;
+            Ok((connection, local_peer, remote_peer, peer_version))
+        })
+        .await?;
+    let version_duration = handshake_start.elapsed();
 
-            let message_verack_remote = match message_verack_remote.payload {
-                NetworkMessage::Verack => message_verack_remote.payload,
-                _ => {
-                    error!(
-                        "Received unexpected message, but expected VerAck message: {:?}",
-                        message_verack_remote.payload
-                    );
-                    return Err(
-                        Report::new(HandshakeMessageVerAckError).attach_printable(format!(
-                            "Received unexpected message, but expected VerAck message: {:?}",
-                            message_verack_remote.payload
-                        )),
-                    )
-                    .change_context(HandshakeMessageExchangeError);
-                }
-            };
+    // Reply with the local version, features and verack, then wait for the VerAck message from the remote peer
+    let verack_start = Instant::now();
+    let (protocol_version_local, local_features, peer_features) =
+        within_phase(HandshakePhase::Verack, timeouts, async {
+            let (protocol_version_local, _) =
+                send_version(&mut connection, settings, local_peer, &remote_peer, None).await?;
+            let negotiated_version = protocol_version_local.min(peer_version.version);
+            let local_features = send_features(
+                &mut connection,
+                settings.network,
+                settings.features,
+                negotiated_version,
+                &remote_peer,
+            )
+            .await?;
+            send_verack(&mut connection, network, &remote_peer).await?;
+            let peer_features = recv_verack(&mut connection, network, &remote_peer).await?;
+            Ok((protocol_version_local, local_features, peer_features))
+        })
+        .await?;
+    let verack_duration = verack_start.elapsed();
+
+    let outcome = HandshakeOutcome {
+        local_addr: local_peer,
+        remote_addr: remote_peer,
+        direction: ConnectionDirection::Inbound,
+        transport: connection.transport(),
+        local_version: protocol_version_local,
+        negotiated_version: protocol_version_local.min(peer_version.version),
+        local_features,
+        peer_features,
+        negotiated_features: local_features.intersection(&peer_features),
+        peer: peer_version,
+        timings: HandshakeTimings {
+            connect: Duration::ZERO,
+            version: version_duration,
+            verack: verack_duration,
+            total: handshake_start.elapsed(),
+        },
+    };
+    Ok((outcome, connection))
+}
+
+/// Runs the `exchange` of a handshake `phase` that must complete in the phase timeout.
+/// An elapsed timeout is reported with the kind of the phase.
+async fn within_phase<T, F>(
+    phase: HandshakePhase,
+    timeouts: &HandshakeTimeouts,
+    exchange: F,
+) -> Result<T, HandshakeMessageExchangeError>
+where
+    F: Future<Output = Result<T, HandshakeMessageExchangeError>>,
+{
+    let phase_timeout = phase.timeout(timeouts);
+    timeout(phase_timeout, exchange)
+        .await
+        .into_report()
+        .change_context(HandshakeTimeoutError)
+        .attach(phase.timeout_kind())
+        .attach_printable_lazy(|| {
+            format!(
+                "Handshake {phase:?} phase timed out after {}ms",
+                phase_timeout.as_millis()
+            )
+        })
+        .change_context(HandshakeMessageExchangeError)?
+}
+
+/// Accepts a single inbound connection on the `listener`
+async fn accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr), HandshakeError> {
+    let (stream, remote) = listener
+        .accept()
+        .await
+        .into_report()
+        .attach(HandshakeErrorKind::Io)
+        .attach_printable("Failed to accept inbound connection")
+        .change_context(HandshakeError)?;
+    info!("Accepted inbound connection from {remote}");
+    Ok((stream, remote))
+}
 
-            info!("Recv VerAck message from {remote}: {message_verack_remote:?}");
+/// Establishes the TCP connection with a `remote` PeerAddr, through the `proxy` if any.
+/// Tor and I2P peers can only be reached through a proxy.
+async fn connect(
+    remote: &PeerAddr,
+    proxy: Option<&ProxySettings>,
+) -> Result<TcpStream, HandshakeMessageExchangeError> {
+    let remote = match (remote, proxy) {
+        (remote, Some(proxy)) => {
+            return proxy
+                .connect(&remote.proxy_target())
+                .await
+                .attach_printable_lazy(|| format!("Failed to connect to node: {remote}"))
+                .change_context(HandshakeMessageExchangeError);
         }
-        Err(e) => {
-            return Err(
-                Report::new(HandshakeMessageExchangeError).attach_printable(format!(
-                    "Failed to connect to node: {:?}, error: {:?}",
-                    remote, e
-                )),
-            );
+        (PeerAddr::Ip(remote), None) => remote,
+        (remote, None) => {
+            return Err(Report::new(HandshakeMessageExchangeError)
+                .attach(HandshakeErrorKind::ConnectFailed)
+                .attach_printable(format!("Node {remote} can only be reached through a proxy")));
         }
+    };
+    TcpStream::connect(remote).await.map_err(|e| {
+        let kind = match e.kind() {
+            io::ErrorKind::ConnectionRefused => HandshakeErrorKind::ConnectRefused,
+            _ => HandshakeErrorKind::ConnectFailed,
+        };
+        Report::new(e)
+            .attach(kind)
+            .attach_printable(format!("Failed to connect to node: {remote:?}"))
+            .change_context(HandshakeMessageExchangeError)
+    })
+}
+
+/// Establishes the connection with a `remote` PeerAddr and performs the v2 handshake if it is enabled.
+/// A remote peer that closes the connection instead of sending its v2 key is connected again with the v1 transport.
+async fn open_connection(
+    remote: &PeerAddr,
+    settings: &HandshakeSettings,
+) -> Result<Connection, HandshakeMessageExchangeError> {
+    let stream = connect(remote, settings.proxy.as_ref()).await?;
+    if !settings.v2_transport {
+        return Ok(Connection::v1(stream));
+    }
+    match Connection::initiate_v2(stream, settings.network).await {
+        Ok(connection) => {
+            info!("Established v2 transport with {remote}");
+            Ok(connection)
+        }
+        Err(report) if report.contains::<V2Unsupported>() => {
+            info!("Remote peer {remote} does not support the v2 transport, reconnect with v1");
+            connect(remote, settings.proxy.as_ref())
+                .await
+                .map(Connection::v1)
+        }
+        Err(report) => Err(report)
+            .attach_printable_lazy(|| format!("Failed to establish v2 transport with {remote}"))
+            .change_context(HandshakeMessageExchangeError),
+    }
+}
+
+/// Returns local and remote halves of the TCP connection
+fn connection_addrs(
+    connection: &Connection,
+) -> Result<(SocketAddr, SocketAddr), HandshakeMessageExchangeError> {
+    connection
+        .addrs()
+        .attach(HandshakeErrorKind::Io)
+        .attach_printable("Failed to return the halves of the TCP connection")
+        .change_context(HandshakeMessageExchangeError)
+}
+
+/// Makes and sends the local Version message from the `settings`.
+/// The nonce is registered in the `local_nonces`, if any, before the message is sent.
+/// Returns the local protocol version and the registered nonce.
+async fn send_version(
+    connection: &mut Connection,
+    settings: &HandshakeSettings,
+    local_peer: SocketAddr,
+    remote_peer: &PeerAddr,
+    local_nonces: Option<&LocalNonces>,
+) -> Result<(u32, Option<LocalNonceGuard>), HandshakeMessageExchangeError> {
+    // Tor and I2P peers need at least the version of the feature negotiation for `sendaddrv2`
+    let mut params = settings.version_params.clone();
+    if remote_peer.is_overlay()
+        && params.protocol_version() < constants::FEATURE_NEGOTIATION_VERSION
+    {
+        params = params.with_protocol_version(constants::FEATURE_NEGOTIATION_VERSION);
+    }
+    let (protocol_version_local, nonce_local, version_message_bytes) =
+        network_messages::new_version_message_serialised(
+            settings.network,
+            local_peer,
+            remote_peer,
+            &params,
+        );
+    let nonce_guard = local_nonces.map(|nonces| nonces.register(nonce_local));
+    info!("Send version message {protocol_version_local} to {remote_peer}");
+    connection
+        .write_message(&version_message_bytes)
+        .await
+        .attach_printable("Failed to send Version message")
+        .change_context(HandshakeMessageExchangeError)?;
+    Ok((protocol_version_local, nonce_guard))
+}
+
+/// Waits for the Version message from the remote peer
+async fn recv_version(
+    connection: &mut Connection,
+    network: Network,
+    remote_peer: &PeerAddr,
+) -> Result<PeerVersion, HandshakeMessageExchangeError> {
+    let message_version_remote = connection
+        .read_message(network.magic())
+        .await
+        .attach_printable("Failed to receive and decode Version message from the remote peer")
+        .change_context(HandshakeMessageExchangeError)?;
+    let message_version_remote = message_version_remote.payload;
+
+    let peer_version = match &message_version_remote {
+        NetworkMessage::Version(version_message_remote) => {
+            PeerVersion::from(version_message_remote)
+        }
+        _ => {
+            return Err(Report::new(HandshakeMessageWrongProtocolError)
+                .attach(HandshakeErrorKind::UnexpectedMessage)
+                .attach_printable(format!(
+                    "Received unexpected protocol version: {:?}",
+                    message_version_remote
+                )))
+            .change_context(HandshakeMessageExchangeError)
+        }
+    };
+    info!(
+        "Recv version message {} from {remote_peer}",
+        peer_version.version
+    );
+    Ok(peer_version)
+}
+
+/// Checks the remote `peer_version` against the policy of the `settings`.
+/// A rejected peer is disconnected gracefully if the policy asks for it.
+async fn apply_policy(
+    connection: &mut Connection,
+    settings: &HandshakeSettings,
+    peer_version: &PeerVersion,
+    remote_peer: &PeerAddr,
+) -> Result<(), HandshakeMessageExchangeError> {
+    let policy = &settings.policy;
+    let checked = match settings.local_nonces.0.lock() {
+        Ok(local_nonces) => policy.check(peer_version, &local_nonces),
+        Err(_) => policy.check(peer_version, &HashSet::new()),
+    };
+    let Err(report) = checked else {
+        return Ok(());
+    };
+
+    if policy.disconnect_gracefully {
+        if let Err(e) = connection.shutdown().await {
+            error!("Failed to shut down the connection with {remote_peer}: {e}");
+        }
+    }
+    Err(report)
+        .attach_printable(format!(
+            "Remote peer {remote_peer} rejected by the handshake policy"
+        ))
+        .change_context(HandshakeMessageExchangeError)
+}
+
+/// Makes and sends the messages of the enabled `features`, if the `negotiated_version` supports them.
+/// Returns the announced features.
+async fn send_features(
+    connection: &mut Connection,
+    network: Network,
+    features: HandshakeFeatures,
+    negotiated_version: u32,
+    remote_peer: &PeerAddr,
+) -> Result<HandshakeFeatures, HandshakeMessageExchangeError> {
+    if negotiated_version < constants::FEATURE_NEGOTIATION_VERSION {
+        return Ok(HandshakeFeatures::default());
+    }
+
+    let messages = [
+        (features.wtxid_relay, NetworkMessage::WtxidRelay),
+        (features.addr_v2, NetworkMessage::SendAddrV2),
+    ];
+    for (_, message) in messages.into_iter().filter(|(enabled, _)| *enabled) {
+        let command = message.cmd();
+        let message_bytes = network_messages::make_message_serialised(network, message);
+        connection
+            .write_message(&message_bytes)
+            .await
+            .attach_printable_lazy(|| {
+                format!("Failed to send {command} message to the remote peer")
+            })
+            .change_context(HandshakeMessageExchangeError)?;
+        info!("Sent {command} message to {remote_peer}");
+    }
+    Ok(features)
+}
+
+/// Makes and sends the local VerAck message
+async fn send_verack(
+    connection: &mut Connection,
+    network: Network,
+    remote_peer: &PeerAddr,
+) -> Result<(), HandshakeMessageExchangeError> {
+    let message_verack_bytes = network_messages::make_verack_message_serialised(network);
+    connection
+        .write_message(&message_verack_bytes)
+        .await
+        .attach_printable("Failed to send VerAck message to the remote peer")
+        .change_context(HandshakeMessageExchangeError)?;
+    info!("Sent VerAck message to {remote_peer}");
+    Ok(())
+}
+
+/// Waits for the VerAck message from the remote peer.
+/// The `wtxidrelay` and `sendaddrv2` messages may arrive before it in any order,
+/// the features they announce are returned.
+async fn recv_verack(
+    connection: &mut Connection,
+    network: Network,
+    remote_peer: &PeerAddr,
+) -> Result<HandshakeFeatures, HandshakeMessageExchangeError> {
+    let mut peer_features = HandshakeFeatures::default();
+    loop {
+        let message_verack_remote = connection
+            .read_message(network.magic())
+            .await
+            .attach_printable("Failed to receive and decode VerAck message from the remote peer")
+            .change_context(HandshakeMessageExchangeError)?;
+
+        let message_verack_remote = match message_verack_remote.payload {
+            NetworkMessage::Verack => message_verack_remote.payload,
+            NetworkMessage::WtxidRelay => {
+                info!("Recv wtxidrelay message from {remote_peer}");
+                peer_features.wtxid_relay = true;
+                continue;
+            }
+            NetworkMessage::SendAddrV2 => {
+                info!("Recv sendaddrv2 message from {remote_peer}");
+                peer_features.addr_v2 = true;
+                continue;
+            }
+            _ => {
+                error!(
+                    "Received unexpected message, but expected VerAck message: {:?}",
+                    message_verack_remote.payload
+                );
+                return Err(Report::new(HandshakeMessageVerAckError)
+                    .attach(HandshakeErrorKind::UnexpectedMessage)
+                    .attach_printable(format!(
+                        "Received unexpected message, but expected VerAck message: {:?}",
+                        message_verack_remote.payload
+                    )))
+                .change_context(HandshakeMessageExchangeError);
+            }
+        };
+
+        info!("Recv VerAck message from {remote_peer}: {message_verack_remote:?}");
+        return Ok(peer_features);
     }
-    Ok(true)
 }
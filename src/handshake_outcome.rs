@@ -0,0 +1,151 @@
+use bitcoin::network::{constants::ServiceFlags, message_network::VersionMessage};
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use crate::{handshake_manager::HandshakeErrorKind, PeerAddr};
+
+/// Fields of the `version` message received from the remote peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerVersion {
+    /// Protocol version advertised by the remote peer
+    pub version: u32,
+    /// Services advertised by the remote peer
+    pub services: ServiceFlags,
+    /// User agent of the remote peer, e.g. `/Satoshi:24.0.1/`
+    pub user_agent: String,
+    /// Height of the best block known to the remote peer
+    pub start_height: i32,
+    /// Whether the remote peer wants to receive transaction announcements
+    pub relay: bool,
+    /// Random nonce used by the remote peer to detect connections to itself
+    pub nonce: u64,
+    /// Unix timestamp of the remote peer at the moment of sending the message
+    pub timestamp: i64,
+    /// Address the remote peer announced for itself (`addr_from`), if it is an IP address.
+    /// Most nodes send `0.0.0.0:0` instead of their real address.
+    pub sender: Option<SocketAddr>,
+}
+
+impl From<&VersionMessage> for PeerVersion {
+    fn from(message: &VersionMessage) -> Self {
+        Self {
+            version: message.version,
+            services: message.services,
+            user_agent: message.user_agent.clone(),
+            start_height: message.start_height,
+            relay: message.relay,
+            nonce: message.nonce,
+            timestamp: message.timestamp,
+            sender: message.sender.socket_addr().ok(),
+        }
+    }
+}
+
+/// Optional features announced between `version` and `verack`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeFeatures {
+    /// Transactions are announced by wtxid, signalled by `wtxidrelay` (BIP339)
+    pub wtxid_relay: bool,
+    /// Addresses are relayed with `addrv2`, signalled by `sendaddrv2` (BIP155)
+    pub addr_v2: bool,
+}
+
+impl HandshakeFeatures {
+    /// Returns the features announced by both sides
+    pub fn intersection(&self, other: &HandshakeFeatures) -> HandshakeFeatures {
+        HandshakeFeatures {
+            wtxid_relay: self.wtxid_relay && other.wtxid_relay,
+            addr_v2: self.addr_v2 && other.addr_v2,
+        }
+    }
+}
+
+/// Measured durations of the handshake phases
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeTimings {
+    /// Time spent to establish the TCP connection
+    pub connect: Duration,
+    /// Time between sending the local `version` and receiving the remote one
+    pub version: Duration,
+    /// Time between sending the local `verack` and receiving the remote one
+    pub verack: Duration,
+    /// Time of the whole handshake, including the connection
+    pub total: Duration,
+}
+
+/// Side that initiated the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// The local peer connected to the remote peer
+    Outbound,
+    /// The remote peer connected to the local peer
+    Inbound,
+}
+
+/// Transport of the messages exchanged with the remote peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportVersion {
+    /// Plaintext messages with the network magic and a checksum
+    #[default]
+    V1,
+    /// Messages encrypted and authenticated as in BIP324
+    V2,
+}
+
+impl TransportVersion {
+    /// Returns the name of the transport, `v1` or `v2`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransportVersion::V1 => "v1",
+            TransportVersion::V2 => "v2",
+        }
+    }
+}
+
+impl fmt::Display for TransportVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Everything learned about the remote peer during a successful handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeOutcome {
+    /// Local half of the TCP connection, the one to the proxy for a proxied connection
+    pub local_addr: SocketAddr,
+    /// Remote peer: the remote half of the TCP connection, or the target of a proxied connection
+    pub remote_addr: PeerAddr,
+    /// Side that initiated the connection
+    pub direction: ConnectionDirection,
+    /// Transport of the messages, v2 if both peers support BIP324
+    pub transport: TransportVersion,
+    /// Protocol version sent to the remote peer
+    pub local_version: u32,
+    /// `version` message received from the remote peer
+    pub peer: PeerVersion,
+    /// Protocol version both peers use after the handshake - the minimum of the 2 versions
+    pub negotiated_version: u32,
+    /// Features announced by the local peer
+    pub local_features: HandshakeFeatures,
+    /// Features announced by the remote peer
+    pub peer_features: HandshakeFeatures,
+    /// Features announced by both peers, i.e. in use after the handshake
+    pub negotiated_features: HandshakeFeatures,
+    /// Durations of the handshake phases
+    pub timings: HandshakeTimings,
+}
+
+/// Status of a handshake recorded by `HandshakeManager`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeStatus {
+    /// The handshake completed successfully
+    Completed(Box<HandshakeOutcome>),
+    /// The handshake failed with the given kind of failure
+    Failed(HandshakeErrorKind),
+}
+
+impl HandshakeStatus {
+    /// Returns `true` if the handshake completed successfully
+    pub fn is_completed(&self) -> bool {
+        matches!(self, HandshakeStatus::Completed(_))
+    }
+}
@@ -0,0 +1,96 @@
+use bitcoin::network::constants::ServiceFlags;
+use error_stack::{Report, Result};
+use std::{collections::HashSet, error::Error, fmt, time::Duration};
+
+use crate::{constants, handshake_manager::HandshakeErrorKind, handshake_outcome::PeerVersion};
+
+/// Handshake Policy Error - the remote `version` message is not acceptable
+#[derive(Debug)]
+pub struct HandshakePolicyError;
+
+impl fmt::Display for HandshakePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handshake policy error: remote version message rejected")
+    }
+}
+
+impl Error for HandshakePolicyError {}
+
+/// Acceptance policy applied to the remote `version` message before the local `verack` is sent.
+///
+/// Every rejection is reported with its own `HandshakeErrorKind`: `VersionTooOld`,
+/// `MissingServices`, `ClockSkew` or `SelfConnection`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakePolicy {
+    /// The oldest protocol version the remote peer may advertise
+    pub min_protocol_version: u32,
+    /// Service bits the remote peer must advertise, e.g. `NETWORK | WITNESS`
+    pub required_services: ServiceFlags,
+    /// Maximum difference between the remote timestamp and the local clock, unlimited if `None`
+    pub max_clock_skew: Option<Duration>,
+    /// Whether to reject a remote `version` that carries the nonce of a local `version`
+    pub reject_self_connection: bool,
+    /// Whether to shut the connection down gracefully after a rejection instead of just dropping it
+    pub disconnect_gracefully: bool,
+}
+
+impl Default for HandshakePolicy {
+    fn default() -> Self {
+        Self {
+            min_protocol_version: constants::MIN_PEER_PROTOCOL_VERSION,
+            required_services: ServiceFlags::NONE,
+            max_clock_skew: None,
+            reject_self_connection: true,
+            disconnect_gracefully: false,
+        }
+    }
+}
+
+impl HandshakePolicy {
+    /// Checks the `peer` version against the policy.
+    /// `local_nonces` are the nonces of the local `version` messages sent on the outbound connections
+    /// whose handshake is not complete yet: a remote peer that carries one of them is the local node itself.
+    pub fn check(
+        &self,
+        peer: &PeerVersion,
+        local_nonces: &HashSet<u64>,
+    ) -> Result<(), HandshakePolicyError> {
+        if peer.version < self.min_protocol_version {
+            return Err(Report::new(HandshakePolicyError)
+                .attach(HandshakeErrorKind::VersionTooOld)
+                .attach_printable(format!(
+                    "Remote protocol version {} is older than the minimum accepted version {}",
+                    peer.version, self.min_protocol_version
+                )));
+        }
+
+        if !peer.services.has(self.required_services) {
+            return Err(Report::new(HandshakePolicyError)
+                .attach(HandshakeErrorKind::MissingServices)
+                .attach_printable(format!(
+                    "Remote services {} do not include the required services {}",
+                    peer.services, self.required_services
+                )));
+        }
+
+        if let Some(max_clock_skew) = self.max_clock_skew {
+            let skew = chrono::Utc::now().timestamp().abs_diff(peer.timestamp);
+            if skew > max_clock_skew.as_secs() {
+                return Err(Report::new(HandshakePolicyError)
+                    .attach(HandshakeErrorKind::ClockSkew)
+                    .attach_printable(format!(
+                        "Remote clock differs from the local one by {skew}s, the maximum is {}s",
+                        max_clock_skew.as_secs()
+                    )));
+            }
+        }
+
+        if self.reject_self_connection && local_nonces.contains(&peer.nonce) {
+            return Err(Report::new(HandshakePolicyError)
+                .attach(HandshakeErrorKind::SelfConnection)
+                .attach_printable(format!("Received own nonce {}", peer.nonce)));
+        }
+
+        Ok(())
+    }
+}
@@ -1,13 +1,58 @@
+mod address_book;
 mod config;
 mod constants;
+mod crawler;
+mod dns;
 mod dns_seed_mananger;
+mod ellswift;
+mod fschacha20;
 mod handshake_manager;
+mod handshake_outcome;
+mod handshake_policy;
+mod mock_dns;
+mod mock_peer;
+mod mock_proxy;
 mod network_messages;
+mod output;
+mod peer_addr;
+mod peer_session;
+mod proxy;
+mod seed_check;
+mod seeder;
+mod target;
+mod transport;
+mod v2_transport;
 
 // For the external usage
+pub use address_book::{
+    AddressBook, AddressBookEntry, AddressBookError, AdvertisedVersion, PeerSource,
+};
 pub use config::run;
-pub use config::Config;
-
-// For the internal usage
-use dns_seed_mananger::DnsSeedManager;
-use handshake_manager::HandshakeManager;
+pub use config::{
+    BookCommand, Command, Config, DnsArgs, HandshakeArgs, HandshakeTargets, ProxyArgs,
+    SeedSelection, SeedsCommand,
+};
+pub use crawler::{CrawlLimits, CrawledPeer, Crawler, NetworkMap};
+pub use dns_seed_mananger::{DnsLookupError, DnsSeedManager, DnsSeedSettings, SeedLookup};
+pub use handshake_manager::{
+    HandshakeError, HandshakeErrorKind, HandshakeManager, HandshakeTimeouts,
+};
+pub use handshake_outcome::{
+    ConnectionDirection, HandshakeFeatures, HandshakeOutcome, HandshakeStatus, HandshakeTimings,
+    PeerVersion, TransportVersion,
+};
+pub use handshake_policy::{HandshakePolicy, HandshakePolicyError};
+pub use mock_dns::{MockDnsServer, MockDnsServerError};
+pub use mock_peer::{MockBehaviour, MockPeer, MockPeerError};
+pub use mock_proxy::{MockSocks5Proxy, MockSocks5ProxyError, ProxyRequest};
+pub use network_messages::{VersionParams, VersionParamsError};
+pub use output::{
+    AddressBookRecord, AddressRecord, CrawlRecord, HandshakeRecord, OutputError, OutputFormat,
+    OutputRecord, SeedHealthRecord, SeedRecord,
+};
+pub use peer_addr::{PeerAddr, PeerAddrError};
+pub use peer_session::{PeerSession, PeerSessionError};
+pub use proxy::{ProxyCredentials, ProxyError, ProxySettings, ProxyTarget};
+pub use seed_check::{SeedChecker, SeedHealth};
+pub use seeder::{DnsSeeder, SeederError, SeederSettings};
+pub use target::{Target, TargetError, TargetHost};
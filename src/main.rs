@@ -1,3 +1,4 @@
+use clap::Parser;
 use log::error;
 use p2p_node_handshake::Config;
 
@@ -6,10 +7,8 @@ async fn main() {
     let env = env_logger::Env::default().filter_or("log-level-info", "info");
     env_logger::init_from_env(env);
 
-    let config = Config::build(std::env::args()).unwrap_or_else(|err| {
-        error!("Problem parsing arguments: {err:?}");
-        std::process::exit(1);
-    });
+    // Prints the help, the version or the invalid argument and exits when needed
+    let config = Config::parse();
 
     if let Err(e) = p2p_node_handshake::run(&config).await {
         error!("Application error:\n{e:?}");
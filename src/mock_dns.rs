@@ -0,0 +1,191 @@
+use error_stack::{IntoReport, Result, ResultExt};
+use log::info;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::dns;
+
+/// Mock DNS Server Error
+#[derive(Debug)]
+pub struct MockDnsServerError;
+
+impl fmt::Display for MockDnsServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mock DNS server error: failed to start mock DNS server")
+    }
+}
+
+impl Error for MockDnsServerError {}
+
+/// Records served by a `MockDnsServer`
+#[derive(Debug, Default)]
+struct MockZone {
+    records: HashMap<String, Vec<IpAddr>>,
+    silent: HashSet<String>,
+    truncated: HashSet<String>,
+    queries: Vec<String>,
+}
+
+/// A local nameserver for offline tests.
+///
+/// Listens on a random localhost port, both UDP and TCP, and answers A and AAAA queries with the addresses
+/// set by `MockDnsServer::set_records`. Unknown names are answered with NXDOMAIN, and names set
+/// with `MockDnsServer::set_silent` are never answered. Names set with `MockDnsServer::set_truncated` are answered
+/// with a truncated response without answers over UDP, and with their addresses over TCP only. The server stops when dropped.
+pub struct MockDnsServer {
+    addr: SocketAddr,
+    zone: Arc<Mutex<MockZone>>,
+    handles: [JoinHandle<()>; 2],
+}
+
+impl MockDnsServer {
+    /// Starts a mock DNS server on a random localhost port, both UDP and TCP
+    pub async fn start() -> Result<Self, MockDnsServerError> {
+        let (socket, listener) = bind_udp_and_tcp().await?;
+        let addr = socket
+            .local_addr()
+            .into_report()
+            .attach_printable("Failed to return mock DNS server address")
+            .change_context(MockDnsServerError)?;
+        let zone = Arc::new(Mutex::new(MockZone::default()));
+
+        let udp_zone = Arc::clone(&zone);
+        let udp = tokio::spawn(async move {
+            let mut buffer = [0u8; dns::MAX_UDP_MESSAGE];
+            while let Ok((length, remote)) = socket.recv_from(&mut buffer).await {
+                let Some(response) = answer(&udp_zone, &buffer[..length], true) else {
+                    continue;
+                };
+                info!("Mock DNS server {addr} answers UDP query of {remote}");
+                let _ = socket.send_to(&response, remote).await;
+            }
+        });
+
+        let tcp_zone = Arc::clone(&zone);
+        let tcp = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            while let Ok((mut stream, remote)) = listener.accept().await {
+                let zone = Arc::clone(&tcp_zone);
+                connections.spawn(async move {
+                    while let Ok(query) = dns::read_tcp_message(&mut stream).await {
+                        let Some(response) = answer(&zone, &query, false) else {
+                            continue;
+                        };
+                        info!("Mock DNS server {addr} answers TCP query of {remote}");
+                        if dns::write_tcp_message(&mut stream, &response)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            zone,
+            handles: [udp, tcp],
+        })
+    }
+
+    /// Returns the address the mock DNS server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sets the addresses of the `name`, both A and AAAA records
+    pub fn set_records(&self, name: &str, addresses: Vec<IpAddr>) {
+        if let Ok(mut zone) = self.zone.lock() {
+            zone.records.insert(dns::normalise_name(name), addresses);
+        }
+    }
+
+    /// Makes the server ignore the queries for the `name`, so that lookups time out
+    pub fn set_silent(&self, name: &str) {
+        if let Ok(mut zone) = self.zone.lock() {
+            zone.silent.insert(dns::normalise_name(name));
+        }
+    }
+
+    /// Makes the server answer the queries for the `name` over UDP with a truncated response,
+    /// so that the addresses are only returned over TCP
+    pub fn set_truncated(&self, name: &str) {
+        if let Ok(mut zone) = self.zone.lock() {
+            zone.truncated.insert(dns::normalise_name(name));
+        }
+    }
+
+    /// Returns the names of all queries received so far, normalised to lower case without the trailing dot
+    pub fn queries(&self) -> Vec<String> {
+        self.zone
+            .lock()
+            .map(|zone| zone.queries.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Binds the UDP socket and the TCP listener on the same random localhost port
+async fn bind_udp_and_tcp() -> Result<(UdpSocket, TcpListener), MockDnsServerError> {
+    let mut attempts = 0;
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .into_report()
+            .attach_printable("Failed to bind mock DNS server listener")
+            .change_context(MockDnsServerError)?;
+        let addr = listener
+            .local_addr()
+            .into_report()
+            .attach_printable("Failed to return mock DNS server address")
+            .change_context(MockDnsServerError)?;
+        // The UDP port of the same number may be taken by another socket
+        match UdpSocket::bind(addr).await.into_report() {
+            Ok(socket) => return Ok((socket, listener)),
+            Err(report) if attempts >= 10 => {
+                return Err(report
+                    .attach_printable("Failed to bind mock DNS server socket")
+                    .change_context(MockDnsServerError))
+            }
+            Err(_) => attempts += 1,
+        }
+    }
+}
+
+/// Returns the response to the DNS `query` received over UDP if `udp`, over TCP otherwise.
+/// Returns `None` if the query is invalid or its name is silent.
+fn answer(zone: &Mutex<MockZone>, query: &[u8], udp: bool) -> Option<Vec<u8>> {
+    let question = dns::decode_query(query)?;
+    let mut zone = zone.lock().ok()?;
+    zone.queries.push(question.name.clone());
+    if zone.silent.contains(&question.name) {
+        return None;
+    }
+    if udp && zone.truncated.contains(&question.name) {
+        let mut response = dns::encode_response(&question, dns::RCODE_NO_ERROR, &[], 60);
+        dns::set_truncated(&mut response);
+        return Some(response);
+    }
+    Some(match zone.records.get(&question.name) {
+        Some(addresses) => dns::encode_response(&question, dns::RCODE_NO_ERROR, addresses, 60),
+        None => dns::encode_response(&question, dns::RCODE_NAME_ERROR, &[], 60),
+    })
+}
+
+impl Drop for MockDnsServer {
+    fn drop(&mut self) {
+        for handle in &self.handles {
+            handle.abort();
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use bitcoin::{
+    network::{
+        address::AddrV2Message,
+        constants::ServiceFlags,
+        message::{NetworkMessage, RawNetworkMessage},
+        message_network::VersionMessage,
+        Address,
+    },
+    Network,
+};
+use error_stack::{IntoReport, Result, ResultExt};
+use log::{info, warn};
+use std::{
+    error::Error,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::transport::{Connection, TransportError};
+use crate::PeerAddr;
+use crate::TransportVersion;
+
+/// Mock Peer Error
+#[derive(Debug)]
+pub struct MockPeerError;
+
+impl fmt::Display for MockPeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mock peer error: failed to start mock peer")
+    }
+}
+
+impl Error for MockPeerError {}
+
+/// Scripted behaviour of a `MockPeer` when a node connects to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockBehaviour {
+    /// Completes the version handshake as a regular Bitcoin node
+    Handshake,
+    /// Replies to the `version` message with `verack` instead of `version`
+    WrongFirstMessage,
+    /// Replies with `version` but never sends `verack`
+    MissingVerack,
+    /// Replies with messages that carry the magic of another network, the v2 transport carries no magic
+    WrongMagic,
+    /// Replies with a `version` message that has a corrupted checksum, the v2 transport carries no checksum
+    BadChecksum,
+    /// Completes the version handshake, but waits the given duration before each reply
+    SlowResponse(Duration),
+    /// Sends a part of the `version` message and closes the connection
+    DisconnectMidStream,
+    /// Replies with the `version` message received from the node, as if the node connected to itself
+    EchoVersion,
+    /// Completes the version handshake announcing `sendaddrv2` and `wtxidrelay` before `verack`
+    NegotiateFeatures,
+    /// Completes the version handshake, then sends `ping` with `MockPeer::NONCE` followed by `sendheaders`
+    PingAfterHandshake,
+    /// Completes the version handshake, then sends a packet with the short message ID `MockPeer::UNKNOWN_SHORT_ID`
+    /// followed by `sendheaders`. The unknown packet is only sent over the v2 transport.
+    UnknownMessageAfterHandshake,
+    /// Completes the version handshake with a timestamp that is ahead of the local clock by the given duration
+    SkewedClock(Duration),
+    /// Completes the version handshake, but never answers `ping`
+    IgnorePings,
+}
+
+/// A local Bitcoin peer for offline tests.
+///
+/// Listens on a random localhost port and serves every inbound connection with the scripted
+/// `MockBehaviour`. All messages received from the connected nodes are recorded,
+/// every `ping` received after the handshake is answered with `pong` (unless the behaviour ignores pings), and every `getaddr`
+/// with the addresses set by `MockPeer::set_gossip`, as `addrv2` if the node sent `sendaddrv2`.
+/// Tor and I2P addresses are gossiped only in `addrv2`.
+/// Both the v1 and the BIP324 v2 transports are accepted, unless `MockPeer::set_v2_transport` disables v2.
+/// The peer stops and closes all its connections when dropped.
+pub struct MockPeer {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<NetworkMessage>>>,
+    gossip: Arc<Mutex<Vec<PeerAddr>>>,
+    v2_transport: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl MockPeer {
+    /// Protocol version advertised by the mock peer
+    pub const PROTOCOL_VERSION: u32 = 70016;
+    /// User agent advertised by the mock peer
+    pub const USER_AGENT: &'static str = "/mock-peer:0.1.0/";
+    /// Start height advertised by the mock peer
+    pub const START_HEIGHT: i32 = 777_000;
+    /// Nonce advertised by the mock peer
+    pub const NONCE: u64 = 0x6d6f_636b_7065_6572;
+    /// Short message ID of the v2 transport that is not assigned to any message
+    pub const UNKNOWN_SHORT_ID: u8 = 0xff;
+
+    /// Starts a mock peer for the given `network` on a random localhost port
+    pub async fn start(network: Network, behaviour: MockBehaviour) -> Result<Self, MockPeerError> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .into_report()
+            .attach_printable("Failed to bind mock peer listener")
+            .change_context(MockPeerError)?;
+        let addr = listener
+            .local_addr()
+            .into_report()
+            .attach_printable("Failed to return mock peer listener address")
+            .change_context(MockPeerError)?;
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let gossip = Arc::new(Mutex::new(Vec::new()));
+        let v2_transport = Arc::new(AtomicBool::new(true));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+
+        let connection_received = Arc::clone(&received);
+        let connection_gossip = Arc::clone(&gossip);
+        let connection_v2_transport = Arc::clone(&v2_transport);
+        let connection_handles = Arc::clone(&connections);
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, remote)) = listener.accept().await {
+                info!(
+                    "Mock peer {addr} accepted connection from {remote}, behaviour: {behaviour:?}"
+                );
+                let received = Arc::clone(&connection_received);
+                let gossip = Arc::clone(&connection_gossip);
+                let v2_transport = connection_v2_transport.load(Ordering::Relaxed);
+                let connection = tokio::spawn(async move {
+                    if let Err(e) =
+                        serve(stream, network, behaviour, v2_transport, received, gossip).await
+                    {
+                        warn!("Mock peer {addr} connection with {remote} ended: {e:?}");
+                    }
+                });
+                if let Ok(mut handles) = connection_handles.lock() {
+                    handles.push(connection);
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            received,
+            gossip,
+            v2_transport,
+            handle,
+            connections,
+        })
+    }
+
+    /// Returns the address the mock peer listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sets the addresses sent in response to `getaddr`
+    pub fn set_gossip<A: Into<PeerAddr>>(&self, addresses: Vec<A>) {
+        if let Ok(mut gossip) = self.gossip.lock() {
+            *gossip = addresses.into_iter().map(Into::into).collect();
+        }
+    }
+
+    /// Sets whether the v2 transport is accepted on the next connections.
+    /// A mock peer without v2 closes the connections that do not start with a v1 `version` message.
+    pub fn set_v2_transport(&self, v2_transport: bool) {
+        self.v2_transport.store(v2_transport, Ordering::Relaxed);
+    }
+
+    /// Returns all messages received by the mock peer so far
+    pub fn received_messages(&self) -> Vec<NetworkMessage> {
+        self.received.lock().map(|r| r.clone()).unwrap_or_default()
+    }
+}
+
+impl Drop for MockPeer {
+    fn drop(&mut self) {
+        self.handle.abort();
+        if let Ok(handles) = self.connections.lock() {
+            handles.iter().for_each(JoinHandle::abort);
+        }
+    }
+}
+
+/// Serves a single connection according to the `behaviour`
+async fn serve(
+    stream: TcpStream,
+    network: Network,
+    behaviour: MockBehaviour,
+    v2_transport: bool,
+    received: Arc<Mutex<Vec<NetworkMessage>>>,
+    gossip: Arc<Mutex<Vec<PeerAddr>>>,
+) -> Result<(), TransportError> {
+    let local_peer = stream
+        .local_addr()
+        .into_report()
+        .change_context(TransportError)?;
+    let remote_peer = stream
+        .peer_addr()
+        .into_report()
+        .change_context(TransportError)?;
+    let magic = network.magic();
+
+    // Every behaviour waits for the node to speak first
+    let mut stream = Connection::accept(stream, network, v2_transport).await?;
+    let first_message = stream.read_message(magic).await?.payload;
+    record(&received, first_message.clone());
+
+    let version = mock_version_message(local_peer, remote_peer);
+    match behaviour {
+        MockBehaviour::Handshake | MockBehaviour::IgnorePings => {
+            send(&mut stream, magic, version).await?;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+        }
+        MockBehaviour::WrongFirstMessage => {
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+        }
+        MockBehaviour::MissingVerack => {
+            send(&mut stream, magic, version).await?;
+        }
+        MockBehaviour::WrongMagic => {
+            let wrong_magic = match network {
+                Network::Bitcoin => Network::Testnet.magic(),
+                _ => Network::Bitcoin.magic(),
+            };
+            send(&mut stream, wrong_magic, version).await?;
+            send(&mut stream, wrong_magic, NetworkMessage::Verack).await?;
+        }
+        MockBehaviour::BadChecksum => {
+            let mut bytes = bitcoin::consensus::encode::serialize(&RawNetworkMessage {
+                magic,
+                payload: version,
+            });
+            // The checksum follows magic (4), command (12) and payload length (4)
+            bytes[20] ^= 0xff;
+            stream.write_message(&bytes).await?;
+        }
+        MockBehaviour::SlowResponse(delay) => {
+            tokio::time::sleep(delay).await;
+            send(&mut stream, magic, version).await?;
+            tokio::time::sleep(delay).await;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+        }
+        MockBehaviour::DisconnectMidStream => {
+            let bytes = bitcoin::consensus::encode::serialize(&RawNetworkMessage {
+                magic,
+                payload: version,
+            });
+            stream.write_message(&bytes[..bytes.len() / 2]).await?;
+            return stream
+                .shutdown()
+                .await
+                .into_report()
+                .change_context(TransportError);
+        }
+        MockBehaviour::EchoVersion => {
+            send(&mut stream, magic, first_message).await?;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+        }
+        MockBehaviour::NegotiateFeatures => {
+            send(&mut stream, magic, version).await?;
+            send(&mut stream, magic, NetworkMessage::SendAddrV2).await?;
+            send(&mut stream, magic, NetworkMessage::WtxidRelay).await?;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+        }
+        MockBehaviour::PingAfterHandshake => {
+            send(&mut stream, magic, version).await?;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+            send(&mut stream, magic, NetworkMessage::Ping(MockPeer::NONCE)).await?;
+            send(&mut stream, magic, NetworkMessage::SendHeaders).await?;
+        }
+        MockBehaviour::UnknownMessageAfterHandshake => {
+            send(&mut stream, magic, version).await?;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+            if stream.transport() == TransportVersion::V2 {
+                stream
+                    .write_contents(&[MockPeer::UNKNOWN_SHORT_ID, 1, 2, 3])
+                    .await?;
+            }
+            send(&mut stream, magic, NetworkMessage::SendHeaders).await?;
+        }
+        MockBehaviour::SkewedClock(skew) => {
+            let mut version = version;
+            if let NetworkMessage::Version(message) = &mut version {
+                message.timestamp += skew.as_secs() as i64;
+            }
+            send(&mut stream, magic, version).await?;
+            send(&mut stream, magic, NetworkMessage::Verack).await?;
+        }
+    }
+
+    // Keep the connection open and record everything until the node disconnects
+    let mut addr_v2 = false;
+    loop {
+        let message = stream.read_message(magic).await?.payload;
+        match message {
+            NetworkMessage::Ping(nonce) if behaviour != MockBehaviour::IgnorePings => {
+                send(&mut stream, magic, NetworkMessage::Pong(nonce)).await?;
+            }
+            NetworkMessage::SendAddrV2 => addr_v2 = true,
+            NetworkMessage::GetAddr => {
+                let addresses = gossip.lock().map(|g| g.clone()).unwrap_or_default();
+                send(&mut stream, magic, mock_addr_message(&addresses, addr_v2)).await?;
+            }
+            _ => {}
+        }
+        record(&received, message);
+    }
+}
+
+/// Serialises and sends a `payload` with the given `magic`
+async fn send(
+    stream: &mut Connection,
+    magic: u32,
+    payload: NetworkMessage,
+) -> Result<(), TransportError> {
+    let bytes = bitcoin::consensus::encode::serialize(&RawNetworkMessage { magic, payload });
+    stream.write_message(&bytes).await
+}
+
+/// Records a received message
+fn record(received: &Mutex<Vec<NetworkMessage>>, message: NetworkMessage) {
+    if let Ok(mut received) = received.lock() {
+        received.push(message);
+    }
+}
+
+/// Builds the `version` message advertised by the mock peer
+fn mock_version_message(local_peer: SocketAddr, remote_peer: SocketAddr) -> NetworkMessage {
+    let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+    let mut message = VersionMessage::new(
+        services,
+        chrono::Utc::now().timestamp(),
+        Address::new(&remote_peer, ServiceFlags::NONE),
+        Address::new(&local_peer, services),
+        MockPeer::NONCE,
+        MockPeer::USER_AGENT.to_owned(),
+        MockPeer::START_HEIGHT,
+    );
+    message.version = MockPeer::PROTOCOL_VERSION;
+    message.relay = true;
+    NetworkMessage::Version(message)
+}
+
+/// Builds the `addr` or `addrv2` message with the gossiped `addresses`
+fn mock_addr_message(addresses: &[PeerAddr], addr_v2: bool) -> NetworkMessage {
+    let time = chrono::Utc::now().timestamp() as u32;
+    let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+    if !addr_v2 {
+        let addresses = addresses
+            .iter()
+            .filter_map(PeerAddr::socket_addr)
+            .map(|addr| (time, Address::new(&addr, services)))
+            .collect();
+        return NetworkMessage::Addr(addresses);
+    }
+
+    let addresses = addresses
+        .iter()
+        .filter_map(|addr| {
+            Some(AddrV2Message {
+                time,
+                services,
+                addr: addr.to_addr_v2()?,
+                port: addr.port(),
+            })
+        })
+        .collect();
+    NetworkMessage::AddrV2(addresses)
+}
@@ -0,0 +1,300 @@
+use error_stack::{IntoReport, Result, ResultExt};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::proxy::{
+    self, ProxyCredentials, ProxyTarget, ATYP_DOMAIN, ATYP_IPV4, ATYP_IPV6, CMD_CONNECT,
+    CMD_RESOLVE, REPLY_COMMAND_NOT_SUPPORTED, REPLY_CONNECTION_REFUSED, REPLY_GENERAL_FAILURE,
+    REPLY_HOST_UNREACHABLE, REPLY_SUCCEEDED,
+};
+
+/// Mock SOCKS5 Proxy Error
+#[derive(Debug)]
+pub struct MockSocks5ProxyError;
+
+impl fmt::Display for MockSocks5ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Mock SOCKS5 proxy error: failed to start mock SOCKS5 proxy"
+        )
+    }
+}
+
+impl Error for MockSocks5ProxyError {}
+
+/// A request received by a `MockSocks5Proxy`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRequest {
+    /// Requested destination, the port of a `RESOLVE` request is 0
+    pub target: ProxyTarget,
+    /// `true` for the `RESOLVE` command of Tor, `false` for `CONNECT`
+    pub resolve: bool,
+    /// Credentials sent by the client, `None` if it did not authenticate
+    pub credentials: Option<ProxyCredentials>,
+}
+
+/// State shared by the connections of a `MockSocks5Proxy`
+#[derive(Debug, Default)]
+struct MockProxyState {
+    required: Option<ProxyCredentials>,
+    hosts: HashMap<String, SocketAddr>,
+    reject_resolve: bool,
+    requests: Vec<ProxyRequest>,
+}
+
+/// A local SOCKS5 proxy for offline tests.
+///
+/// Listens on a random localhost port, relays the `CONNECT` requests to the requested addresses
+/// and answers the `RESOLVE` requests of Tor, unless `MockSocks5Proxy::set_resolve_supported` disables them. Host names are known to the proxy only if set with
+/// `MockSocks5Proxy::set_host`. The username and password method is chosen whenever the client offers it,
+/// so that the credentials of every request are recorded. The proxy stops and closes all its connections when dropped.
+pub struct MockSocks5Proxy {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockProxyState>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockSocks5Proxy {
+    /// Starts a mock SOCKS5 proxy on a random localhost port.
+    /// The clients must authenticate with the `credentials` if given, any or none are accepted otherwise.
+    pub async fn start(
+        credentials: Option<ProxyCredentials>,
+    ) -> Result<Self, MockSocks5ProxyError> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .into_report()
+            .attach_printable("Failed to bind mock SOCKS5 proxy listener")
+            .change_context(MockSocks5ProxyError)?;
+        let addr = listener
+            .local_addr()
+            .into_report()
+            .attach_printable("Failed to return mock SOCKS5 proxy listener address")
+            .change_context(MockSocks5ProxyError)?;
+        let state = Arc::new(Mutex::new(MockProxyState {
+            required: credentials,
+            ..MockProxyState::default()
+        }));
+
+        let server_state = Arc::clone(&state);
+        let handle = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            while let Ok((stream, remote)) = listener.accept().await {
+                let state = Arc::clone(&server_state);
+                connections.spawn(async move {
+                    if let Err(e) = serve(stream, &state).await {
+                        warn!("Mock SOCKS5 proxy {addr} connection with {remote} ended: {e}");
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            state,
+            handle,
+        })
+    }
+
+    /// Returns the address the mock proxy listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Makes the host `name` known to the proxy: connections to it go to the `addr` whatever the requested port,
+    /// and it resolves to the IP address of the `addr`
+    pub fn set_host(&self, name: &str, addr: SocketAddr) {
+        if let Ok(mut state) = self.state.lock() {
+            state.hosts.insert(name.to_ascii_lowercase(), addr);
+        }
+    }
+
+    /// Makes the proxy answer the `RESOLVE` requests with "command not supported", as proxies other than Tor do
+    pub fn set_resolve_supported(&self, supported: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            state.reject_resolve = !supported;
+        }
+    }
+
+    /// Returns all requests received so far
+    pub fn requests(&self) -> Vec<ProxyRequest> {
+        self.state
+            .lock()
+            .map(|state| state.requests.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for MockSocks5Proxy {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Serves a single client connection: negotiates the method, authenticates and runs the request
+async fn serve(mut stream: TcpStream, state: &Mutex<MockProxyState>) -> io::Result<()> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let required = state
+        .lock()
+        .map_err(|_| io::ErrorKind::Other)?
+        .required
+        .clone();
+    let credentials = if methods.contains(&0x02) {
+        stream.write_all(&[0x05, 0x02]).await?;
+        let credentials = read_credentials(&mut stream).await?;
+        let accepted = required
+            .as_ref()
+            .is_none_or(|required| *required == credentials);
+        stream
+            .write_all(&[0x01, if accepted { 0x00 } else { 0x01 }])
+            .await?;
+        if !accepted {
+            return Ok(());
+        }
+        Some(credentials)
+    } else if methods.contains(&0x00) && required.is_none() {
+        stream.write_all(&[0x05, 0x00]).await?;
+        None
+    } else {
+        return stream.write_all(&[0x05, 0xff]).await;
+    };
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let target = read_target(&mut stream, request[3]).await?;
+    let command = request[1];
+    let (resolved, reject_resolve) = {
+        let mut state = state.lock().map_err(|_| io::ErrorKind::Other)?;
+        state.requests.push(ProxyRequest {
+            target: target.clone(),
+            resolve: command == CMD_RESOLVE,
+            credentials,
+        });
+        let resolved = match &target {
+            ProxyTarget::Addr(addr) => Some(*addr),
+            ProxyTarget::Domain(name, _) => state.hosts.get(&name.to_ascii_lowercase()).copied(),
+        };
+        (resolved, state.reject_resolve)
+    };
+    info!("Mock SOCKS5 proxy received command {command:#x} for {target}");
+
+    match (command, resolved) {
+        (CMD_RESOLVE, _) if reject_resolve => {
+            reply(
+                &mut stream,
+                REPLY_COMMAND_NOT_SUPPORTED,
+                Ipv4Addr::UNSPECIFIED.into(),
+            )
+            .await
+        }
+        (CMD_RESOLVE, Some(addr)) => reply(&mut stream, REPLY_SUCCEEDED, addr.ip()).await,
+        (CMD_CONNECT, Some(addr)) => match TcpStream::connect(addr).await {
+            Ok(mut outbound) => {
+                reply(&mut stream, REPLY_SUCCEEDED, Ipv4Addr::UNSPECIFIED.into()).await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut outbound)
+                    .await
+                    .map(|_| ())
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                reply(
+                    &mut stream,
+                    REPLY_CONNECTION_REFUSED,
+                    Ipv4Addr::UNSPECIFIED.into(),
+                )
+                .await
+            }
+            Err(_) => {
+                reply(
+                    &mut stream,
+                    REPLY_GENERAL_FAILURE,
+                    Ipv4Addr::UNSPECIFIED.into(),
+                )
+                .await
+            }
+        },
+        (CMD_CONNECT | CMD_RESOLVE, None) => {
+            reply(
+                &mut stream,
+                REPLY_HOST_UNREACHABLE,
+                Ipv4Addr::UNSPECIFIED.into(),
+            )
+            .await
+        }
+        _ => {
+            reply(
+                &mut stream,
+                REPLY_COMMAND_NOT_SUPPORTED,
+                Ipv4Addr::UNSPECIFIED.into(),
+            )
+            .await
+        }
+    }
+}
+
+async fn read_credentials(stream: &mut TcpStream) -> io::Result<ProxyCredentials> {
+    let mut field = [0u8; 2];
+    stream.read_exact(&mut field).await?;
+    let mut username = vec![0u8; field[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+    Ok(ProxyCredentials {
+        username: String::from_utf8_lossy(&username).into_owned(),
+        password: String::from_utf8_lossy(&password).into_owned(),
+    })
+}
+
+async fn read_target(stream: &mut TcpStream, address_type: u8) -> io::Result<ProxyTarget> {
+    let target = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            ProxyTarget::Addr(SocketAddr::new(
+                Ipv4Addr::from(octets).into(),
+                stream.read_u16().await?,
+            ))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            ProxyTarget::Addr(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                stream.read_u16().await?,
+            ))
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            ProxyTarget::Domain(
+                String::from_utf8_lossy(&name).into_owned(),
+                stream.read_u16().await?,
+            )
+        }
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    Ok(target)
+}
+
+async fn reply(stream: &mut TcpStream, code: u8, bound: IpAddr) -> io::Result<()> {
+    let mut message = vec![0x05, code, 0x00];
+    // Encoding an address target never fails
+    message.extend(
+        proxy::encode_target(&ProxyTarget::Addr(SocketAddr::new(bound, 0))).unwrap_or_default(),
+    );
+    stream.write_all(&message).await
+}
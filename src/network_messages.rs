@@ -4,63 +4,248 @@ use bitcoin::network::{
     message_network::VersionMessage,
     Address,
 };
+use bitcoin::Network;
+use error_stack::{Report, Result};
 use rand::Rng;
-use std::net;
+use std::{error::Error, fmt, net};
 
-use crate::constants;
+use crate::{constants, PeerAddr};
 
-/// Builds and returns a version message tuple
+/// Maximum length of the user agent, as in Bitcoin Core `MAX_SUBVERSION_LENGTH`
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// Default user agent in the BIP14 format
+const DEFAULT_USER_AGENT: &str = concat!("/p2p-node-handshake:", env!("CARGO_PKG_VERSION"), "/");
+
+/// Version Params Error - invalid field of the outgoing version message
+#[derive(Debug)]
+pub struct VersionParamsError;
+
+impl fmt::Display for VersionParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Version params error: invalid version message field")
+    }
+}
+
+impl Error for VersionParamsError {}
+
+/// Fields of the outgoing `version` message.
+///
+/// Built from the defaults with the `with_*` methods:
+///
+/// ```
+/// use bitcoin::network::constants::ServiceFlags;
+/// use p2p_node_handshake::VersionParams;
+///
+/// let params = VersionParams::new()
+///     .with_protocol_version(70016)
+///     .with_services(ServiceFlags::NETWORK | ServiceFlags::WITNESS)
+///     .with_user_agent("/Satoshi:24.0.1/")
+///     .unwrap()
+///     .with_start_height(780_000)
+///     .with_relay(true);
+///
+/// assert_eq!(params.user_agent(), "/Satoshi:24.0.1/");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionParams {
+    protocol_version: u32,
+    services: ServiceFlags,
+    user_agent: String,
+    start_height: i32,
+    relay: bool,
+}
+
+impl Default for VersionParams {
+    fn default() -> Self {
+        Self {
+            protocol_version: constants::PROTOCOL_VERSION,
+            services: ServiceFlags::NONE,
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            start_height: 0,
+            relay: false,
+        }
+    }
+}
+
+impl VersionParams {
+    /// Construct VersionParams with the default fields
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the advertised protocol version
+    pub fn with_protocol_version(mut self, protocol_version: u32) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// Sets the advertised services
+    pub fn with_services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Sets the advertised user agent. The user agent must follow the BIP14 format,
+    /// e.g. `/Satoshi:24.0.1/` or `/BitcoinJ:0.2(iPad; U; CPU OS 3_2_1)/AndroidBuild:0.8/`.
+    pub fn with_user_agent(mut self, user_agent: &str) -> Result<Self, VersionParamsError> {
+        validate_user_agent(user_agent)?;
+        self.user_agent = user_agent.to_owned();
+        Ok(self)
+    }
+
+    /// Sets the advertised height of the best known block
+    pub fn with_start_height(mut self, start_height: i32) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    /// Sets whether the remote peer should announce transactions
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Returns the advertised protocol version
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Returns the advertised services
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    /// Returns the advertised user agent
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// Returns the advertised height of the best known block
+    pub fn start_height(&self) -> i32 {
+        self.start_height
+    }
+
+    /// Returns whether the remote peer should announce transactions
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
+}
+
+/// Checks that the `user_agent` follows the BIP14 format:
+/// one or more `/Name:Version/` components, each optionally followed by `(comments)`.
+fn validate_user_agent(user_agent: &str) -> Result<(), VersionParamsError> {
+    let invalid = |reason: &str| {
+        Err(Report::new(VersionParamsError)
+            .attach_printable(format!("Invalid BIP14 user agent {user_agent:?}: {reason}")))
+    };
+
+    if user_agent.len() > MAX_USER_AGENT_LENGTH {
+        return invalid("too long");
+    }
+    let Some(components) = user_agent
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+    else {
+        return invalid("must start and end with '/'");
+    };
+
+    for component in components.split('/') {
+        let (name_version, comments) = match component.split_once('(') {
+            Some((name_version, comments)) => (name_version, Some(comments)),
+            None => (component, None),
+        };
+        let Some((name, version)) = name_version.split_once(':') else {
+            return invalid("each component must be in the Name:Version form");
+        };
+        let is_valid_token = |token: &str| {
+            !token.is_empty()
+                && token
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || " .-_".contains(c))
+        };
+        if !is_valid_token(name) || !is_valid_token(version) {
+            return invalid("name and version must be non-empty and contain no special characters");
+        }
+        if let Some(comments) = comments {
+            let Some(comments) = comments.strip_suffix(')') else {
+                return invalid("comments must be closed with ')'");
+            };
+            if comments.contains(['(', ')']) {
+                return invalid("comments must not be nested");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds and returns a version message tuple.
+/// The receiver address of a Tor or I2P `remote_peer` is unspecified, as it does not fit into the `version` message.
 pub fn new_version_message(
     local_peer: net::SocketAddr,
-    remote_peer: net::SocketAddr,
+    remote_peer: &PeerAddr,
+    params: &VersionParams,
 ) -> (u32, NetworkMessage) {
-    const SERVICES: ServiceFlags = ServiceFlags::NONE;
-
     let timestamp = chrono::Utc::now().timestamp();
-    let receiver = Address::new(&remote_peer, SERVICES);
-    let sender = Address::new(&local_peer, SERVICES);
+    let receiver = remote_peer.to_address(ServiceFlags::NONE);
+    let sender = Address::new(&local_peer, params.services);
     let nonce = rand::thread_rng().gen();
-    let user_agent = "user-agent-bitcoin-p2p-handshake".to_owned();
-    let start_height = 0;
 
     // Construct the message
     let mut message = VersionMessage::new(
-        SERVICES,
+        params.services,
         timestamp,
         receiver,
         sender,
         nonce,
-        user_agent,
-        start_height,
+        params.user_agent.clone(),
+        params.start_height,
     );
 
-    message.version = constants::PROTOCOL_VERSION;
+    message.version = params.protocol_version;
+    message.relay = params.relay;
 
     (message.version, NetworkMessage::Version(message))
 }
 
-/// Make RawVersion message and serealize it. Returns a tuple of (protocol_verion, serealized_message)
+/// Make RawVersion message for the given `network` and serealize it.
+/// Returns a tuple of (protocol_verion, nonce, serealized_message)
 pub fn new_version_message_serialised(
+    network: Network,
     local_peer: net::SocketAddr,
-    remote_peer: net::SocketAddr,
-) -> (u32, Vec<u8>) {
-    let version_message_tup = new_version_message(local_peer, remote_peer);
+    remote_peer: &PeerAddr,
+    params: &VersionParams,
+) -> (u32, u64, Vec<u8>) {
+    let version_message_tup = new_version_message(local_peer, remote_peer, params);
+    let nonce = match &version_message_tup.1 {
+        NetworkMessage::Version(message) => message.nonce,
+        _ => 0,
+    };
     let version_message_local_raw = RawNetworkMessage {
-        magic: bitcoin::Network::Bitcoin.magic(),
+        magic: network.magic(),
         payload: version_message_tup.1,
     };
     (
         version_message_tup.0,
+        nonce,
         bitcoin::consensus::encode::serialize(&version_message_local_raw),
     )
 }
 
-/// Make local VerAck message and serealize it into bytes.
-pub fn make_verack_message_serialised() -> Vec<u8> {
+/// Make local VerAck message for the given `network` and serealize it into bytes.
+pub fn make_verack_message_serialised(network: Network) -> Vec<u8> {
     let message_verack_local = NetworkMessage::Verack;
     let message_verack_local_raw = RawNetworkMessage {
-        magic: bitcoin::Network::Bitcoin.magic(),
+        magic: network.magic(),
         payload: message_verack_local,
     };
     bitcoin::consensus::encode::serialize(&message_verack_local_raw)
 }
+
+/// Make a message with the given `payload` for the given `network` and serealize it into bytes.
+pub fn make_message_serialised(network: Network, payload: NetworkMessage) -> Vec<u8> {
+    bitcoin::consensus::encode::serialize(&RawNetworkMessage {
+        magic: network.magic(),
+        payload,
+    })
+}
@@ -0,0 +1,648 @@
+use clap::ValueEnum;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use serde::Serialize;
+use std::{error::Error, fmt, io, net::SocketAddr, str::FromStr};
+
+use crate::{
+    AddressBook, AddressBookEntry, CrawledPeer, HandshakeError, HandshakeOutcome, HandshakeStatus,
+    PeerAddr, PeerSource, SeedHealth,
+};
+
+/// Output Error - failed to format or write the command output
+#[derive(Debug)]
+pub struct OutputError;
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output error: failed to write the command output")
+    }
+}
+
+impl Error for OutputError {}
+
+/// Format of the records printed by the CLI commands to the standard output.
+/// Log messages are written to the standard error, so the output can be piped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable tables
+    #[default]
+    Text,
+    /// A JSON array of records, or one JSON record per line for commands that run until interrupted
+    Json,
+    /// A header line with the field names followed by one line per record
+    Csv,
+}
+
+/// Parses the same names as the `--output` option of the CLI
+impl FromStr for OutputFormat {
+    type Err = Report<OutputError>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, false).map_err(|_| {
+            let names: Vec<_> = Self::value_variants()
+                .iter()
+                .filter_map(ValueEnum::to_possible_value)
+                .map(|value| value.get_name().to_owned())
+                .collect();
+            Report::new(OutputError).attach_printable(format!(
+                "Unknown output format: {s:?}, expected one of {}",
+                names.join(", ")
+            ))
+        })
+    }
+}
+
+/// Record printed by a command. The JSON and CSV fields are the serialised struct fields, in declaration order.
+pub trait OutputRecord: Serialize {
+    /// Names of the CSV columns, the same as the serialised field names
+    const FIELDS: &'static [&'static str];
+
+    /// Header of the text table, if any
+    fn text_header() -> Option<String>;
+
+    /// Row of the text table
+    fn text_row(&self) -> String;
+}
+
+impl OutputFormat {
+    /// Prints all `records` of a command that ran to completion
+    pub(crate) fn print<R: OutputRecord>(self, records: &[R]) -> Result<(), OutputError> {
+        match self {
+            OutputFormat::Text => {
+                if let Some(header) = R::text_header() {
+                    println!("{header}");
+                }
+                for record in records {
+                    println!("{}", record.text_row());
+                }
+                Ok(())
+            }
+            OutputFormat::Json => {
+                let json = serde_json::to_string_pretty(records)
+                    .into_report()
+                    .change_context(OutputError)?;
+                println!("{json}");
+                Ok(())
+            }
+            OutputFormat::Csv => {
+                let mut writer = csv_writer();
+                writer
+                    .write_record(R::FIELDS)
+                    .into_report()
+                    .change_context(OutputError)?;
+                for record in records {
+                    writer
+                        .serialize(record)
+                        .into_report()
+                        .change_context(OutputError)?;
+                }
+                writer.flush().into_report().change_context(OutputError)
+            }
+        }
+    }
+
+    /// Prints the header of the records of a command that runs until interrupted
+    pub(crate) fn print_stream_header<R: OutputRecord>(self) -> Result<(), OutputError> {
+        match self {
+            OutputFormat::Text => {
+                if let Some(header) = R::text_header() {
+                    println!("{header}");
+                }
+                Ok(())
+            }
+            OutputFormat::Json => Ok(()),
+            OutputFormat::Csv => {
+                let mut writer = csv_writer();
+                writer
+                    .write_record(R::FIELDS)
+                    .into_report()
+                    .change_context(OutputError)?;
+                writer.flush().into_report().change_context(OutputError)
+            }
+        }
+    }
+
+    /// Prints a single `record` of a command that runs until interrupted
+    pub(crate) fn print_stream_record<R: OutputRecord>(
+        self,
+        record: &R,
+    ) -> Result<(), OutputError> {
+        match self {
+            OutputFormat::Text => {
+                println!("{}", record.text_row());
+                Ok(())
+            }
+            OutputFormat::Json => {
+                let json = serde_json::to_string(record)
+                    .into_report()
+                    .change_context(OutputError)?;
+                println!("{json}");
+                Ok(())
+            }
+            OutputFormat::Csv => {
+                let mut writer = csv_writer();
+                writer
+                    .serialize(record)
+                    .into_report()
+                    .change_context(OutputError)?;
+                writer.flush().into_report().change_context(OutputError)
+            }
+        }
+    }
+}
+
+/// Creates a CSV writer that does not write the header on its own
+fn csv_writer() -> csv::Writer<io::Stdout> {
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(io::stdout())
+}
+
+/// A DNS seed of the selected network, printed by `seeds list`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SeedRecord {
+    /// Index of the seed, used as the `<DNS URL INDEX>` argument
+    pub index: usize,
+    /// Host name of the seed
+    pub seed: String,
+}
+
+impl OutputRecord for SeedRecord {
+    const FIELDS: &'static [&'static str] = &["index", "seed"];
+
+    fn text_header() -> Option<String> {
+        None
+    }
+
+    fn text_row(&self) -> String {
+        format!("{}: {}", self.index, self.seed)
+    }
+}
+
+/// A node address resolved from a DNS seed, printed by `seeds resolve`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AddressRecord {
+    /// Index of the address, used as the `<REMOTE PEER URL INDEX>` argument
+    pub index: usize,
+    /// Address of the node
+    pub address: SocketAddr,
+    /// Host names of the DNS seeds that returned the address, separated by spaces
+    pub seed: String,
+}
+
+impl OutputRecord for AddressRecord {
+    const FIELDS: &'static [&'static str] = &["index", "address", "seed"];
+
+    fn text_header() -> Option<String> {
+        None
+    }
+
+    fn text_row(&self) -> String {
+        format!("{}: {}", self.index, self.address)
+    }
+}
+
+/// Result of a handshake, printed by `handshake`, `scan`, `book handshake` and `listen`.
+/// The fields of the remote `version` and the timings are only set if the handshake completed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HandshakeRecord {
+    /// Address of the remote peer, IP or Tor and I2P
+    pub address: PeerAddr,
+    /// `ok` or `failed`
+    pub status: &'static str,
+    /// Kind of the failure, e.g. `connect_timeout`, see `HandshakeErrorKind`
+    pub error_kind: Option<String>,
+    /// Protocol version advertised by the remote peer
+    pub protocol_version: Option<u32>,
+    /// Service bits advertised by the remote peer
+    pub services: Option<u64>,
+    /// User agent advertised by the remote peer
+    pub user_agent: Option<String>,
+    /// Best block height advertised by the remote peer
+    pub start_height: Option<i32>,
+    /// Whether the remote peer asked for transaction relay
+    pub relay: Option<bool>,
+    /// Lower of the local and the remote protocol versions
+    pub negotiated_version: Option<u32>,
+    /// Whether both peers sent `wtxidrelay`
+    pub wtxid_relay: Option<bool>,
+    /// Whether both peers sent `sendaddrv2`
+    pub addr_v2: Option<bool>,
+    /// Transport of the messages, `v1` or `v2`
+    pub transport: Option<&'static str>,
+    /// Duration of the connect phase in milliseconds
+    pub connect_ms: Option<u64>,
+    /// Duration of the version phase in milliseconds
+    pub version_ms: Option<u64>,
+    /// Duration of the verack phase in milliseconds
+    pub verack_ms: Option<u64>,
+    /// Duration of the whole handshake in milliseconds
+    pub total_ms: Option<u64>,
+}
+
+impl HandshakeRecord {
+    /// Creates the record of a handshake with the `address` that ended with the `result`
+    pub fn new(
+        address: impl Into<PeerAddr>,
+        result: &Result<HandshakeOutcome, HandshakeError>,
+    ) -> Self {
+        match result {
+            Ok(outcome) => Self::completed(outcome),
+            Err(e) => Self::failed(address.into(), HandshakeError::kind(e).to_string()),
+        }
+    }
+
+    /// Creates the record of a completed handshake
+    pub fn completed(outcome: &HandshakeOutcome) -> Self {
+        let peer = &outcome.peer;
+        Self {
+            address: outcome.remote_addr.clone(),
+            status: "ok",
+            error_kind: None,
+            protocol_version: Some(peer.version),
+            services: Some(peer.services.to_u64()),
+            user_agent: Some(peer.user_agent.clone()),
+            start_height: Some(peer.start_height),
+            relay: Some(peer.relay),
+            negotiated_version: Some(outcome.negotiated_version),
+            wtxid_relay: Some(outcome.negotiated_features.wtxid_relay),
+            addr_v2: Some(outcome.negotiated_features.addr_v2),
+            transport: Some(outcome.transport.as_str()),
+            connect_ms: Some(outcome.timings.connect.as_millis() as u64),
+            version_ms: Some(outcome.timings.version.as_millis() as u64),
+            verack_ms: Some(outcome.timings.verack.as_millis() as u64),
+            total_ms: Some(outcome.timings.total.as_millis() as u64),
+        }
+    }
+
+    fn failed(address: PeerAddr, error_kind: String) -> Self {
+        Self {
+            address,
+            status: "failed",
+            error_kind: Some(error_kind),
+            protocol_version: None,
+            services: None,
+            user_agent: None,
+            start_height: None,
+            relay: None,
+            negotiated_version: None,
+            wtxid_relay: None,
+            addr_v2: None,
+            transport: None,
+            connect_ms: None,
+            version_ms: None,
+            verack_ms: None,
+            total_ms: None,
+        }
+    }
+}
+
+impl OutputRecord for HandshakeRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "address",
+        "status",
+        "error_kind",
+        "protocol_version",
+        "services",
+        "user_agent",
+        "start_height",
+        "relay",
+        "negotiated_version",
+        "wtxid_relay",
+        "addr_v2",
+        "transport",
+        "connect_ms",
+        "version_ms",
+        "verack_ms",
+        "total_ms",
+    ];
+
+    fn text_header() -> Option<String> {
+        Some(format!(
+            "{:<48} {:<8} {:>10} {:>10} {:>10} {:>10} {:>8} {:>9}  USER AGENT / REASON",
+            "ADDRESS", "STATUS", "CONNECT", "VERSION", "VERACK", "LATENCY", "PROTOCOL", "TRANSPORT"
+        ))
+    }
+
+    fn text_row(&self) -> String {
+        let millis = |ms: Option<u64>| ms.map_or_else(|| "-".to_owned(), |ms| format!("{ms}ms"));
+        let reason = self
+            .user_agent
+            .as_deref()
+            .or(self.error_kind.as_deref())
+            .unwrap_or_default();
+        format!(
+            "{:<48} {:<8} {:>10} {:>10} {:>10} {:>10} {:>8} {:>9}  {}",
+            self.address,
+            self.status,
+            millis(self.connect_ms),
+            millis(self.version_ms),
+            millis(self.verack_ms),
+            millis(self.total_ms),
+            self.protocol_version
+                .map_or_else(|| "-".to_owned(), |version| version.to_string()),
+            self.transport.unwrap_or("-"),
+            reason
+        )
+    }
+}
+
+/// A peer found by a crawl, printed by `crawl`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrawlRecord {
+    /// Address of the peer
+    pub address: SocketAddr,
+    /// Number of gossip hops from the seeds
+    pub depth: usize,
+    /// Peer that gossiped the address, empty for the seeds
+    pub source: Option<SocketAddr>,
+    /// `ok`, `failed` or `not_attempted`
+    pub status: &'static str,
+    /// Kind of the failure, e.g. `connect_timeout`, see `HandshakeErrorKind`
+    pub error_kind: Option<String>,
+    /// Protocol version advertised by the peer
+    pub protocol_version: Option<u32>,
+    /// User agent advertised by the peer
+    pub user_agent: Option<String>,
+    /// Number of addresses gossiped by the peer
+    pub gossiped: usize,
+}
+
+impl CrawlRecord {
+    /// Creates the record of the crawled `peer` with the `address`
+    pub fn new(address: SocketAddr, peer: &CrawledPeer) -> Self {
+        let (status, error_kind, outcome) = match &peer.status {
+            Some(HandshakeStatus::Completed(outcome)) => ("ok", None, Some(outcome)),
+            Some(HandshakeStatus::Failed(kind)) => ("failed", Some(kind.to_string()), None),
+            None => ("not_attempted", None, None),
+        };
+        Self {
+            address,
+            depth: peer.depth,
+            source: peer.source,
+            status,
+            error_kind,
+            protocol_version: outcome.map(|outcome| outcome.peer.version),
+            user_agent: outcome.map(|outcome| outcome.peer.user_agent.clone()),
+            gossiped: peer.gossiped.len(),
+        }
+    }
+}
+
+impl OutputRecord for CrawlRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "address",
+        "depth",
+        "source",
+        "status",
+        "error_kind",
+        "protocol_version",
+        "user_agent",
+        "gossiped",
+    ];
+
+    fn text_header() -> Option<String> {
+        Some(format!(
+            "{:<48} {:>5} {:<18} {:>8}  SOURCE",
+            "ADDRESS", "DEPTH", "STATUS", "GOSSIPED"
+        ))
+    }
+
+    fn text_row(&self) -> String {
+        let status = self.error_kind.as_deref().unwrap_or(self.status);
+        let source = self
+            .source
+            .map_or_else(|| "seed".to_owned(), |source| source.to_string());
+        format!(
+            "{:<48} {:>5} {:<18} {:>8}  {}",
+            self.address, self.depth, status, self.gossiped, source
+        )
+    }
+}
+
+/// Health of a DNS seed, printed by `seeds check`.
+/// The handshake fields are empty if the lookup failed or no handshake completed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SeedHealthRecord {
+    /// DNS seed as given in the settings
+    pub seed: String,
+    /// `ok` or `failed`, the status of the seed lookup
+    pub status: &'static str,
+    /// Reason of the failed lookup
+    pub error: Option<String>,
+    /// Duration of the seed lookup
+    pub lookup_ms: u64,
+    /// Number of addresses returned by the seed
+    pub addresses: usize,
+    /// Number of IPv4 addresses
+    pub ipv4: usize,
+    /// Number of IPv6 addresses
+    pub ipv6: usize,
+    /// Number of addresses handshaked with
+    pub sampled: usize,
+    /// Number of sampled addresses that completed the handshake
+    pub reachable: usize,
+    /// Percentage of the sampled addresses that completed the handshake, rounded to one decimal
+    pub reachable_percent: Option<f64>,
+    /// Median duration of the completed handshakes
+    pub median_latency_ms: Option<u64>,
+    /// Number of completed handshakes by protocol version, e.g. `70016:7 70015:2`, the newest version first
+    pub versions: String,
+}
+
+impl SeedHealthRecord {
+    /// Creates the record of the seed `health`
+    pub fn new(health: &SeedHealth) -> Self {
+        Self {
+            seed: health.seed.clone(),
+            status: if health.lookup_error.is_none() {
+                "ok"
+            } else {
+                "failed"
+            },
+            error: health.lookup_error.clone(),
+            lookup_ms: health.lookup_latency.as_millis() as u64,
+            addresses: health.addresses,
+            ipv4: health.ipv4,
+            ipv6: health.ipv6,
+            sampled: health.sampled,
+            reachable: health.reachable,
+            reachable_percent: health
+                .reachable_percent()
+                .map(|percent| (percent * 10.0).round() / 10.0),
+            median_latency_ms: health
+                .median_latency
+                .map(|latency| latency.as_millis() as u64),
+            versions: health
+                .versions
+                .iter()
+                .rev()
+                .map(|(version, count)| format!("{version}:{count}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+impl OutputRecord for SeedHealthRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "seed",
+        "status",
+        "error",
+        "lookup_ms",
+        "addresses",
+        "ipv4",
+        "ipv6",
+        "sampled",
+        "reachable",
+        "reachable_percent",
+        "median_latency_ms",
+        "versions",
+    ];
+
+    fn text_header() -> Option<String> {
+        Some(format!(
+            "{:<40} {:>9} {:>6} {:>6} {:>7} {:>9} {:>9}  VERSIONS / REASON",
+            "SEED", "ADDRESSES", "IPV4", "IPV6", "SAMPLED", "REACHABLE", "LATENCY"
+        ))
+    }
+
+    fn text_row(&self) -> String {
+        if let Some(error) = &self.error {
+            return format!(
+                "{:<40} {:>9} {:>6} {:>6} {:>7} {:>9} {:>9}  {}",
+                self.seed, "-", "-", "-", "-", "-", "-", error
+            );
+        }
+        let reachable = self
+            .reachable_percent
+            .map_or_else(|| "-".to_owned(), |percent| format!("{percent:.1}%"));
+        let latency = self
+            .median_latency_ms
+            .map_or_else(|| "-".to_owned(), |ms| format!("{ms}ms"));
+        format!(
+            "{:<40} {:>9} {:>6} {:>6} {:>7} {:>9} {:>9}  {}",
+            self.seed,
+            self.addresses,
+            self.ipv4,
+            self.ipv6,
+            self.sampled,
+            reachable,
+            latency,
+            self.versions
+        )
+    }
+}
+
+/// A peer of the address book, printed by `book list`. Times are Unix timestamps in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AddressBookRecord {
+    /// Address of the peer
+    pub address: PeerAddr,
+    /// `seed`, `peer`, `inbound` or `manual`
+    pub source_kind: &'static str,
+    /// Host name of the DNS seed or address of the peer the address was learned from
+    pub source: Option<String>,
+    /// When the address was learned
+    pub first_seen: i64,
+    /// When the last handshake was attempted
+    pub last_attempt: Option<i64>,
+    /// When the last handshake completed
+    pub last_success: Option<i64>,
+    /// Number of failed handshakes
+    pub failure_count: u32,
+    /// Kind of the last handshake failure
+    pub last_error: Option<String>,
+    /// Last protocol version advertised by the peer
+    pub protocol_version: Option<u32>,
+    /// Last service bits advertised by the peer
+    pub services: Option<u64>,
+    /// Last user agent advertised by the peer
+    pub user_agent: Option<String>,
+    /// Last best block height advertised by the peer
+    pub start_height: Option<i32>,
+}
+
+impl AddressBookRecord {
+    /// Creates the records of all peers of the `address_book`, ordered by address
+    pub fn list(address_book: &AddressBook) -> Vec<Self> {
+        address_book
+            .peers()
+            .iter()
+            .map(|(address, entry)| Self::new(address.clone(), entry))
+            .collect()
+    }
+
+    /// Creates the record of the address book `entry` of the `address`
+    pub fn new(address: PeerAddr, entry: &AddressBookEntry) -> Self {
+        let (source_kind, source) = match &entry.source {
+            PeerSource::Seed(seed) => ("seed", Some(seed.clone())),
+            PeerSource::Peer(peer) => ("peer", Some(peer.to_string())),
+            PeerSource::Inbound => ("inbound", None),
+            PeerSource::Manual => ("manual", None),
+        };
+        let version = entry.last_version.as_ref();
+        Self {
+            address,
+            source_kind,
+            source,
+            first_seen: entry.first_seen,
+            last_attempt: entry.last_attempt,
+            last_success: entry.last_success,
+            failure_count: entry.failure_count,
+            last_error: entry.last_error.clone(),
+            protocol_version: version.map(|version| version.version),
+            services: version.map(|version| version.services),
+            user_agent: version.map(|version| version.user_agent.clone()),
+            start_height: version.map(|version| version.start_height),
+        }
+    }
+}
+
+impl OutputRecord for AddressBookRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "address",
+        "source_kind",
+        "source",
+        "first_seen",
+        "last_attempt",
+        "last_success",
+        "failure_count",
+        "last_error",
+        "protocol_version",
+        "services",
+        "user_agent",
+        "start_height",
+    ];
+
+    fn text_header() -> Option<String> {
+        Some(format!(
+            "{:<48} {:<19} {:<19} {:>8} {:>8}  SOURCE",
+            "ADDRESS", "LAST ATTEMPT", "LAST SUCCESS", "FAILURES", "PROTOCOL"
+        ))
+    }
+
+    fn text_row(&self) -> String {
+        let format_time = |time: Option<i64>| {
+            time.and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+                .map_or_else(
+                    || "-".to_owned(),
+                    |time| time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                )
+        };
+        let source = match &self.source {
+            Some(source) => format!("{} {source}", self.source_kind),
+            None => self.source_kind.to_owned(),
+        };
+        format!(
+            "{:<48} {:<19} {:<19} {:>8} {:>8}  {}",
+            self.address,
+            format_time(self.last_attempt),
+            format_time(self.last_success),
+            self.failure_count,
+            self.protocol_version
+                .map_or_else(|| "-".to_owned(), |version| version.to_string()),
+            source
+        )
+    }
+}
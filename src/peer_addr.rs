@@ -0,0 +1,241 @@
+//! Addresses of the peers reachable over IP, Tor (onion v3) and I2P, see
+//! [BIP155](https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki).
+//!
+//! Tor and I2P peers have no IP address: they are reached by name through a SOCKS5 proxy,
+//! and gossiped only in `addrv2` messages. A host name given by the user can also be connected to
+//! by name through the proxy, so that the proxy resolves it, but it is never gossiped.
+use bitcoin::network::{
+    address::{AddrV2, AddrV2Message},
+    constants::ServiceFlags,
+    Address,
+};
+use data_encoding::BASE32_NOPAD;
+use error_stack::{IntoReport, Report, ResultExt};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha3::{Digest, Sha3_256};
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::ProxyTarget;
+
+const ONION_SUFFIX: &str = ".onion";
+const I2P_SUFFIX: &str = ".b32.i2p";
+const TOR_V3_VERSION: u8 = 3;
+
+/// Peer Address Error - the address is not an IP, onion v3 or I2P address with port
+#[derive(Debug)]
+pub struct PeerAddrError;
+
+impl fmt::Display for PeerAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Peer address error: invalid peer address")
+    }
+}
+
+impl Error for PeerAddrError {}
+
+/// Address of a peer: an IP socket address, a Tor v3 or I2P address, or a host name resolved by the proxy, with port
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PeerAddr {
+    /// IPv4 or IPv6 socket address
+    Ip(SocketAddr),
+    /// Tor onion service v3, identified by its ed25519 public key
+    TorV3 { pubkey: [u8; 32], port: u16 },
+    /// I2P destination, identified by the SHA256 hash of the destination
+    I2p { hash: [u8; 32], port: u16 },
+    /// Host name that is sent to the proxy to resolve when connecting, never to the local resolver
+    Name { host: String, port: u16 },
+}
+
+impl PeerAddr {
+    /// Returns the socket address of an IP peer, `None` for Tor and I2P peers and host names
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            PeerAddr::Ip(addr) => Some(*addr),
+            PeerAddr::TorV3 { .. } | PeerAddr::I2p { .. } | PeerAddr::Name { .. } => None,
+        }
+    }
+
+    /// Returns the port of the peer
+    pub fn port(&self) -> u16 {
+        match self {
+            PeerAddr::Ip(addr) => addr.port(),
+            PeerAddr::TorV3 { port, .. }
+            | PeerAddr::I2p { port, .. }
+            | PeerAddr::Name { port, .. } => *port,
+        }
+    }
+
+    /// Returns `true` for Tor and I2P peers, which can only be reached through a proxy
+    pub fn is_overlay(&self) -> bool {
+        matches!(self, PeerAddr::TorV3 { .. } | PeerAddr::I2p { .. })
+    }
+
+    /// Returns the host of the peer: the IP address, `<base32>.onion`, `<base32>.b32.i2p` or the host name
+    pub fn host(&self) -> String {
+        match self {
+            PeerAddr::Ip(addr) => addr.ip().to_string(),
+            PeerAddr::TorV3 { pubkey, .. } => {
+                let mut onion = pubkey.to_vec();
+                onion.extend_from_slice(&onion_checksum(pubkey));
+                onion.push(TOR_V3_VERSION);
+                format!("{}{ONION_SUFFIX}", base32_encode(&onion))
+            }
+            PeerAddr::I2p { hash, .. } => format!("{}{I2P_SUFFIX}", base32_encode(hash)),
+            PeerAddr::Name { host, .. } => host.clone(),
+        }
+    }
+
+    /// Parses a Tor v3 or I2P `host` name, `None` if the host is not one of them.
+    /// Onion names must carry a valid checksum.
+    pub fn from_overlay_host(host: &str, port: u16) -> Option<Self> {
+        let host = host.to_ascii_lowercase();
+        if let Some(name) = host.strip_suffix(I2P_SUFFIX) {
+            let hash = base32_decode(name)?.try_into().ok()?;
+            return (name.len() == 52).then_some(PeerAddr::I2p { hash, port });
+        }
+        let name = host.strip_suffix(ONION_SUFFIX)?;
+        let onion = base32_decode(name)?;
+        if name.len() != 56 || onion.len() != 35 || onion[34] != TOR_V3_VERSION {
+            return None;
+        }
+        let pubkey: [u8; 32] = onion[..32].try_into().ok()?;
+        (onion[32..34] == onion_checksum(&pubkey)).then_some(PeerAddr::TorV3 { pubkey, port })
+    }
+
+    /// Converts a gossiped `addrv2` entry, `None` for the networks that are not supported
+    pub fn from_addr_v2(message: &AddrV2Message) -> Option<Self> {
+        let port = message.port;
+        match message.addr {
+            AddrV2::Ipv4(ip) => Some(PeerAddr::Ip(SocketAddr::new(IpAddr::V4(ip), port))),
+            AddrV2::Ipv6(ip) => Some(PeerAddr::Ip(SocketAddr::new(IpAddr::V6(ip), port))),
+            AddrV2::TorV3(pubkey) => Some(PeerAddr::TorV3 { pubkey, port }),
+            AddrV2::I2p(hash) => Some(PeerAddr::I2p { hash, port }),
+            AddrV2::TorV2(_) | AddrV2::Cjdns(_) | AddrV2::Unknown(..) => None,
+        }
+    }
+
+    /// Returns the `addrv2` address of the peer, `None` for a host name
+    pub fn to_addr_v2(&self) -> Option<AddrV2> {
+        match self {
+            PeerAddr::Ip(SocketAddr::V4(addr)) => Some(AddrV2::Ipv4(*addr.ip())),
+            PeerAddr::Ip(SocketAddr::V6(addr)) => Some(AddrV2::Ipv6(*addr.ip())),
+            PeerAddr::TorV3 { pubkey, .. } => Some(AddrV2::TorV3(*pubkey)),
+            PeerAddr::I2p { hash, .. } => Some(AddrV2::I2p(*hash)),
+            PeerAddr::Name { .. } => None,
+        }
+    }
+
+    /// Returns the address of the peer in the legacy format of the `version` and `addr` messages.
+    /// Tor v3 and I2P addresses and host names do not fit into it and are sent as the unspecified address
+    /// with port 0, as Bitcoin Core does.
+    pub fn to_address(&self, services: ServiceFlags) -> Address {
+        match self {
+            PeerAddr::Ip(addr) => Address::new(addr, services),
+            PeerAddr::TorV3 { .. } | PeerAddr::I2p { .. } | PeerAddr::Name { .. } => Address {
+                services,
+                address: [0; 8],
+                port: 0,
+            },
+        }
+    }
+
+    /// Returns the destination of a proxied connection: the socket address, or the name of a Tor or I2P peer
+    /// or of a host that the proxy resolves
+    pub fn proxy_target(&self) -> ProxyTarget {
+        match self {
+            PeerAddr::Ip(addr) => ProxyTarget::Addr(*addr),
+            PeerAddr::TorV3 { port, .. }
+            | PeerAddr::I2p { port, .. }
+            | PeerAddr::Name { port, .. } => ProxyTarget::Domain(self.host(), *port),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Ip(addr)
+    }
+}
+
+impl PartialEq<SocketAddr> for PeerAddr {
+    fn eq(&self, other: &SocketAddr) -> bool {
+        self.socket_addr().as_ref() == Some(other)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => fmt::Display::fmt(addr, f),
+            PeerAddr::TorV3 { port, .. }
+            | PeerAddr::I2p { port, .. }
+            | PeerAddr::Name { port, .. } => f.pad(&format!("{}:{port}", self.host())),
+        }
+    }
+}
+
+impl FromStr for PeerAddr {
+    type Err = Report<PeerAddrError>;
+
+    /// Parses `ip:port`, `[ipv6]:port`, `<base32>.onion:port` or `<base32>.b32.i2p:port`.
+    /// Host names are not parsed, they are connected to only when given as a `Target`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(PeerAddr::Ip(addr));
+        }
+        let (host, port) = s.rsplit_once(':').ok_or_else(|| {
+            Report::new(PeerAddrError).attach_printable(format!("Missing port in {s:?}"))
+        })?;
+        let port = port
+            .parse()
+            .into_report()
+            .change_context(PeerAddrError)
+            .attach_printable_lazy(|| format!("Invalid port in {s:?}"))?;
+        PeerAddr::from_overlay_host(host, port).ok_or_else(|| {
+            Report::new(PeerAddrError).attach_printable(format!(
+                "{host:?} is not an IP address, a valid onion v3 or a .b32.i2p address"
+            ))
+        })
+    }
+}
+
+impl Serialize for PeerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid peer address {s:?}")))
+    }
+}
+
+/// Returns the checksum of an onion v3 address: the first 2 bytes of SHA3-256(".onion checksum" | pubkey | version)
+fn onion_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut preimage = b".onion checksum".to_vec();
+    preimage.extend_from_slice(pubkey);
+    preimage.push(TOR_V3_VERSION);
+    let hash = Sha3_256::digest(&preimage);
+    [hash[0], hash[1]]
+}
+
+/// Encodes the `data` with the lower case RFC 4648 base32 alphabet, without padding
+fn base32_encode(data: &[u8]) -> String {
+    BASE32_NOPAD.encode(data).to_ascii_lowercase()
+}
+
+/// Decodes lower case base32 without padding, `None` on characters outside of the alphabet
+/// or on trailing bits that are not zero
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    BASE32_NOPAD
+        .decode(encoded.to_ascii_uppercase().as_bytes())
+        .ok()
+}
@@ -0,0 +1,301 @@
+use bitcoin::{
+    network::message::{NetworkMessage, RawNetworkMessage},
+    Network,
+};
+use error_stack::{Report, Result, ResultExt};
+use log::{info, warn};
+use rand::Rng;
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    sync::{mpsc, Mutex as AsyncMutex},
+    task::{AbortHandle, JoinHandle},
+};
+
+use crate::{
+    handshake_manager::HandshakeErrorKind,
+    handshake_outcome::HandshakeOutcome,
+    network_messages,
+    transport::{Connection, MessageReader, MessageWriter, TransportError},
+};
+
+/// Peer Session Error - failed to exchange messages with the remote peer after the handshake
+#[derive(Debug)]
+pub struct PeerSessionError;
+
+impl fmt::Display for PeerSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Peer session error: failed to exchange message with the remote peer"
+        )
+    }
+}
+
+impl Error for PeerSessionError {}
+
+/// Ping sent to the remote peer and not answered yet
+#[derive(Debug, Default)]
+struct PingState {
+    pending: Option<(u64, Instant)>,
+    latency: Option<Duration>,
+    /// The reader waits for room in the buffer and does not read the connection
+    paused: bool,
+    /// The reader paused since the pending `ping` was sent, so its `pong` may not be read yet
+    stalled: bool,
+}
+
+/// Connection with a remote peer that completed the version handshake.
+///
+/// The session owns the connection and keeps it alive in the background:
+/// every `ping` of the remote peer is answered with `pong`, and a `ping` is sent every
+/// ping interval to measure the round trip latency. A `ping` that is still not answered
+/// when the next one is due closes the session as disconnected. All other received messages
+/// are buffered until they are taken with `recv`, so `ping` and `pong` are handled even when `recv`
+/// is not called. Only when the buffer reaches the session buffer size, the session stops reading
+/// from the connection until `recv` makes room, and pauses its own pings meanwhile: a `pong` that
+/// could not be read is not counted against the remote peer.
+/// The connection is closed when the session is dropped.
+pub struct PeerSession {
+    outcome: HandshakeOutcome,
+    network: Network,
+    writer: Arc<AsyncMutex<MessageWriter<OwnedWriteHalf>>>,
+    ping_state: Arc<Mutex<PingState>>,
+    received: mpsc::Receiver<Result<NetworkMessage, PeerSessionError>>,
+    reader_handle: JoinHandle<()>,
+    pinger_handle: JoinHandle<()>,
+}
+
+impl PeerSession {
+    /// Starts a session on the `connection` of a completed handshake described by the `outcome`.
+    /// At most `buffer_size` received messages are buffered until they are taken with `recv`.
+    pub(crate) fn start(
+        connection: Connection,
+        outcome: HandshakeOutcome,
+        network: Network,
+        ping_interval: Duration,
+        buffer_size: usize,
+    ) -> Self {
+        let (reader, writer) = connection.into_split();
+        let writer = Arc::new(AsyncMutex::new(writer));
+        let ping_state = Arc::new(Mutex::new(PingState::default()));
+        // The messages wait in the buffer of the reader, the channel only hands them over
+        let (sender, received) = mpsc::channel(1);
+        let ping_sender = sender.downgrade();
+
+        let reader_handle = tokio::spawn(read_loop(
+            reader,
+            network,
+            buffer_size.max(1),
+            Arc::clone(&writer),
+            Arc::clone(&ping_state),
+            sender,
+        ));
+        let pinger_handle = tokio::spawn(ping_loop(
+            network,
+            ping_interval,
+            Arc::clone(&writer),
+            Arc::clone(&ping_state),
+            ping_sender,
+            reader_handle.abort_handle(),
+        ));
+
+        Self {
+            outcome,
+            network,
+            writer,
+            ping_state,
+            received,
+            reader_handle,
+            pinger_handle,
+        }
+    }
+
+    /// Returns the outcome of the handshake that opened the session
+    pub fn outcome(&self) -> &HandshakeOutcome {
+        &self.outcome
+    }
+
+    /// Returns the round trip time of the last answered `ping`, if any
+    pub fn latency(&self) -> Option<Duration> {
+        self.ping_state.lock().ok().and_then(|state| state.latency)
+    }
+
+    /// Sends a `message` to the remote peer
+    pub async fn send(&self, message: NetworkMessage) -> Result<(), PeerSessionError> {
+        send_message(&self.writer, self.network, message).await
+    }
+
+    /// Waits for the next message from the remote peer, except `ping` and `pong` that are handled by the session.
+    /// Fails when the remote peer disconnected or sent an invalid message.
+    pub async fn recv(&mut self) -> Result<NetworkMessage, PeerSessionError> {
+        match self.received.recv().await {
+            Some(result) => result,
+            None => Err(Report::new(PeerSessionError)
+                .attach(HandshakeErrorKind::PeerDisconnected)
+                .attach_printable(format!(
+                    "Session with {} is closed",
+                    self.outcome.remote_addr
+                ))),
+        }
+    }
+}
+
+impl Drop for PeerSession {
+    fn drop(&mut self) {
+        self.reader_handle.abort();
+        self.pinger_handle.abort();
+    }
+}
+
+/// Serialises and sends a `message` through the shared `writer`
+async fn send_message(
+    writer: &AsyncMutex<MessageWriter<OwnedWriteHalf>>,
+    network: Network,
+    message: NetworkMessage,
+) -> Result<(), PeerSessionError> {
+    let command = message.cmd();
+    let message_bytes = network_messages::make_message_serialised(network, message);
+    let mut writer = writer.lock().await;
+    writer
+        .write_message(&message_bytes)
+        .await
+        .attach_printable_lazy(|| format!("Failed to send {command} message"))
+        .change_context(PeerSessionError)
+}
+
+/// Reads the next message of the `reader` and gives the reader back to read the following one
+async fn read_next(
+    mut reader: MessageReader<OwnedReadHalf>,
+    magic: u32,
+) -> (
+    MessageReader<OwnedReadHalf>,
+    Result<RawNetworkMessage, TransportError>,
+) {
+    let result = reader.read_message(magic).await;
+    (reader, result)
+}
+
+/// Reads messages until the connection fails: answers `ping`, measures `pong`, forwards everything else.
+/// Up to `buffer_size` messages wait for the consumer while the connection is still read.
+async fn read_loop(
+    reader: MessageReader<OwnedReadHalf>,
+    network: Network,
+    buffer_size: usize,
+    writer: Arc<AsyncMutex<MessageWriter<OwnedWriteHalf>>>,
+    ping_state: Arc<Mutex<PingState>>,
+    sender: mpsc::Sender<Result<NetworkMessage, PeerSessionError>>,
+) {
+    let mut buffered = VecDeque::new();
+    let mut closed = false;
+    let mut was_paused = false;
+    // The read is kept across the iterations: dropping it halfway would lose the bytes read so far
+    let mut next = std::pin::pin!(read_next(reader, network.magic()));
+    loop {
+        // Waits while the buffer is full, so that a slow consumer slows down the remote peer
+        let paused = !closed && buffered.len() >= buffer_size;
+        if paused != was_paused {
+            if let Ok(mut state) = ping_state.lock() {
+                state.paused = paused;
+                state.stalled |= paused;
+            }
+            was_paused = paused;
+        }
+
+        tokio::select! {
+            (reader, result) = &mut next, if !closed && !paused => {
+                match result.map(|message| message.payload) {
+                    Ok(NetworkMessage::Ping(nonce)) => {
+                        if let Err(e) =
+                            send_message(&writer, network, NetworkMessage::Pong(nonce)).await
+                        {
+                            buffered.push_back(Err(e));
+                            closed = true;
+                        }
+                    }
+                    Ok(NetworkMessage::Pong(nonce)) => {
+                        if let Ok(mut state) = ping_state.lock() {
+                            match state.pending {
+                                Some((pending_nonce, sent_at)) if pending_nonce == nonce => {
+                                    state.latency = Some(sent_at.elapsed());
+                                    state.pending = None;
+                                }
+                                _ => warn!("Received unsolicited pong with nonce {nonce}"),
+                            }
+                        }
+                    }
+                    Ok(message) => buffered.push_back(Ok(message)),
+                    Err(e) => {
+                        buffered.push_back(Err(e.change_context(PeerSessionError)));
+                        closed = true;
+                    }
+                }
+                if !closed {
+                    next.set(read_next(reader, network.magic()));
+                }
+            }
+            permit = sender.reserve(), if !buffered.is_empty() => {
+                let (Ok(permit), Some(message)) = (permit, buffered.pop_front()) else {
+                    return;
+                };
+                permit.send(message);
+            }
+            else => return,
+        }
+    }
+}
+
+/// Sends a `ping` every `ping_interval`, the first one right away.
+/// Closes the session when the previous `ping` is still not answered at the next one,
+/// the `reader` is stopped and the disconnect is reported through the `sender`.
+async fn ping_loop(
+    network: Network,
+    ping_interval: Duration,
+    writer: Arc<AsyncMutex<MessageWriter<OwnedWriteHalf>>>,
+    ping_state: Arc<Mutex<PingState>>,
+    sender: mpsc::WeakSender<Result<NetworkMessage, PeerSessionError>>,
+    reader: AbortHandle,
+) {
+    // A zero interval is not allowed by tokio
+    let mut interval = tokio::time::interval(ping_interval.max(Duration::from_millis(1)));
+    loop {
+        interval.tick().await;
+        let nonce = rand::thread_rng().gen();
+        let unanswered = match ping_state.lock() {
+            Ok(mut state) => {
+                // The `pong` may wait unread behind a full buffer: the consumer is behind, not the remote peer
+                if state.paused || (std::mem::take(&mut state.stalled) && state.pending.is_some()) {
+                    continue;
+                }
+                state.pending.replace((nonce, Instant::now()))
+            }
+            Err(_) => None,
+        };
+        if let Some((unanswered_nonce, sent_at)) = unanswered {
+            // The session is already closed if the reader dropped its sender
+            let Some(sender) = sender.upgrade() else {
+                return;
+            };
+            reader.abort();
+            let _ = writer.lock().await.shutdown().await;
+            let report = Report::new(PeerSessionError)
+                .attach(HandshakeErrorKind::PeerDisconnected)
+                .attach_printable(format!(
+                    "Remote peer did not answer ping {unanswered_nonce} within {}ms",
+                    sent_at.elapsed().as_millis()
+                ));
+            let _ = sender.send(Err(report)).await;
+            return;
+        }
+        if let Err(e) = send_message(&writer, network, NetworkMessage::Ping(nonce)).await {
+            info!("Stop pinging, failed to send ping: {e:?}");
+            return;
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! SOCKS5 proxy client (RFC 1928) with username/password authentication (RFC 1929)
+//! and the `RESOLVE` command of Tor for name lookups through the proxy.
+//!
+//! Peers given by host name are connected to with a `CONNECT` request for the name, which every SOCKS5 proxy
+//! resolves itself. `RESOLVE` is used only where an IP address is needed, for the DNS seeds without a nameserver.
+//! It is an extension of Tor: other proxies reject it, which is reported as such.
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::HandshakeErrorKind;
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+pub(crate) const CMD_CONNECT: u8 = 0x01;
+/// Tor extension: resolves a host name into an address
+pub(crate) const CMD_RESOLVE: u8 = 0xf0;
+
+pub(crate) const ATYP_IPV4: u8 = 0x01;
+pub(crate) const ATYP_DOMAIN: u8 = 0x03;
+pub(crate) const ATYP_IPV6: u8 = 0x04;
+
+pub(crate) const REPLY_SUCCEEDED: u8 = 0x00;
+pub(crate) const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub(crate) const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub(crate) const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub(crate) const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub(crate) const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub(crate) const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub(crate) const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub(crate) const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Proxy Error - the SOCKS5 proxy could not be reached or did not complete the request
+#[derive(Debug)]
+pub struct ProxyError;
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Proxy error: SOCKS5 request failed")
+    }
+}
+
+impl Error for ProxyError {}
+
+/// Username and password of the SOCKS5 proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Destination of a connection through the proxy
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProxyTarget {
+    /// IPv4 or IPv6 socket address
+    Addr(SocketAddr),
+    /// Host name resolved by the proxy, and port
+    Domain(String, u16),
+}
+
+impl fmt::Display for ProxyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyTarget::Addr(addr) => write!(f, "{addr}"),
+            ProxyTarget::Domain(name, port) => write!(f, "{name}:{port}"),
+        }
+    }
+}
+
+/// Settings of the SOCKS5 proxy used for the outbound connections and the DNS seed lookups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxySettings {
+    /// Address of the proxy
+    pub addr: SocketAddr,
+    /// Credentials sent to the proxy, no authentication if `None`
+    pub credentials: Option<ProxyCredentials>,
+    /// Sends random credentials with every connection, so that Tor uses a separate circuit for each of them.
+    /// Given credentials are used as the prefix of the random username and as the password.
+    pub isolate_streams: bool,
+}
+
+impl ProxySettings {
+    /// Returns the settings of a proxy at `addr` without authentication and stream isolation
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            credentials: None,
+            isolate_streams: false,
+        }
+    }
+
+    /// Connects to the `target` through the proxy.
+    ///
+    /// A refused connection is reported with the `HandshakeErrorKind::ConnectRefused` kind,
+    /// an unreachable target with `HandshakeErrorKind::ConnectFailed` and a failure of the proxy itself
+    /// with `HandshakeErrorKind::ProxyFailed`.
+    pub async fn connect(&self, target: &ProxyTarget) -> Result<TcpStream, ProxyError> {
+        let mut stream = self.open().await?;
+        let (reply, _) = self
+            .request(&mut stream, CMD_CONNECT, target)
+            .await
+            .attach_printable_lazy(|| {
+                format!(
+                    "Failed to connect to {target} through the proxy {}",
+                    self.addr
+                )
+            })?;
+        if reply != REPLY_SUCCEEDED {
+            let kind = match reply {
+                REPLY_CONNECTION_REFUSED => HandshakeErrorKind::ConnectRefused,
+                REPLY_NETWORK_UNREACHABLE | REPLY_HOST_UNREACHABLE | REPLY_TTL_EXPIRED => {
+                    HandshakeErrorKind::ConnectFailed
+                }
+                _ => HandshakeErrorKind::ProxyFailed,
+            };
+            return Err(Report::new(ProxyError)
+                .attach(kind)
+                .attach_printable(format!(
+                    "Proxy {} failed to connect to {target}: {}",
+                    self.addr,
+                    reply_reason(reply)
+                )));
+        }
+        Ok(stream)
+    }
+
+    /// Resolves the host `name` with the `RESOLVE` command of Tor. Returns the single address chosen by the proxy.
+    /// Only Tor supports the command, other proxies fail with the `HandshakeErrorKind::ProxyFailed` kind.
+    pub async fn resolve(&self, name: &str) -> Result<IpAddr, ProxyError> {
+        let mut stream = self.open().await?;
+        let (reply, bound) = self
+            .request(
+                &mut stream,
+                CMD_RESOLVE,
+                &ProxyTarget::Domain(name.to_owned(), 0),
+            )
+            .await
+            .attach_printable_lazy(|| {
+                format!("Failed to resolve {name:?} through the proxy {}", self.addr)
+            })?;
+        match (reply, bound) {
+            (REPLY_SUCCEEDED, Some(addr)) => Ok(addr.ip()),
+            (REPLY_COMMAND_NOT_SUPPORTED, _) => Err(Report::new(ProxyError)
+                .attach(HandshakeErrorKind::ProxyFailed)
+                .attach_printable(format!(
+                    "Proxy {} does not support the RESOLVE command: name lookups through a proxy \
+                     need Tor, or a nameserver that is queried through the proxy",
+                    self.addr
+                ))),
+            _ => Err(Report::new(ProxyError).attach_printable(format!(
+                "Proxy {} failed to resolve {name:?}: {}",
+                self.addr,
+                reply_reason(reply)
+            ))),
+        }
+    }
+
+    /// Returns the credentials of a new connection: random ones if the streams are isolated
+    fn connection_credentials(&self) -> Option<ProxyCredentials> {
+        if !self.isolate_streams {
+            return self.credentials.clone();
+        }
+        let isolation = format!("{:016x}", rand::random::<u64>());
+        Some(match &self.credentials {
+            Some(credentials) => ProxyCredentials {
+                username: format!("{}-{isolation}", credentials.username),
+                password: credentials.password.clone(),
+            },
+            None => ProxyCredentials {
+                username: isolation.clone(),
+                password: isolation,
+            },
+        })
+    }
+
+    /// Connects to the proxy and authenticates
+    async fn open(&self) -> Result<TcpStream, ProxyError> {
+        let mut stream = TcpStream::connect(self.addr)
+            .await
+            .into_report()
+            .attach(HandshakeErrorKind::ProxyFailed)
+            .attach_printable_lazy(|| format!("Failed to connect to the proxy {}", self.addr))
+            .change_context(ProxyError)?;
+
+        let credentials = self.connection_credentials();
+        let greeting: &[u8] = match credentials {
+            Some(_) => &[SOCKS_VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+            None => &[SOCKS_VERSION, 1, METHOD_NO_AUTH],
+        };
+        write_all(&mut stream, greeting).await?;
+        let mut choice = [0u8; 2];
+        read_exact(&mut stream, &mut choice).await?;
+
+        match (choice, credentials) {
+            ([SOCKS_VERSION, METHOD_NO_AUTH], _) => Ok(stream),
+            ([SOCKS_VERSION, METHOD_USERNAME_PASSWORD], Some(credentials)) => {
+                authenticate(&mut stream, &credentials).await?;
+                Ok(stream)
+            }
+            ([SOCKS_VERSION, METHOD_NOT_ACCEPTABLE], _) => Err(Report::new(ProxyError)
+                .attach(HandshakeErrorKind::ProxyFailed)
+                .attach_printable(format!(
+                    "Proxy {} accepts none of the authentication methods",
+                    self.addr
+                ))),
+            _ => Err(Report::new(ProxyError)
+                .attach(HandshakeErrorKind::ProxyFailed)
+                .attach_printable(format!(
+                    "Proxy {} sent an invalid method selection {choice:?}",
+                    self.addr
+                ))),
+        }
+    }
+
+    /// Sends the `command` for the `target` and returns the reply code and the bound address of the reply
+    async fn request(
+        &self,
+        stream: &mut TcpStream,
+        command: u8,
+        target: &ProxyTarget,
+    ) -> Result<(u8, Option<SocketAddr>), ProxyError> {
+        let mut request = vec![SOCKS_VERSION, command, 0x00];
+        request.extend(encode_target(target)?);
+        write_all(stream, &request).await?;
+
+        let mut header = [0u8; 4];
+        read_exact(stream, &mut header).await?;
+        if header[0] != SOCKS_VERSION {
+            return Err(Report::new(ProxyError)
+                .attach(HandshakeErrorKind::ProxyFailed)
+                .attach_printable(format!(
+                    "Proxy {} sent an invalid reply {header:?}",
+                    self.addr
+                )));
+        }
+        let bound = read_address(stream, header[3]).await?;
+        Ok((header[1], bound))
+    }
+}
+
+/// Authenticates with the username and password method
+async fn authenticate(
+    stream: &mut TcpStream,
+    credentials: &ProxyCredentials,
+) -> Result<(), ProxyError> {
+    let (username, password) = (
+        credentials.username.as_bytes(),
+        credentials.password.as_bytes(),
+    );
+    if username.len() > 255 || password.len() > 255 {
+        return Err(Report::new(ProxyError)
+            .attach(HandshakeErrorKind::ProxyFailed)
+            .attach_printable("Proxy username and password must not be longer than 255 bytes"));
+    }
+    let mut request = vec![AUTH_VERSION, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    write_all(stream, &request).await?;
+
+    let mut status = [0u8; 2];
+    read_exact(stream, &mut status).await?;
+    if status[1] != AUTH_SUCCEEDED {
+        return Err(Report::new(ProxyError)
+            .attach(HandshakeErrorKind::ProxyFailed)
+            .attach_printable("Proxy rejected the username and password"));
+    }
+    Ok(())
+}
+
+/// Encodes the address type, the address and the port of the `target`
+pub(crate) fn encode_target(target: &ProxyTarget) -> Result<Vec<u8>, ProxyError> {
+    let mut encoded = Vec::new();
+    let port = match target {
+        ProxyTarget::Addr(SocketAddr::V4(addr)) => {
+            encoded.push(ATYP_IPV4);
+            encoded.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        ProxyTarget::Addr(SocketAddr::V6(addr)) => {
+            encoded.push(ATYP_IPV6);
+            encoded.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        ProxyTarget::Domain(name, port) => {
+            if name.is_empty() || name.len() > 255 {
+                return Err(Report::new(ProxyError)
+                    .attach(HandshakeErrorKind::ProxyFailed)
+                    .attach_printable(format!("Invalid length of the host name {name:?}")));
+            }
+            encoded.push(ATYP_DOMAIN);
+            encoded.push(name.len() as u8);
+            encoded.extend_from_slice(name.as_bytes());
+            *port
+        }
+    };
+    encoded.extend_from_slice(&port.to_be_bytes());
+    Ok(encoded)
+}
+
+/// Reads the address of the `address_type` and the port. A domain name is read but not returned.
+async fn read_address(
+    stream: &mut TcpStream,
+    address_type: u8,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    let ip = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            read_exact(stream, &mut octets).await?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            read_exact(stream, &mut octets).await?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        ATYP_DOMAIN => {
+            let mut length = [0u8; 1];
+            read_exact(stream, &mut length).await?;
+            let mut name = vec![0u8; length[0] as usize];
+            read_exact(stream, &mut name).await?;
+            None
+        }
+        _ => {
+            return Err(Report::new(ProxyError)
+                .attach(HandshakeErrorKind::ProxyFailed)
+                .attach_printable(format!("Proxy sent an unknown address type {address_type}")))
+        }
+    };
+    let mut port = [0u8; 2];
+    read_exact(stream, &mut port).await?;
+    Ok(ip.map(|ip| SocketAddr::new(ip, u16::from_be_bytes(port))))
+}
+
+/// Returns the description of a SOCKS5 reply code
+fn reply_reason(reply: u8) -> &'static str {
+    match reply {
+        REPLY_SUCCEEDED => "succeeded",
+        REPLY_GENERAL_FAILURE => "general failure",
+        REPLY_NOT_ALLOWED => "connection not allowed by ruleset",
+        REPLY_NETWORK_UNREACHABLE => "network unreachable",
+        REPLY_HOST_UNREACHABLE => "host unreachable",
+        REPLY_CONNECTION_REFUSED => "connection refused",
+        REPLY_TTL_EXPIRED => "TTL expired",
+        REPLY_COMMAND_NOT_SUPPORTED => "command not supported",
+        REPLY_ADDRESS_TYPE_NOT_SUPPORTED => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn write_all(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), ProxyError> {
+    stream
+        .write_all(bytes)
+        .await
+        .into_report()
+        .attach(HandshakeErrorKind::ProxyFailed)
+        .attach_printable("Failed to send to the proxy")
+        .change_context(ProxyError)
+}
+
+async fn read_exact(stream: &mut TcpStream, buffer: &mut [u8]) -> Result<(), ProxyError> {
+    stream
+        .read_exact(buffer)
+        .await
+        .into_report()
+        .attach(HandshakeErrorKind::ProxyFailed)
+        .attach_printable("Failed to receive from the proxy")
+        .change_context(ProxyError)
+        .map(|_| ())
+}
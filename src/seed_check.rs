@@ -0,0 +1,150 @@
+use log::info;
+use rand::seq::SliceRandom;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::{transport::report_reason, HandshakeManager, HandshakeOutcome, SeedLookup};
+
+/// Health of a DNS seed: the addresses it returned and the handshakes with a sample of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedHealth {
+    /// DNS seed as given in the settings
+    pub seed: String,
+    /// Duration of the seed lookup
+    pub lookup_latency: Duration,
+    /// Reason of the failed lookup, `None` if the seed answered
+    pub lookup_error: Option<String>,
+    /// Number of addresses returned by the seed
+    pub addresses: usize,
+    /// Number of IPv4 addresses returned by the seed
+    pub ipv4: usize,
+    /// Number of IPv6 addresses returned by the seed
+    pub ipv6: usize,
+    /// Number of addresses handshaked with
+    pub sampled: usize,
+    /// Number of sampled addresses that completed the handshake
+    pub reachable: usize,
+    /// Median duration of the completed handshakes
+    pub median_latency: Option<Duration>,
+    /// Number of completed handshakes by the protocol version of the peer
+    pub versions: BTreeMap<u32, usize>,
+}
+
+impl SeedHealth {
+    /// Returns the percentage of the sampled addresses that completed the handshake, `None` if none were sampled
+    pub fn reachable_percent(&self) -> Option<f64> {
+        (self.sampled > 0).then(|| self.reachable as f64 * 100.0 / self.sampled as f64)
+    }
+}
+
+/// SeedChecker - measures the health of DNS seeds.
+///
+/// For every seed lookup the checker counts the returned addresses, performs handshakes with a random sample
+/// of them and summarises the results. An address returned by several seeds is handshaked once.
+pub struct SeedChecker {
+    handshake_manager: HandshakeManager,
+    sample_size: usize,
+    concurrency: usize,
+}
+
+impl SeedChecker {
+    /// Construct a new SeedChecker that performs handshakes with up to `sample_size` addresses of every seed
+    pub fn new(
+        handshake_manager: HandshakeManager,
+        sample_size: usize,
+        concurrency: usize,
+    ) -> Self {
+        Self {
+            handshake_manager,
+            sample_size,
+            concurrency,
+        }
+    }
+
+    /// Returns the handshake manager with the statuses of all handshakes of the check
+    pub fn handshake_manager(&self) -> &HandshakeManager {
+        &self.handshake_manager
+    }
+
+    /// Checks the seeds of the `lookups`, see `DnsSeedManager::resolve_all`.
+    /// Returns the health of every seed in the order of the lookups.
+    pub async fn check(&mut self, lookups: &[SeedLookup]) -> Vec<SeedHealth> {
+        let mut rng = rand::thread_rng();
+        let samples: Vec<Vec<SocketAddr>> = lookups
+            .iter()
+            .map(|lookup| match &lookup.result {
+                Ok(nodes) => nodes
+                    .choose_multiple(&mut rng, self.sample_size)
+                    .copied()
+                    .collect(),
+                Err(_) => Vec::new(),
+            })
+            .collect();
+
+        let mut remotes: Vec<_> = samples.iter().flatten().copied().collect();
+        remotes.sort();
+        remotes.dedup();
+        info!(
+            "Handshake with {} sampled nodes, concurrency {}",
+            remotes.len(),
+            self.concurrency
+        );
+        let results: HashMap<_, _> = self
+            .handshake_manager
+            .establish_handshakes(remotes, self.concurrency)
+            .await
+            .into_iter()
+            .map(|(remote, result)| (remote, result.ok()))
+            .collect();
+
+        lookups
+            .iter()
+            .zip(samples)
+            .map(|(lookup, sample)| {
+                let outcomes: Vec<&HandshakeOutcome> = sample
+                    .iter()
+                    .filter_map(|remote| results.get(remote).and_then(Option::as_ref))
+                    .collect();
+                seed_health(lookup, sample.len(), &outcomes)
+            })
+            .collect()
+    }
+}
+
+/// Summarises the `lookup` of a seed and the `outcomes` of the completed handshakes with `sampled` of its addresses
+fn seed_health(lookup: &SeedLookup, sampled: usize, outcomes: &[&HandshakeOutcome]) -> SeedHealth {
+    let nodes = lookup.result.as_deref().unwrap_or_default();
+    let ipv4 = nodes.iter().filter(|node| node.is_ipv4()).count();
+
+    let mut latencies: Vec<_> = outcomes
+        .iter()
+        .map(|outcome| outcome.timings.total)
+        .collect();
+    latencies.sort();
+    let median_latency = match latencies.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(latencies[n / 2]),
+        n => Some((latencies[n / 2 - 1] + latencies[n / 2]) / 2),
+    };
+
+    let mut versions = BTreeMap::new();
+    for outcome in outcomes {
+        *versions.entry(outcome.peer.version).or_insert(0) += 1;
+    }
+
+    SeedHealth {
+        seed: lookup.seed.clone(),
+        lookup_latency: lookup.latency,
+        lookup_error: lookup.result.as_ref().err().map(report_reason),
+        addresses: nodes.len(),
+        ipv4,
+        ipv6: nodes.len() - ipv4,
+        sampled,
+        reachable: outcomes.len(),
+        median_latency,
+        versions,
+    }
+}
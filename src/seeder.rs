@@ -0,0 +1,301 @@
+use bitcoin::network::constants::ServiceFlags;
+use error_stack::{IntoReport, Result, ResultExt};
+use log::{debug, error};
+use rand::seq::SliceRandom;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{dns, HandshakeStatus, PeerAddr};
+
+/// Seeder Error - the DNS server of the seeder could not be started
+#[derive(Debug)]
+pub struct SeederError;
+
+impl fmt::Display for SeederError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Seeder error: failed to start the DNS seeder")
+    }
+}
+
+impl Error for SeederError {}
+
+/// Settings of a `DnsSeeder`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeederSettings {
+    /// Zone served by the seeder, e.g. `seed.example.org`
+    pub zone: String,
+    /// UDP address the DNS server listens on
+    pub bind: SocketAddr,
+    /// Only the nodes on this port are served, as DNS answers can not carry ports. Any port if `None`
+    pub port: Option<u16>,
+    /// Time to live of the answers in seconds
+    pub ttl: u32,
+    /// Maximum number of addresses in an answer
+    pub max_answers: usize,
+    /// How long a node is served after its last completed handshake
+    pub max_age: Duration,
+    /// Maximum number of nodes kept, the least recently verified ones make room for the new ones
+    pub max_nodes: usize,
+}
+
+impl SeederSettings {
+    /// Returns the settings of a seeder of the `zone` listening on `bind` that serves the nodes on any port
+    pub fn new(zone: &str, bind: SocketAddr) -> Self {
+        Self {
+            zone: zone.to_owned(),
+            bind,
+            port: None,
+            ttl: 60,
+            max_answers: 25,
+            max_age: Duration::from_secs(3600),
+            max_nodes: 10_000,
+        }
+    }
+}
+
+/// A node that completed the handshake
+#[derive(Debug, Clone, Copy)]
+struct VerifiedNode {
+    services: ServiceFlags,
+    verified_at: Instant,
+}
+
+/// Nodes served by a `DnsSeeder`
+#[derive(Debug)]
+struct SeederZone {
+    /// Normalised name of the zone
+    name: String,
+    settings: SeederSettings,
+    nodes: HashMap<SocketAddr, VerifiedNode>,
+}
+
+impl SeederZone {
+    /// Returns the nodes verified within the maximum age that advertise the `services`
+    fn good_nodes(&self, services: ServiceFlags) -> Vec<SocketAddr> {
+        self.nodes
+            .iter()
+            .filter(|(addr, node)| {
+                node.verified_at.elapsed() <= self.settings.max_age
+                    && node.services.has(services)
+                    && self.settings.port.is_none_or(|port| addr.port() == port)
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Keeps the verified `node` at the `addr`. When the zone is full, the nodes older than the maximum age
+    /// are dropped, then the least recently verified one if there is still no room.
+    fn insert(&mut self, addr: SocketAddr, node: VerifiedNode) {
+        if !self.nodes.contains_key(&addr) && self.nodes.len() >= self.settings.max_nodes {
+            let max_age = self.settings.max_age;
+            self.nodes
+                .retain(|_, node| node.verified_at.elapsed() <= max_age);
+            while self.nodes.len() >= self.settings.max_nodes.max(1) {
+                let Some(oldest) = self
+                    .nodes
+                    .iter()
+                    .min_by_key(|(_, node)| node.verified_at)
+                    .map(|(addr, _)| *addr)
+                else {
+                    break;
+                };
+                self.nodes.remove(&oldest);
+            }
+        }
+        self.nodes.insert(addr, node);
+    }
+
+    /// Builds the response to the `question`: a random choice of the good nodes for the zone itself,
+    /// of the good nodes with the given services for `x<hex flags>.<zone>`, NXDOMAIN for other names of the zone.
+    /// Names outside of the zone are refused.
+    fn answer(&self, question: &dns::DnsQuestion) -> Vec<u8> {
+        let Some(label) = zone_label(&question.name, &self.name) else {
+            return dns::encode_response(question, dns::RCODE_REFUSED, &[], 0);
+        };
+        let services = match label {
+            "" => ServiceFlags::NONE,
+            label => match parse_filter(label) {
+                Some(services) => services,
+                None => {
+                    return dns::encode_response(
+                        question,
+                        dns::RCODE_NAME_ERROR,
+                        &[],
+                        self.settings.ttl,
+                    )
+                }
+            },
+        };
+
+        let mut nodes: Vec<_> = self
+            .good_nodes(services)
+            .into_iter()
+            .filter(|node| match question.qtype {
+                dns::TYPE_A => node.is_ipv4(),
+                dns::TYPE_AAAA => node.is_ipv6(),
+                _ => false,
+            })
+            .map(|node| node.ip())
+            .collect();
+        nodes.shuffle(&mut rand::thread_rng());
+        nodes.truncate(self.settings.max_answers);
+        dns::encode_response(question, dns::RCODE_NO_ERROR, &nodes, self.settings.ttl)
+    }
+}
+
+/// DnsSeeder - a DNS seed server backed by the nodes that recently completed the handshake.
+///
+/// Answers the A and AAAA queries for the zone with the verified nodes, and the queries
+/// for the `x<hex flags>.<zone>` names with the verified nodes that advertise the given services.
+/// The nodes are fed with the handshake statuses, see `DnsSeeder::record_statuses`. The server stops when dropped.
+pub struct DnsSeeder {
+    addr: SocketAddr,
+    zone: Arc<Mutex<SeederZone>>,
+    handle: JoinHandle<()>,
+}
+
+impl DnsSeeder {
+    /// Starts the DNS server of the seeder on the address of the `settings`
+    pub async fn start(settings: SeederSettings) -> Result<Self, SeederError> {
+        let socket = UdpSocket::bind(settings.bind)
+            .await
+            .into_report()
+            .attach_printable_lazy(|| {
+                format!("Failed to bind seeder DNS server to {}", settings.bind)
+            })
+            .change_context(SeederError)?;
+        let addr = socket
+            .local_addr()
+            .into_report()
+            .attach_printable("Failed to return seeder DNS server address")
+            .change_context(SeederError)?;
+        let zone = Arc::new(Mutex::new(SeederZone {
+            name: dns::normalise_name(&settings.zone),
+            settings,
+            nodes: HashMap::new(),
+        }));
+
+        let server_zone = Arc::clone(&zone);
+        let handle = tokio::spawn(async move {
+            let mut buffer = [0u8; dns::MAX_UDP_MESSAGE];
+            loop {
+                let (length, remote) = match socket.recv_from(&mut buffer).await {
+                    Ok(received) => received,
+                    Err(e) if is_transient(&e) => {
+                        debug!("Seeder failed to receive a query: {e}");
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Seeder DNS server stops, its socket failed: {e}");
+                        return;
+                    }
+                };
+                let Some(question) = dns::decode_query(&buffer[..length]) else {
+                    continue;
+                };
+                let response = {
+                    let Ok(zone) = server_zone.lock() else {
+                        return;
+                    };
+                    zone.answer(&question)
+                };
+                debug!(
+                    "Seeder answers {:?} type {} to {remote}",
+                    question.name, question.qtype
+                );
+                let _ = socket.send_to(&response, remote).await;
+            }
+        });
+
+        Ok(Self { addr, zone, handle })
+    }
+
+    /// Returns the address the DNS server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Records the result of a handshake with the `remote` peer: a completed handshake makes it a good node,
+    /// a failed one stops serving it
+    pub fn record_status(&self, remote: SocketAddr, status: &HandshakeStatus) {
+        let Ok(mut zone) = self.zone.lock() else {
+            return;
+        };
+        match status {
+            HandshakeStatus::Completed(outcome) => {
+                let node = VerifiedNode {
+                    services: outcome.peer.services,
+                    verified_at: Instant::now(),
+                };
+                zone.insert(remote, node);
+            }
+            HandshakeStatus::Failed(_) => {
+                zone.nodes.remove(&remote);
+            }
+        }
+    }
+
+    /// Records the results of the handshakes with all IP peers of the `statuses`
+    pub fn record_statuses(&self, statuses: &HashMap<PeerAddr, HandshakeStatus>) {
+        for (remote, status) in statuses.iter() {
+            if let Some(remote) = remote.socket_addr() {
+                self.record_status(remote, status);
+            }
+        }
+    }
+
+    /// Returns the nodes currently served for the zone, ordered by address
+    pub fn good_nodes(&self) -> Vec<SocketAddr> {
+        let mut nodes = self
+            .zone
+            .lock()
+            .map(|zone| zone.good_nodes(ServiceFlags::NONE))
+            .unwrap_or_default();
+        nodes.sort();
+        nodes
+    }
+}
+
+impl Drop for DnsSeeder {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Returns `true` for the errors of a single datagram that leave the socket usable, e.g. the ICMP port unreachable
+/// reported when a client is gone before its answer arrived
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Returns the label in front of the `zone` in the `name`, an empty one for the zone itself,
+/// `None` if the name is outside of the zone
+fn zone_label<'a>(name: &'a str, zone: &str) -> Option<&'a str> {
+    if name == zone {
+        return Some("");
+    }
+    name.strip_suffix(zone)?.strip_suffix('.')
+}
+
+/// Converts an `x<hex flags>` label into the requested services
+fn parse_filter(label: &str) -> Option<ServiceFlags> {
+    let hex = label.strip_prefix('x')?;
+    if hex.is_empty() || hex.len() > 16 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok().map(ServiceFlags::from)
+}
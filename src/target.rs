@@ -0,0 +1,223 @@
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use std::{
+    error::Error,
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{PeerAddr, ProxySettings};
+
+/// Target Error - the target is malformed or could not be resolved
+#[derive(Debug)]
+pub struct TargetError;
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Target error: invalid or unresolvable target")
+    }
+}
+
+impl Error for TargetError {}
+
+/// Host of a target
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetHost {
+    /// IPv4 or IPv6 address
+    Ip(IpAddr),
+    /// Host name resolved with the system resolver, or by the proxy when connecting
+    Name(String),
+    /// Tor onion service v3 public key, reachable only through the proxy
+    TorV3([u8; 32]),
+    /// I2P destination hash, reachable only through the proxy
+    I2p([u8; 32]),
+}
+
+/// Node to perform a handshake with, given as `host[:port]`.
+///
+/// Accepted forms are `1.2.3.4`, `1.2.3.4:8333`, `2001:db8::1`, `[2001:db8::1]`, `[2001:db8::1]:8333`,
+/// `node.example.org`, `node.example.org:8333`, `<base32>.onion[:port]` (Tor v3) and `<base32>.b32.i2p[:port]`.
+/// A target without port uses the default port of the network.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub host: TargetHost,
+    pub port: Option<u16>,
+}
+
+impl Target {
+    /// Returns the socket address of an IP target, `None` for a host name, Tor or I2P target
+    pub fn socket_addr(&self, default_port: u16) -> Option<SocketAddr> {
+        match &self.host {
+            TargetHost::Ip(ip) => Some(SocketAddr::new(*ip, self.port.unwrap_or(default_port))),
+            TargetHost::Name(_) | TargetHost::TorV3(_) | TargetHost::I2p(_) => None,
+        }
+    }
+
+    /// Returns the peer address of an IP, Tor or I2P target, `None` for a host name
+    pub fn peer_addr(&self, default_port: u16) -> Option<PeerAddr> {
+        let port = self.port.unwrap_or(default_port);
+        match &self.host {
+            TargetHost::Ip(ip) => Some(PeerAddr::Ip(SocketAddr::new(*ip, port))),
+            TargetHost::Name(_) => None,
+            TargetHost::TorV3(pubkey) => Some(PeerAddr::TorV3 {
+                pubkey: *pubkey,
+                port,
+            }),
+            TargetHost::I2p(hash) => Some(PeerAddr::I2p { hash: *hash, port }),
+        }
+    }
+
+    /// Resolves the target into a socket address, the first one returned by the system resolver for a host name.
+    /// Tor and I2P targets have no socket address and are never passed to the resolver.
+    pub async fn resolve(&self, default_port: u16) -> Result<SocketAddr, TargetError> {
+        let port = self.port.unwrap_or(default_port);
+        match &self.host {
+            TargetHost::Ip(ip) => Ok(SocketAddr::new(*ip, port)),
+            TargetHost::TorV3(_) | TargetHost::I2p(_) => Err(Report::new(TargetError)
+                .attach_printable(format!(
+                    "Target {self} has no IP address, it is reachable only through a proxy"
+                ))),
+            TargetHost::Name(name) => tokio::net::lookup_host((name.as_str(), port))
+                .await
+                .into_report()
+                .attach_printable_lazy(|| format!("Failed to resolve host {name:?}"))
+                .change_context(TargetError)?
+                .next()
+                .ok_or_else(|| {
+                    Report::new(TargetError)
+                        .attach_printable(format!("Host {name:?} has no addresses"))
+                }),
+        }
+    }
+
+    /// Resolves the target into the address of the peer to connect to.
+    /// With a `proxy`, a host name is not resolved locally: it is sent to the proxy in the `CONNECT` request,
+    /// so that the proxy resolves it. Tor and I2P targets are connected to by name too, which requires a proxy.
+    pub async fn resolve_peer(
+        &self,
+        default_port: u16,
+        proxy: Option<&ProxySettings>,
+    ) -> Result<PeerAddr, TargetError> {
+        if let (TargetHost::Name(host), Some(_)) = (&self.host, proxy) {
+            return Ok(PeerAddr::Name {
+                host: host.clone(),
+                port: self.port.unwrap_or(default_port),
+            });
+        }
+        match (self.peer_addr(default_port), proxy) {
+            (Some(PeerAddr::Ip(addr)), _) => Ok(PeerAddr::Ip(addr)),
+            (Some(peer), Some(_)) => Ok(peer),
+            (Some(_), None) => Err(Report::new(TargetError).attach_printable(format!(
+                "Target {self} is reachable only through a proxy, see --proxy"
+            ))),
+            (None, _) => self.resolve(default_port).await.map(PeerAddr::from),
+        }
+    }
+
+    /// Reads the targets from a file with one target per line. Empty lines and lines starting with `#` are skipped.
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Vec<Target>, TargetError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .into_report()
+            .attach_printable_lazy(|| format!("Failed to read targets file {}", path.display()))
+            .change_context(TargetError)?;
+
+        content
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| {
+                line.parse()
+                    .attach_printable_lazy(|| format!("at {}:{}", path.display(), i + 1))
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Target {
+    type Err = Report<TargetError>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let ip_target = |ip, port| Target {
+            host: TargetHost::Ip(ip),
+            port,
+        };
+
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(ip_target(addr.ip(), Some(addr.port())));
+        }
+        // A bare IPv4 or IPv6 address, IPv6 may be in brackets
+        let unbracketed = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+        if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+            return Ok(ip_target(ip, None));
+        }
+
+        let (name, port) = match s.rsplit_once(':') {
+            Some((name, port)) => {
+                let port = port
+                    .parse()
+                    .into_report()
+                    .change_context(TargetError)
+                    .attach_printable_lazy(|| format!("Invalid port in target {s:?}"))?;
+                (name, Some(port))
+            }
+            None => (s, None),
+        };
+        let lowercase = name.to_ascii_lowercase();
+        if lowercase.ends_with(".onion") || lowercase.ends_with(".i2p") {
+            let host = match PeerAddr::from_overlay_host(name, 0) {
+                Some(PeerAddr::TorV3 { pubkey, .. }) => TargetHost::TorV3(pubkey),
+                Some(PeerAddr::I2p { hash, .. }) => TargetHost::I2p(hash),
+                _ => {
+                    return Err(Report::new(TargetError).attach_printable(format!(
+                        "Invalid target {s:?}, expected a Tor v3 .onion or an I2P .b32.i2p address"
+                    )))
+                }
+            };
+            return Ok(Target { host, port });
+        }
+
+        let is_valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+        if !is_valid_name {
+            return Err(Report::new(TargetError).attach_printable(format!(
+                "Invalid target {s:?}, expected host[:port], IPv4 or IPv6 address"
+            )));
+        }
+
+        Ok(Target {
+            host: TargetHost::Name(name.to_owned()),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.host, self.port) {
+            (TargetHost::Ip(IpAddr::V6(ip)), Some(port)) => write!(f, "[{ip}]:{port}"),
+            (TargetHost::Ip(ip), Some(port)) => write!(f, "{ip}:{port}"),
+            (TargetHost::Ip(ip), None) => write!(f, "{ip}"),
+            (TargetHost::Name(name), Some(port)) => write!(f, "{name}:{port}"),
+            (TargetHost::Name(name), None) => write!(f, "{name}"),
+            (TargetHost::TorV3(_) | TargetHost::I2p(_), _) => {
+                // The port is not part of the host name
+                let peer = self
+                    .peer_addr(0)
+                    .map(|peer| peer.host())
+                    .unwrap_or_default();
+                match self.port {
+                    Some(port) => write!(f, "{peer}:{port}"),
+                    None => write!(f, "{peer}"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use bitcoin::{
+    consensus::encode,
+    network::message::{RawNetworkMessage, MAX_MSG_SIZE},
+    Network,
+};
+use error_stack::{Context, IntoReport, Report, Result, ResultExt};
+use std::{error::Error, fmt, io, net::SocketAddr};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+use crate::{
+    handshake_manager::HandshakeErrorKind,
+    handshake_outcome::TransportVersion,
+    v2_transport::{self, PacketDecryptor, PacketEncryptor, V1_PREFIX_SIZE},
+};
+
+/// Size of the message header: magic (4), command (12), payload length (4) and checksum (4).
+const MESSAGE_HEADER_SIZE: usize = 24;
+
+/// Offset of the little-endian payload length within the message header.
+const MESSAGE_HEADER_LENGTH_OFFSET: usize = 16;
+
+/// Transport Error - failed to read or write a framed network message
+#[derive(Debug)]
+pub struct TransportError;
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transport error: failed to exchange network message")
+    }
+}
+
+impl Error for TransportError {}
+
+/// Writes an already serialised network message into the `writer` and flushes it.
+pub async fn write_message<W>(writer: &mut W, message_bytes: &[u8]) -> Result<(), TransportError>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer
+        .write_all(message_bytes)
+        .await
+        .map_err(io_report)
+        .attach_printable("Failed to write message bytes")
+        .change_context(TransportError)?;
+
+    writer
+        .flush()
+        .await
+        .map_err(io_report)
+        .attach_printable("Failed to flush message bytes")
+        .change_context(TransportError)
+}
+
+/// Reads a single framed network message from the `reader`.
+///
+/// The header is read first to check the network `magic` and learn the payload length,
+/// then exactly that many payload bytes are read and the whole frame is decoded as `RawNetworkMessage`.
+pub async fn read_message<R>(
+    reader: &mut R,
+    magic: u32,
+) -> Result<RawNetworkMessage, TransportError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut frame = vec![0u8; MESSAGE_HEADER_SIZE];
+    reader
+        .read_exact(&mut frame)
+        .await
+        .map_err(io_report)
+        .attach_printable("Failed to read message header")
+        .change_context(TransportError)?;
+
+    let mut magic_bytes = [0u8; 4];
+    magic_bytes.copy_from_slice(&frame[..4]);
+    let magic_remote = u32::from_le_bytes(magic_bytes);
+    if magic_remote != magic {
+        return Err(Report::new(TransportError)
+            .attach(HandshakeErrorKind::BadMagic)
+            .attach_printable(format!(
+                "Unexpected network magic: expected {magic:#010x}, received {magic_remote:#010x}"
+            )));
+    }
+
+    let mut length_bytes = [0u8; 4];
+    length_bytes
+        .copy_from_slice(&frame[MESSAGE_HEADER_LENGTH_OFFSET..MESSAGE_HEADER_LENGTH_OFFSET + 4]);
+    let payload_length = u32::from_le_bytes(length_bytes) as usize;
+    if payload_length > MAX_MSG_SIZE {
+        return Err(Report::new(TransportError)
+            .attach(HandshakeErrorKind::MalformedMessage)
+            .attach_printable(format!(
+                "Message payload length {payload_length} exceeds the limit of {MAX_MSG_SIZE} bytes"
+            )));
+    }
+
+    frame.resize(MESSAGE_HEADER_SIZE + payload_length, 0);
+    reader
+        .read_exact(&mut frame[MESSAGE_HEADER_SIZE..])
+        .await
+        .map_err(io_report)
+        .attach_printable_lazy(|| {
+            format!("Failed to read message payload of {payload_length} bytes")
+        })
+        .change_context(TransportError)?;
+
+    decode_frame(&frame)
+}
+
+/// Decodes a whole frame of a network message
+fn decode_frame(frame: &[u8]) -> Result<RawNetworkMessage, TransportError> {
+    encode::deserialize(frame)
+        .map_err(|e| {
+            let kind = match e {
+                encode::Error::InvalidChecksum { .. } => HandshakeErrorKind::ChecksumMismatch,
+                _ => HandshakeErrorKind::MalformedMessage,
+            };
+            Report::new(e).attach(kind)
+        })
+        .attach_printable("Failed to decode network message")
+        .change_context(TransportError)
+}
+
+/// Reads the network messages received on a connection, decrypting them if the connection uses the v2 transport
+pub(crate) struct MessageReader<R> {
+    reader: R,
+    /// Bytes of the first v1 message read to detect the transport of an inbound connection
+    prefix: Vec<u8>,
+    decryptor: Option<PacketDecryptor>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    /// Reads the next network message, skipping the decoy packets of the v2 transport and the messages
+    /// with a short ID that is not known yet
+    pub(crate) async fn read_message(
+        &mut self,
+        magic: u32,
+    ) -> Result<RawNetworkMessage, TransportError> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            let prefix = std::mem::take(&mut self.prefix);
+            return read_message(&mut prefix.as_slice().chain(&mut self.reader), magic).await;
+        };
+        loop {
+            let (contents, decoy) = decryptor.read_packet(&mut self.reader, &[]).await?;
+            if decoy {
+                continue;
+            }
+            if let Some(message_bytes) = v2_transport::decode_contents(&contents, magic)? {
+                return decode_frame(&message_bytes);
+            }
+        }
+    }
+}
+
+/// Writes the network messages sent on a connection, encrypting them if the connection uses the v2 transport
+pub(crate) struct MessageWriter<W> {
+    writer: W,
+    encryptor: Option<PacketEncryptor>,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    /// Writes an already serialised network message and flushes it
+    pub(crate) async fn write_message(
+        &mut self,
+        message_bytes: &[u8],
+    ) -> Result<(), TransportError> {
+        match self.encryptor.as_mut() {
+            Some(encryptor) => {
+                let packet = encryptor.encrypt(
+                    &v2_transport::encode_contents(message_bytes)?,
+                    &[],
+                    false,
+                )?;
+                write_message(&mut self.writer, &packet).await
+            }
+            None => write_message(&mut self.writer, message_bytes).await,
+        }
+    }
+
+    /// Writes a v2 packet with the raw `contents`, that are not necessarily a known message
+    pub(crate) async fn write_contents(&mut self, contents: &[u8]) -> Result<(), TransportError> {
+        let Some(encryptor) = self.encryptor.as_mut() else {
+            return Err(Report::new(TransportError)
+                .attach_printable("Packet contents can only be written over the v2 transport"));
+        };
+        let packet = encryptor.encrypt(contents, &[], false)?;
+        write_message(&mut self.writer, &packet).await
+    }
+
+    /// Shuts down the write half of the connection
+    pub(crate) async fn shutdown(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+}
+
+/// TCP connection with a remote peer that exchanges network messages over the v1 or the v2 transport
+pub(crate) struct Connection {
+    transport: TransportVersion,
+    reader: MessageReader<OwnedReadHalf>,
+    writer: MessageWriter<OwnedWriteHalf>,
+}
+
+impl Connection {
+    /// Uses the plaintext v1 transport on the `stream`
+    pub(crate) fn v1(stream: TcpStream) -> Self {
+        Self::new(stream, TransportVersion::V1, Vec::new(), None)
+    }
+
+    /// Performs the v2 handshake as the initiator of the connection on the `stream`.
+    /// A remote peer that closes the connection instead is reported with `V2Unsupported` attached,
+    /// the connection may be opened again with the v1 transport.
+    pub(crate) async fn initiate_v2(
+        mut stream: TcpStream,
+        network: Network,
+    ) -> Result<Self, TransportError> {
+        let (encryptor, decryptor) = v2_transport::initiate(&mut stream, network).await?;
+        Ok(Self::new(
+            stream,
+            TransportVersion::V2,
+            Vec::new(),
+            Some((encryptor, decryptor)),
+        ))
+    }
+
+    /// Detects the transport of the inbound connection on the `stream` from its first bytes:
+    /// a v1 peer sends the magic and the `version` command first, anything else is the key of a v2 peer.
+    /// Only the v1 transport is detected if `v2` is `false`.
+    pub(crate) async fn accept(
+        mut stream: TcpStream,
+        network: Network,
+        v2: bool,
+    ) -> Result<Self, TransportError> {
+        if !v2 {
+            return Ok(Self::v1(stream));
+        }
+        let mut prefix = vec![0u8; V1_PREFIX_SIZE];
+        stream
+            .read_exact(&mut prefix)
+            .await
+            .map_err(io_report)
+            .attach_printable("Failed to read the first bytes of the connection")
+            .change_context(TransportError)?;
+        if prefix == v2_transport::v1_prefix(network) {
+            return Ok(Self::new(stream, TransportVersion::V1, prefix, None));
+        }
+        let (encryptor, decryptor) = v2_transport::respond(&mut stream, network, &prefix).await?;
+        Ok(Self::new(
+            stream,
+            TransportVersion::V2,
+            Vec::new(),
+            Some((encryptor, decryptor)),
+        ))
+    }
+
+    fn new(
+        stream: TcpStream,
+        transport: TransportVersion,
+        prefix: Vec<u8>,
+        ciphers: Option<(PacketEncryptor, PacketDecryptor)>,
+    ) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (encryptor, decryptor) = ciphers.unzip();
+        Self {
+            transport,
+            reader: MessageReader {
+                reader,
+                prefix,
+                decryptor,
+            },
+            writer: MessageWriter { writer, encryptor },
+        }
+    }
+
+    /// Returns the transport of the connection
+    pub(crate) fn transport(&self) -> TransportVersion {
+        self.transport
+    }
+
+    /// Reads the next network message with the network `magic`
+    pub(crate) async fn read_message(
+        &mut self,
+        magic: u32,
+    ) -> Result<RawNetworkMessage, TransportError> {
+        self.reader.read_message(magic).await
+    }
+
+    /// Writes an already serialised network message and flushes it
+    pub(crate) async fn write_message(
+        &mut self,
+        message_bytes: &[u8],
+    ) -> Result<(), TransportError> {
+        self.writer.write_message(message_bytes).await
+    }
+
+    /// Writes a v2 packet with the raw `contents`, fails on the v1 transport
+    pub(crate) async fn write_contents(&mut self, contents: &[u8]) -> Result<(), TransportError> {
+        self.writer.write_contents(contents).await
+    }
+
+    /// Shuts down the write half of the connection
+    pub(crate) async fn shutdown(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+
+    /// Returns the local and the remote halves of the TCP connection
+    pub(crate) fn addrs(&self) -> Result<(SocketAddr, SocketAddr), io::Error> {
+        let local = self.reader.reader.local_addr().into_report()?;
+        let remote = self.reader.reader.peer_addr().into_report()?;
+        Ok((local, remote))
+    }
+
+    /// Splits the connection into its reader and writer
+    pub(crate) fn into_split(
+        self,
+    ) -> (MessageReader<OwnedReadHalf>, MessageWriter<OwnedWriteHalf>) {
+        (self.reader, self.writer)
+    }
+}
+
+/// Converts an I/O `error` into a report with the matching `HandshakeErrorKind` attached
+pub(crate) fn io_report(error: io::Error) -> Report<io::Error> {
+    let kind = match error.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => HandshakeErrorKind::PeerDisconnected,
+        _ => HandshakeErrorKind::Io,
+    };
+    Report::new(error).attach(kind)
+}
+
+/// Returns the reason of a failure: the first printable attachment of the `report`, or the report itself
+pub(crate) fn report_reason<C: Context>(report: &Report<C>) -> String {
+    report
+        .frames()
+        .find_map(|frame| {
+            frame.downcast_ref::<String>().cloned().or_else(|| {
+                frame
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+            })
+        })
+        .unwrap_or_else(|| report.to_string())
+}
@@ -0,0 +1,413 @@
+//! The encrypted v2 transport of [BIP324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki).
+//!
+//! The peers exchange ElligatorSwift encoded ephemeral keys followed by random garbage, derive the session keys
+//! from the x-only ECDH secret, and authenticate the garbage with the first packet sent after the garbage terminator.
+//! Every message is then sent as a packet: the encrypted 3 byte length of the contents, followed by the
+//! ChaCha20-Poly1305 encrypted header byte and contents. The contents are the 1 byte short ID of the message,
+//! or a zero byte and the 12 byte command, followed by the payload.
+use bitcoin::{
+    hashes::{sha256, sha256d, Hash, HashEngine, Hmac, HmacEngine},
+    network::message::MAX_MSG_SIZE,
+    Network,
+};
+use error_stack::{Report, Result, ResultExt};
+use log::debug;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::{
+    ellswift::{EllSwiftKey, ENCODING_SIZE},
+    fschacha20::{FSChaCha20, FSChaCha20Poly1305, KEY_SIZE, TAG_SIZE},
+    handshake_manager::HandshakeErrorKind,
+    transport::{self, TransportError},
+};
+
+/// Size of the garbage terminator
+const TERMINATOR_SIZE: usize = 16;
+/// Maximum size of the garbage sent after the key
+const MAX_GARBAGE_SIZE: usize = 4095;
+/// Size of the encrypted contents length
+const LENGTH_SIZE: usize = 3;
+/// Size of the header byte of a packet
+const HEADER_SIZE: usize = 1;
+/// Header bit of the decoy packets, ignored by the receiver
+const IGNORE_BIT: u8 = 0x80;
+/// Size of the command of the messages without a short ID
+const COMMAND_SIZE: usize = 12;
+/// Size of the v1 message header: magic (4), command (12), payload length (4) and checksum (4)
+const V1_HEADER_SIZE: usize = 24;
+/// Size of the prefix of the v1 `version` message that tells v1 and v2 connections apart: magic and command
+pub(crate) const V1_PREFIX_SIZE: usize = 16;
+
+/// Commands of the short message IDs 1 to 28
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+/// Attached to the report of a v2 handshake that the remote peer closed before sending its key,
+/// as the peers that only support the v1 transport do
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct V2Unsupported;
+
+/// Returns the first bytes of the v1 `version` message on the `network`: the magic and the `version` command
+pub(crate) fn v1_prefix(network: Network) -> [u8; V1_PREFIX_SIZE] {
+    let mut prefix = [0u8; V1_PREFIX_SIZE];
+    prefix[..4].copy_from_slice(&network.magic().to_le_bytes());
+    prefix[4..11].copy_from_slice(b"version");
+    prefix
+}
+
+/// Cipher of the packets sent to the remote peer
+pub(crate) struct PacketEncryptor {
+    length: FSChaCha20,
+    packet: FSChaCha20Poly1305,
+}
+
+impl PacketEncryptor {
+    /// Encrypts the `contents` into a packet that authenticates the `aad`. The remote peer ignores decoy packets.
+    pub(crate) fn encrypt(
+        &mut self,
+        contents: &[u8],
+        aad: &[u8],
+        decoy: bool,
+    ) -> Result<Vec<u8>, TransportError> {
+        let mut packet = (contents.len() as u32).to_le_bytes()[..LENGTH_SIZE].to_vec();
+        self.length.crypt(&mut packet);
+
+        let mut plaintext = Vec::with_capacity(HEADER_SIZE + contents.len());
+        plaintext.push(if decoy { IGNORE_BIT } else { 0 });
+        plaintext.extend_from_slice(contents);
+        let ciphertext = self.packet.encrypt(aad, &plaintext).ok_or_else(|| {
+            Report::new(TransportError).attach_printable(format!(
+                "Packet contents of {} bytes are too large to encrypt",
+                contents.len()
+            ))
+        })?;
+        packet.extend(ciphertext);
+        Ok(packet)
+    }
+}
+
+/// Cipher of the packets received from the remote peer
+pub(crate) struct PacketDecryptor {
+    length: FSChaCha20,
+    packet: FSChaCha20Poly1305,
+}
+
+impl PacketDecryptor {
+    /// Reads and decrypts the next packet that authenticates the `aad`.
+    /// Returns the contents and whether it is a decoy packet.
+    pub(crate) async fn read_packet<R>(
+        &mut self,
+        reader: &mut R,
+        aad: &[u8],
+    ) -> Result<(Vec<u8>, bool), TransportError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut length = [0u8; 4];
+        read_exact(reader, &mut length[..LENGTH_SIZE], "packet length").await?;
+        self.length.crypt(&mut length[..LENGTH_SIZE]);
+        let length = u32::from_le_bytes(length) as usize;
+        // The largest contents are a message with the full command
+        if length > HEADER_SIZE + COMMAND_SIZE + MAX_MSG_SIZE {
+            return Err(Report::new(TransportError)
+                .attach(HandshakeErrorKind::MalformedMessage)
+                .attach_printable(format!(
+                    "Packet length {length} exceeds the limit of {MAX_MSG_SIZE} bytes"
+                )));
+        }
+
+        let mut ciphertext = vec![0u8; HEADER_SIZE + length + TAG_SIZE];
+        read_exact(reader, &mut ciphertext, "packet").await?;
+        let plaintext = self.packet.decrypt(aad, &ciphertext).ok_or_else(|| {
+            Report::new(TransportError)
+                .attach(HandshakeErrorKind::MalformedMessage)
+                .attach_printable("Failed to authenticate the packet")
+        })?;
+        Ok((
+            plaintext[HEADER_SIZE..].to_vec(),
+            plaintext[0] & IGNORE_BIT != 0,
+        ))
+    }
+}
+
+/// Converts a serialised v1 message into the contents of a v2 packet
+pub(crate) fn encode_contents(message_bytes: &[u8]) -> Result<Vec<u8>, TransportError> {
+    if message_bytes.len() < V1_HEADER_SIZE {
+        return Err(
+            Report::new(TransportError).attach_printable("Message is shorter than its header")
+        );
+    }
+    let command = &message_bytes[4..4 + COMMAND_SIZE];
+    let name: Vec<u8> = command
+        .iter()
+        .copied()
+        .take_while(|byte| *byte != 0)
+        .collect();
+    let payload = &message_bytes[V1_HEADER_SIZE..];
+
+    let mut contents = Vec::with_capacity(1 + COMMAND_SIZE + payload.len());
+    match SHORT_IDS.iter().position(|id| id.as_bytes() == name) {
+        Some(index) => contents.push(index as u8 + 1),
+        None => {
+            contents.push(0);
+            contents.extend_from_slice(command);
+        }
+    }
+    contents.extend_from_slice(payload);
+    Ok(contents)
+}
+
+/// Converts the contents of a v2 packet into a serialised v1 message with the network `magic`.
+/// Returns `None` for a short message ID that is not known yet, such messages are ignored like the decoy packets.
+pub(crate) fn decode_contents(
+    contents: &[u8],
+    magic: u32,
+) -> Result<Option<Vec<u8>>, TransportError> {
+    let (command, payload) = match contents.split_first() {
+        Some((0, rest)) if rest.len() >= COMMAND_SIZE => {
+            let (command, payload) = rest.split_at(COMMAND_SIZE);
+            (command.to_vec(), payload)
+        }
+        Some((&id, payload)) if id != 0 => {
+            let Some(name) = SHORT_IDS.get(id as usize - 1) else {
+                debug!("Ignore message with unknown short ID {id}");
+                return Ok(None);
+            };
+            let mut command = name.as_bytes().to_vec();
+            command.resize(COMMAND_SIZE, 0);
+            (command, payload)
+        }
+        _ => {
+            return Err(Report::new(TransportError)
+                .attach(HandshakeErrorKind::MalformedMessage)
+                .attach_printable("Packet contents are too short for a message"))
+        }
+    };
+
+    let mut message_bytes = Vec::with_capacity(V1_HEADER_SIZE + payload.len());
+    message_bytes.extend_from_slice(&magic.to_le_bytes());
+    message_bytes.extend_from_slice(&command);
+    message_bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message_bytes.extend_from_slice(&sha256d::Hash::hash(payload)[..4]);
+    message_bytes.extend_from_slice(payload);
+    Ok(Some(message_bytes))
+}
+
+/// Performs the v2 handshake as the initiator of the connection: sends the key, waits for the key of the remote
+/// peer, then exchanges the garbage terminators and the transport version packets.
+/// A remote peer that closes the connection before sending its key is reported with `V2Unsupported` attached.
+pub(crate) async fn initiate<S>(
+    stream: &mut S,
+    network: Network,
+) -> Result<(PacketEncryptor, PacketDecryptor), TransportError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = EllSwiftKey::generate();
+    let garbage = random_garbage();
+    let mut bytes = key.encoding().to_vec();
+    bytes.extend_from_slice(&garbage);
+    transport::write_message(stream, &bytes)
+        .await
+        .attach_printable("Failed to send the v2 key")?;
+
+    let mut remote_key = [0u8; ENCODING_SIZE];
+    read_exact(stream, &mut remote_key, "v2 key")
+        .await
+        .attach(V2Unsupported)?;
+    let mut session = Session::new(&key, &remote_key, network, true);
+    transport::write_message(stream, &session.terminator_and_version(&garbage)?).await?;
+    session.recv_garbage_and_version(stream).await
+}
+
+/// Performs the v2 handshake as the responder of the connection, when the `received` bytes
+/// are the beginning of the key of the remote peer rather than a v1 message
+pub(crate) async fn respond<S>(
+    stream: &mut S,
+    network: Network,
+    received: &[u8],
+) -> Result<(PacketEncryptor, PacketDecryptor), TransportError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut remote_key = [0u8; ENCODING_SIZE];
+    remote_key[..received.len()].copy_from_slice(received);
+    read_exact(stream, &mut remote_key[received.len()..], "v2 key").await?;
+
+    let key = EllSwiftKey::generate();
+    let garbage = random_garbage();
+    let mut session = Session::new(&key, &remote_key, network, false);
+    let mut bytes = key.encoding().to_vec();
+    bytes.extend_from_slice(&garbage);
+    bytes.extend(session.terminator_and_version(&garbage)?);
+    transport::write_message(stream, &bytes).await?;
+    session.recv_garbage_and_version(stream).await
+}
+
+/// State of a v2 handshake after the keys were exchanged
+struct Session {
+    encryptor: PacketEncryptor,
+    decryptor: PacketDecryptor,
+    send_terminator: [u8; TERMINATOR_SIZE],
+    recv_terminator: [u8; TERMINATOR_SIZE],
+}
+
+impl Session {
+    /// Derives the session keys from the ECDH secret of the local `key` and the `remote_key`
+    fn new(
+        key: &EllSwiftKey,
+        remote_key: &[u8; ENCODING_SIZE],
+        network: Network,
+        initiator: bool,
+    ) -> Self {
+        let secret = key.shared_secret(remote_key, initiator);
+
+        // HKDF-SHA256 with the network magic in the salt
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&network.magic().to_le_bytes());
+        let mut engine = HmacEngine::<sha256::Hash>::new(&salt);
+        engine.input(&secret);
+        let prk = Hmac::<sha256::Hash>::from_engine(engine).into_inner();
+        let expand = |info: &[u8]| {
+            let mut engine = HmacEngine::<sha256::Hash>::new(&prk);
+            engine.input(info);
+            engine.input(&[1]);
+            Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+        };
+
+        let terminators = expand(b"garbage_terminators");
+        let mut initiator_terminator = [0u8; TERMINATOR_SIZE];
+        let mut responder_terminator = [0u8; TERMINATOR_SIZE];
+        initiator_terminator.copy_from_slice(&terminators[..TERMINATOR_SIZE]);
+        responder_terminator.copy_from_slice(&terminators[TERMINATOR_SIZE..]);
+        let initiator_ciphers = (expand(b"initiator_L"), expand(b"initiator_P"));
+        let responder_ciphers = (expand(b"responder_L"), expand(b"responder_P"));
+
+        let (send, recv, send_terminator, recv_terminator) = match initiator {
+            true => (
+                initiator_ciphers,
+                responder_ciphers,
+                initiator_terminator,
+                responder_terminator,
+            ),
+            false => (
+                responder_ciphers,
+                initiator_ciphers,
+                responder_terminator,
+                initiator_terminator,
+            ),
+        };
+        Self {
+            encryptor: cipher_pair(send, |length, packet| PacketEncryptor { length, packet }),
+            decryptor: cipher_pair(recv, |length, packet| PacketDecryptor { length, packet }),
+            send_terminator,
+            recv_terminator,
+        }
+    }
+
+    /// Returns the garbage terminator followed by the transport version packet that authenticates the sent `garbage`
+    fn terminator_and_version(&mut self, garbage: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut bytes = self.send_terminator.to_vec();
+        // No transport version features are defined, the contents are empty
+        bytes.extend(self.encryptor.encrypt(&[], garbage, false)?);
+        Ok(bytes)
+    }
+
+    /// Skips the garbage of the remote peer up to its terminator, then receives its transport version packet,
+    /// the first packet authenticates the received garbage and decoy packets may precede the version packet
+    async fn recv_garbage_and_version<S>(
+        mut self,
+        stream: &mut S,
+    ) -> Result<(PacketEncryptor, PacketDecryptor), TransportError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut garbage = Vec::new();
+        while !garbage.ends_with(&self.recv_terminator) {
+            // The terminator can not end before the longest end of the garbage that starts it is completed,
+            // reading up to there takes as many bytes as possible without consuming the packets that follow
+            let started = (0..TERMINATOR_SIZE)
+                .rev()
+                .find(|&length| garbage.ends_with(&self.recv_terminator[..length]))
+                .unwrap_or(0);
+            let missing = TERMINATOR_SIZE - started;
+            if garbage.len() + missing > MAX_GARBAGE_SIZE + TERMINATOR_SIZE {
+                return Err(Report::new(TransportError)
+                    .attach(HandshakeErrorKind::MalformedMessage)
+                    .attach_printable("Garbage terminator of the remote peer not found"));
+            }
+            let end = garbage.len();
+            garbage.resize(end + missing, 0);
+            read_exact(stream, &mut garbage[end..], "garbage").await?;
+        }
+        garbage.truncate(garbage.len() - TERMINATOR_SIZE);
+
+        let mut aad = garbage;
+        loop {
+            let (_, decoy) = self.decryptor.read_packet(stream, &aad).await?;
+            aad.clear();
+            if !decoy {
+                return Ok((self.encryptor, self.decryptor));
+            }
+        }
+    }
+}
+
+/// Builds the cipher of one direction from its length and packet keys
+fn cipher_pair<T>(
+    (length, packet): ([u8; KEY_SIZE], [u8; KEY_SIZE]),
+    build: impl FnOnce(FSChaCha20, FSChaCha20Poly1305) -> T,
+) -> T {
+    build(FSChaCha20::new(length), FSChaCha20Poly1305::new(packet))
+}
+
+/// Returns random garbage of a random size up to the maximum
+fn random_garbage() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut garbage = vec![0u8; rng.gen_range(0..=MAX_GARBAGE_SIZE)];
+    rng.fill_bytes(&mut garbage);
+    garbage
+}
+
+/// Fills the `buffer` from the `reader`, `what` names the read bytes in the report
+async fn read_exact<R>(reader: &mut R, buffer: &mut [u8], what: &str) -> Result<(), TransportError>
+where
+    R: AsyncRead + Unpin,
+{
+    reader
+        .read_exact(buffer)
+        .await
+        .map_err(transport::io_report)
+        .attach_printable_lazy(|| format!("Failed to read {what}"))
+        .change_context(TransportError)
+        .map(|_| ())
+}
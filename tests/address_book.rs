@@ -0,0 +1,236 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bitcoin::Network;
+use p2p_node_handshake::{
+    AddressBook, HandshakeErrorKind, HandshakeManager, HandshakeStatus, MockBehaviour, MockPeer,
+    PeerAddr, PeerSource,
+};
+
+/// Returns a path in the temporary directory that is unique to the test
+fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "address_book_{}_{}_{name}.json",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn missing_file_is_an_empty_address_book() {
+    let path = temp_path("missing");
+    let book = AddressBook::load(&path, Network::Bitcoin).expect("missing file should load");
+    assert!(book.is_empty());
+    assert_eq!(book.path(), path.as_path());
+}
+
+#[test]
+fn add_keeps_the_first_source() {
+    let mut book = AddressBook::load(temp_path("add"), Network::Bitcoin).unwrap();
+    let peer = addr("10.0.0.1:8333");
+
+    assert!(book.add(peer, PeerSource::Seed("seed.example.org".to_owned())));
+    assert!(!book.add(peer, PeerSource::Peer(addr("10.0.0.2:8333"))));
+    assert_eq!(
+        book.get(&peer.into()).unwrap().source,
+        PeerSource::Seed("seed.example.org".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn record_status_and_roundtrip() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .expect("mock peer should start");
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+    manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    let path = temp_path("roundtrip");
+    let failed = addr("10.0.0.3:8333");
+    let mut book = AddressBook::load(&path, Network::Bitcoin).unwrap();
+    book.add(failed, PeerSource::Peer(peer.addr()));
+    book.record_statuses(manager.statuses());
+    book.record_status(
+        failed,
+        &HandshakeStatus::Failed(HandshakeErrorKind::ConnectTimeout),
+    );
+    book.record_status(
+        failed,
+        &HandshakeStatus::Failed(HandshakeErrorKind::ConnectRefused),
+    );
+    book.save().expect("address book should be saved");
+
+    let loaded = AddressBook::load(&path, Network::Bitcoin).expect("saved file should load");
+    assert_eq!(loaded.peers(), book.peers());
+
+    let entry = loaded.get(&peer.addr().into()).unwrap();
+    assert_eq!(entry.source, PeerSource::Manual);
+    assert!(entry.last_success.is_some());
+    assert_eq!(entry.last_attempt, entry.last_success);
+    assert_eq!(entry.failure_count, 0);
+    let version = entry
+        .last_version
+        .as_ref()
+        .expect("version should be recorded");
+    assert_eq!(version.user_agent, MockPeer::USER_AGENT);
+
+    let entry = loaded.get(&failed.into()).unwrap();
+    assert_eq!(entry.last_success, None);
+    assert_eq!(entry.failure_count, 2);
+    assert_eq!(entry.last_error.as_deref(), Some("connect_refused"));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn select_peers_prefers_successful_then_untried() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .expect("mock peer should start");
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+    manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    let mut book = AddressBook::load(temp_path("select"), Network::Bitcoin).unwrap();
+    let [untried, failed_once, failed_twice] =
+        ["10.0.0.1:8333", "10.0.0.2:8333", "10.0.0.3:8333"].map(addr);
+    let failed = HandshakeStatus::Failed(HandshakeErrorKind::ConnectTimeout);
+
+    book.add(untried, PeerSource::Manual);
+    book.record_status(failed_twice, &failed);
+    book.record_status(failed_twice, &failed);
+    book.record_status(failed_once, &failed);
+    book.record_statuses(manager.statuses());
+
+    assert_eq!(
+        book.select_peers(10),
+        [peer.addr(), untried, failed_once, failed_twice].map(PeerAddr::from)
+    );
+    assert_eq!(
+        book.select_peers(2),
+        [peer.addr(), untried].map(PeerAddr::from)
+    );
+
+    let stale = book.select_stale_peers(10);
+    assert_eq!(stale.len(), 4);
+    assert_eq!(stale[0], untried);
+    assert_eq!(book.select_stale_peers(1), vec![untried]);
+}
+
+#[tokio::test]
+async fn completed_handshake_resets_the_failures() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .expect("mock peer should start");
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+    manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    let mut book = AddressBook::load(temp_path("reset"), Network::Bitcoin).unwrap();
+    let failed = HandshakeStatus::Failed(HandshakeErrorKind::ConnectTimeout);
+    book.record_status(peer.addr(), &failed);
+    book.record_status(peer.addr(), &failed);
+    book.record_statuses(manager.statuses());
+
+    let entry = book.get(&peer.addr().into()).unwrap();
+    assert!(entry.last_success.is_some());
+    assert_eq!(entry.failure_count, 0);
+    assert_eq!(entry.last_error, None);
+
+    book.record_status(peer.addr(), &failed);
+    let entry = book.get(&peer.addr().into()).unwrap();
+    assert_eq!(entry.failure_count, 1);
+    assert_eq!(entry.last_error.as_deref(), Some("connect_timeout"));
+}
+
+#[test]
+fn inbound_peers_are_kept_with_their_listening_port() {
+    let book = AddressBook::load(temp_path("inbound"), Network::Testnet).unwrap();
+    let remote = addr("10.0.0.1:51234");
+
+    assert_eq!(
+        book.inbound_addr(remote, Some(addr("10.0.0.1:18444"))),
+        addr("10.0.0.1:18444")
+    );
+    assert_eq!(
+        book.inbound_addr(
+            addr("[::ffff:10.0.0.1]:51234"),
+            Some(addr("10.0.0.1:18444"))
+        ),
+        addr("10.0.0.1:18444")
+    );
+    for sender in [
+        None,
+        Some(addr("0.0.0.0:0")),
+        Some(remote),
+        Some(addr("10.0.0.2:18444")),
+    ] {
+        assert_eq!(book.inbound_addr(remote, sender), addr("10.0.0.1:18333"));
+    }
+}
+
+#[test]
+fn overlay_peers_are_kept_by_host_and_host_names_are_skipped() {
+    let path = temp_path("overlay");
+    let onion: PeerAddr = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion:8333"
+        .parse()
+        .unwrap();
+    let i2p: PeerAddr = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0"
+        .parse()
+        .unwrap();
+    let name = PeerAddr::Name {
+        host: "node.example.org".to_owned(),
+        port: 8333,
+    };
+    let failed = HandshakeStatus::Failed(HandshakeErrorKind::ConnectFailed);
+
+    let mut book = AddressBook::load(&path, Network::Bitcoin).unwrap();
+    assert!(book.add(onion.clone(), PeerSource::Manual));
+    assert!(!book.add(name.clone(), PeerSource::Manual));
+    book.record_statuses(&[(i2p.clone(), failed.clone()), (name, failed)].into());
+    book.add(addr("10.0.0.1:8333"), PeerSource::Manual);
+    book.save().unwrap();
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        content.contains("\"pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion:8333\"")
+    );
+    assert!(content.contains("\"ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0\""));
+    let loaded = AddressBook::load(&path, Network::Bitcoin).expect("saved file should load");
+    assert_eq!(loaded.peers(), book.peers());
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded.get(&i2p).unwrap().failure_count, 1);
+    assert_eq!(loaded.select_peers(10).len(), 3);
+    // The crawler and the DNS seeder only handle IP peers
+    assert_eq!(loaded.select_stale_peers(10), vec![addr("10.0.0.1:8333")]);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn address_book_of_another_network_is_rejected() {
+    let path = temp_path("network");
+    let mut book = AddressBook::load(&path, Network::Testnet).unwrap();
+    book.add(addr("10.0.0.1:18333"), PeerSource::Manual);
+    book.save().unwrap();
+
+    assert!(AddressBook::load(&path, Network::Bitcoin).is_err());
+    let _ = std::fs::remove_file(&path);
+}
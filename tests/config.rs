@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use bitcoin::{network::constants::ServiceFlags, Network};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use p2p_node_handshake::{
+    BookCommand, Command, Config, HandshakeFeatures, OutputFormat, SeedSelection, SeedsCommand,
+    Target,
+};
+
+fn parse(args: &[&str]) -> Config {
+    Config::try_parse_from(std::iter::once("p2p-node-handshake").chain(args.iter().copied()))
+        .expect("arguments should be valid")
+}
+
+fn parse_error(args: &[&str]) -> clap::Error {
+    Config::try_parse_from(std::iter::once("p2p-node-handshake").chain(args.iter().copied()))
+        .expect_err("arguments should be invalid")
+}
+
+#[test]
+fn subcommands_are_parsed() {
+    assert!(matches!(
+        parse(&["seeds", "list"]).command,
+        Command::Seeds(SeedsCommand::List)
+    ));
+    assert!(matches!(
+        parse(&["seeds", "resolve", "2"]).command,
+        Command::Seeds(SeedsCommand::Resolve {
+            seed: SeedSelection::Index(2)
+        })
+    ));
+    assert!(matches!(
+        parse(&["seeds", "resolve", "all"]).command,
+        Command::Seeds(SeedsCommand::Resolve {
+            seed: SeedSelection::All
+        })
+    ));
+    assert!(matches!(
+        parse(&["seeds", "check"]).command,
+        Command::Seeds(SeedsCommand::Check {
+            seed: SeedSelection::All,
+            sample: 10
+        })
+    ));
+    assert!(matches!(
+        parse(&["seeds", "check", "1", "--sample", "25"]).command,
+        Command::Seeds(SeedsCommand::Check {
+            seed: SeedSelection::Index(1),
+            sample: 25
+        })
+    ));
+    assert!(matches!(
+        parse(&["scan", "all"]).command,
+        Command::Scan {
+            seed: SeedSelection::All
+        }
+    ));
+    assert!(matches!(
+        parse(&["crawl", "1", "--max-depth", "3"]).command,
+        Command::Crawl {
+            seed: SeedSelection::Index(1),
+            max_depth: 3,
+            max_peers: 1000
+        }
+    ));
+    assert!(matches!(
+        parse(&["listen"]).command,
+        Command::Listen { bind: None }
+    ));
+    assert!(matches!(
+        parse(&["book", "handshake", "10"]).command,
+        Command::Book(BookCommand::Handshake { count: 10 })
+    ));
+    let Command::Seeder {
+        zone,
+        dns_bind,
+        interval,
+        batch,
+        max_age,
+    } = parse(&[
+        "seeder",
+        "seed.example.org",
+        "--dns-bind",
+        "127.0.0.1:5353",
+        "--batch",
+        "64",
+    ])
+    .command
+    else {
+        panic!("expected the seeder command");
+    };
+    assert_eq!(zone, "seed.example.org");
+    assert_eq!(
+        dns_bind,
+        "127.0.0.1:5353".parse::<std::net::SocketAddr>().unwrap()
+    );
+    assert_eq!((interval, batch, max_age), (60, 64, 3600));
+}
+
+#[test]
+fn handshake_targets_are_addresses_a_file_or_seed_and_node_indexes() {
+    let Command::Handshake(targets) =
+        parse(&["handshake", "127.0.0.1:8333", "localhost", "::1"]).command
+    else {
+        panic!("expected the handshake command");
+    };
+    let expected: Vec<Target> = ["127.0.0.1:8333", "localhost", "::1"]
+        .iter()
+        .map(|target| target.parse().unwrap())
+        .collect();
+    assert_eq!(targets.targets, expected);
+    assert_eq!(targets.targets_file, None);
+
+    let Command::Handshake(targets) =
+        parse(&["handshake", "--targets-file", "targets.txt", "10.0.0.1"]).command
+    else {
+        panic!("expected the handshake command");
+    };
+    assert_eq!(targets.targets.len(), 1);
+    assert_eq!(
+        targets
+            .targets_file
+            .as_deref()
+            .and_then(|path| path.to_str()),
+        Some("targets.txt")
+    );
+
+    let Command::Handshake(targets) = parse(&["handshake", "--seed", "0", "--node", "3"]).command
+    else {
+        panic!("expected the handshake command");
+    };
+    assert!(targets.targets.is_empty());
+    assert_eq!((targets.seed, targets.node), (Some(0), Some(3)));
+
+    assert_eq!(
+        parse_error(&["handshake"]).kind(),
+        ErrorKind::MissingRequiredArgument
+    );
+    assert_eq!(
+        parse_error(&["handshake", "--seed", "0"]).kind(),
+        ErrorKind::MissingRequiredArgument
+    );
+    assert_eq!(
+        parse_error(&["handshake", "127.0.0.1:8333", "--seed", "0", "--node", "1"]).kind(),
+        ErrorKind::ArgumentConflict
+    );
+}
+
+#[test]
+fn options_are_accepted_before_and_after_the_command() {
+    let config = parse(&["--network", "testnet", "seeds", "list", "--output", "csv"]);
+    assert_eq!(config.network, Network::Testnet);
+    assert_eq!(config.output, OutputFormat::Csv);
+    assert_eq!(config.concurrency, 32);
+    assert_eq!(
+        config.address_book_path().to_str(),
+        Some("address_book_testnet.json")
+    );
+}
+
+#[test]
+fn handshake_options_configure_the_handshake_manager() {
+    let config = parse(&[
+        "scan",
+        "all",
+        "--connect-timeout",
+        "500",
+        "--services",
+        "network,witness",
+        "--user-agent",
+        "/Satoshi:24.0.1/",
+        "--required-services",
+        "0x1",
+        "--max-clock-skew",
+        "60",
+        "--features",
+        "wtxidrelay",
+    ]);
+    let manager = config.handshake.handshake_manager(config.network);
+
+    assert_eq!(manager.timeouts().connect, Duration::from_millis(500));
+    assert_eq!(manager.timeouts().version, Duration::from_secs(2));
+    assert_eq!(
+        manager.version_params().services(),
+        ServiceFlags::NETWORK | ServiceFlags::WITNESS
+    );
+    assert_eq!(manager.version_params().user_agent(), "/Satoshi:24.0.1/");
+    assert_eq!(manager.policy().required_services, ServiceFlags::NETWORK);
+    assert_eq!(
+        manager.policy().max_clock_skew,
+        Some(Duration::from_secs(60))
+    );
+    assert!(manager.features().wtxid_relay);
+    assert!(!manager.features().addr_v2);
+
+    let config = parse(&["scan", "all"]);
+    let manager = config.handshake.handshake_manager(config.network);
+    assert_eq!(manager.version_params().protocol_version(), 70016);
+    assert!(manager.features().addr_v2 && !manager.features().wtxid_relay);
+    let config = parse(&["scan", "all", "--features", "none"]);
+    let manager = config.handshake.handshake_manager(config.network);
+    assert_eq!(manager.features(), HandshakeFeatures::default());
+
+    let config = parse(&[
+        "scan",
+        "all",
+        "--services",
+        "none",
+        "--required-services",
+        "",
+        "--features",
+        "",
+    ]);
+    let manager = config.handshake.handshake_manager(config.network);
+    assert_eq!(manager.version_params().services(), ServiceFlags::NONE);
+    assert_eq!(manager.policy().required_services, ServiceFlags::NONE);
+    assert_eq!(manager.features(), HandshakeFeatures::default());
+}
+
+#[test]
+fn validation_errors_name_the_bad_argument() {
+    let cases: &[(&[&str], &str)] = &[
+        (&["scan", "first"], "<SEED>"),
+        (&["--network", "moon", "seeds", "list"], "--network"),
+        (&["seeds", "list", "--output", "yaml"], "--output"),
+        (&["seeds", "list", "--concurrency", "0"], "--concurrency"),
+        (
+            &["seeds", "list", "--verack-timeout", "soon"],
+            "--verack-timeout",
+        ),
+        (&["seeds", "list", "--services", "magic"], "--services"),
+        (
+            &["seeds", "list", "--user-agent", "Satoshi"],
+            "--user-agent",
+        ),
+        (&["seeds", "list", "--features", "compact"], "--features"),
+        (&["handshake", "node.example.org:port"], "[TARGET]"),
+    ];
+    for (args, argument) in cases {
+        let error = parse_error(args);
+        assert!(
+            matches!(
+                error.kind(),
+                ErrorKind::ValueValidation | ErrorKind::InvalidValue
+            ),
+            "{args:?}: {:?}",
+            error.kind()
+        );
+        assert!(error.to_string().contains(argument), "{args:?}: {error}");
+    }
+}
+
+#[test]
+fn help_and_version_are_available() {
+    Config::command().debug_assert();
+    assert_eq!(parse_error(&["--help"]).kind(), ErrorKind::DisplayHelp);
+    assert_eq!(
+        parse_error(&["--version"]).kind(),
+        ErrorKind::DisplayVersion
+    );
+    assert_eq!(
+        parse_error(&[]).kind(),
+        ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+    );
+}
@@ -0,0 +1,149 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bitcoin::{network::message::NetworkMessage, Network};
+use p2p_node_handshake::{
+    CrawlLimits, Crawler, HandshakeErrorKind, HandshakeFeatures, HandshakeManager, HandshakeStatus,
+    MockBehaviour, MockPeer, PeerAddr, VersionParams,
+};
+use tokio::net::TcpListener;
+
+async fn start_mock_peers(count: usize) -> Vec<MockPeer> {
+    let mut peers = Vec::new();
+    for _ in 0..count {
+        let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+            .await
+            .expect("mock peer should start");
+        peers.push(peer);
+    }
+    peers
+}
+
+async fn closed_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn limits(max_depth: usize, max_peers: usize) -> CrawlLimits {
+    CrawlLimits {
+        max_depth,
+        max_peers,
+        concurrency: 4,
+        addr_timeout: Duration::from_millis(300),
+    }
+}
+
+#[tokio::test]
+async fn crawl_follows_gossiped_addresses() {
+    let peers = start_mock_peers(4).await;
+    let unreachable = closed_addr().await;
+    let [a, b, c, d] = [0, 1, 2, 3].map(|i| peers[i].addr());
+    peers[0].set_gossip(vec![b, c]);
+    peers[1].set_gossip(vec![c, d, a]);
+    peers[3].set_gossip(vec![unreachable, a]);
+
+    let mut crawler = Crawler::new(HandshakeManager::new(Network::Bitcoin), limits(3, 100));
+    let map = crawler.crawl(vec![a]).await;
+
+    assert_eq!(map.reachable(), {
+        let mut reachable = vec![a, b, c, d];
+        reachable.sort();
+        reachable
+    });
+    assert_eq!(map.peers[&a].depth, 0);
+    assert_eq!(map.peers[&a].source, None);
+    assert_eq!(map.peers[&c].depth, 1);
+    assert_eq!(map.peers[&d].source, Some(b));
+    assert_eq!(map.peers[&d].depth, 2);
+    assert_eq!(
+        map.peers[&unreachable].status,
+        Some(HandshakeStatus::Failed(HandshakeErrorKind::ConnectRefused))
+    );
+    assert_eq!(crawler.handshake_manager().statuses().len(), 5);
+}
+
+#[tokio::test]
+async fn crawl_stops_at_max_depth() {
+    let peers = start_mock_peers(3).await;
+    let [a, b, c] = [0, 1, 2].map(|i| peers[i].addr());
+    peers[0].set_gossip(vec![b]);
+    peers[1].set_gossip(vec![c]);
+
+    let mut crawler = Crawler::new(HandshakeManager::new(Network::Bitcoin), limits(1, 100));
+    let map = crawler.crawl(vec![a]).await;
+
+    assert_eq!(map.peers.len(), 3);
+    assert!(map.peers[&b]
+        .status
+        .as_ref()
+        .is_some_and(HandshakeStatus::is_completed));
+    assert_eq!(map.peers[&c].depth, 2);
+    assert_eq!(map.peers[&c].status, None);
+}
+
+#[tokio::test]
+async fn crawl_stops_at_max_peers() {
+    let peers = start_mock_peers(4).await;
+    let addrs: Vec<_> = peers.iter().map(MockPeer::addr).collect();
+    peers[0].set_gossip(addrs[1..].to_vec());
+
+    let mut crawler = Crawler::new(HandshakeManager::new(Network::Bitcoin), limits(2, 2));
+    let map = crawler.crawl(vec![addrs[0]]).await;
+
+    assert_eq!(map.peers.len(), 4);
+    assert_eq!(
+        map.peers
+            .values()
+            .filter(|peer| peer.status.is_some())
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn crawl_decodes_addrv2_gossip() {
+    let peers = start_mock_peers(3).await;
+    let [a, b, c] = [0, 1, 2].map(|i| peers[i].addr());
+    peers[0].set_gossip(vec![b, c]);
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_version_params(VersionParams::new().with_protocol_version(70016));
+    handshake_manager.set_features(HandshakeFeatures {
+        wtxid_relay: false,
+        addr_v2: true,
+    });
+
+    let mut crawler = Crawler::new(handshake_manager, limits(1, 100));
+    let map = crawler.crawl(vec![a]).await;
+
+    assert!(peers[0]
+        .received_messages()
+        .contains(&NetworkMessage::SendAddrV2));
+    assert_eq!(map.peers[&a].gossiped, {
+        let mut gossiped = vec![b, c];
+        gossiped.sort();
+        gossiped
+    });
+    assert_eq!(map.reachable().len(), 3);
+}
+
+#[tokio::test]
+async fn crawl_records_overlay_gossip_without_following_it() {
+    let peers = start_mock_peers(2).await;
+    let [a, b] = [0, 1].map(|i| peers[i].addr());
+    let onion: PeerAddr = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion:8333"
+        .parse()
+        .unwrap();
+    let i2p: PeerAddr = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:0"
+        .parse()
+        .unwrap();
+    peers[0].set_gossip(vec![PeerAddr::from(b), onion.clone(), i2p.clone()]);
+    // The default settings announce `sendaddrv2`, without it the peers gossip no Tor and I2P addresses
+    let handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let mut crawler = Crawler::new(handshake_manager, limits(1, 100));
+    let map = crawler.crawl(vec![a]).await;
+
+    let mut gossiped = vec![PeerAddr::from(b), onion, i2p];
+    gossiped.sort();
+    assert_eq!(map.peers[&a].gossiped, gossiped);
+    assert_eq!(map.peers.len(), 2);
+}
@@ -0,0 +1,381 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bitcoin::{network::constants::ServiceFlags, Network};
+use clap::Parser;
+use p2p_node_handshake::{Config, DnsSeedManager, DnsSeedSettings, MockDnsServer};
+
+fn ips(addresses: &[&str]) -> Vec<IpAddr> {
+    addresses
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect()
+}
+
+fn settings(server: &MockDnsServer, seeds: &[&str]) -> DnsSeedSettings {
+    DnsSeedSettings {
+        seeds: seeds.iter().map(|seed| seed.to_string()).collect(),
+        nameserver: Some(server.addr()),
+        timeout: Duration::from_millis(500),
+        services: ServiceFlags::NONE,
+        proxy: None,
+    }
+}
+
+#[tokio::test]
+async fn seed_lookup_returns_a_and_aaaa_records_with_the_network_port() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_records(
+        "seed.example.org",
+        ips(&["10.0.0.1", "10.0.0.2", "2001:db8::1"]),
+    );
+    let settings = settings(&server, &["seed.example.org"]);
+
+    let dsm = DnsSeedManager::new_with_dns(Network::Testnet, &settings, "Seed.Example.Org.")
+        .await
+        .unwrap();
+
+    let expected: Vec<SocketAddr> = ["10.0.0.1:18333", "10.0.0.2:18333", "[2001:db8::1]:18333"]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+    let mut nodes = dsm.active_nodes.clone();
+    nodes.sort();
+    assert_eq!(nodes, expected);
+    assert_eq!(dsm.source(&expected[0]), Some("Seed.Example.Org."));
+    assert!(server
+        .queries()
+        .iter()
+        .all(|name| name == "seed.example.org"));
+}
+
+#[tokio::test]
+async fn truncated_seed_lookup_is_queried_again_over_tcp() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_records("seed.example.org", ips(&["10.0.0.1", "2001:db8::1"]));
+    server.set_truncated("seed.example.org");
+    let settings = settings(&server, &["seed.example.org"]);
+
+    let dsm = DnsSeedManager::new_with_dns(Network::Bitcoin, &settings, "seed.example.org")
+        .await
+        .unwrap();
+
+    let mut nodes = dsm.active_nodes.clone();
+    nodes.sort();
+    assert_eq!(
+        nodes,
+        vec![
+            "10.0.0.1:8333".parse::<SocketAddr>().unwrap(),
+            "[2001:db8::1]:8333".parse().unwrap()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unknown_seed_is_an_error() {
+    let server = MockDnsServer::start().await.unwrap();
+    let settings = settings(&server, &["missing.example.org"]);
+
+    let error = DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 0)
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{error:?}").contains("response code 3"),
+        "{error:?}"
+    );
+    assert!(
+        DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 1)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn silent_seed_times_out() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_silent("slow.example.org");
+    let settings = settings(&server, &["slow.example.org"]);
+
+    let started = Instant::now();
+    let error = DnsSeedManager::new_with_dns(Network::Bitcoin, &settings, "slow.example.org")
+        .await
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(
+        format!("{error:?}").contains("timed out after 500ms"),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn all_seeds_lookup_skips_failed_seeds() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_silent("slow.example.org");
+    server.set_records("one.example.org", ips(&["10.0.0.1", "10.0.0.2"]));
+    server.set_records("two.example.org", ips(&["10.0.0.2", "10.0.0.3"]));
+    let settings = settings(
+        &server,
+        &[
+            "slow.example.org",
+            "one.example.org",
+            "missing.example.org",
+            "two.example.org",
+            "10.0.0.9",
+        ],
+    );
+
+    let dsm = DnsSeedManager::new_with_all_dns(Network::Regtest, &settings).await;
+
+    let mut nodes: Vec<_> = dsm
+        .active_nodes
+        .iter()
+        .map(|node| node.to_string())
+        .collect();
+    nodes.sort();
+    assert_eq!(
+        nodes,
+        [
+            "10.0.0.1:18444",
+            "10.0.0.2:18444",
+            "10.0.0.3:18444",
+            "10.0.0.9:18444"
+        ]
+    );
+    assert_eq!(
+        dsm.source(&"10.0.0.9:18444".parse().unwrap()),
+        Some("10.0.0.9")
+    );
+}
+
+#[test]
+fn default_seed_manager_is_empty_and_resolves_nothing() {
+    let dsm = DnsSeedManager::default();
+
+    assert!(dsm.active_nodes.is_empty());
+    assert!(dsm.sources.is_empty());
+}
+
+#[test]
+fn filtered_query_names_carry_the_service_flags_in_hex() {
+    let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+    assert_eq!(
+        DnsSeedManager::filtered_query_name("seed.example.org", services).as_deref(),
+        Some("x9.seed.example.org")
+    );
+    assert_eq!(
+        DnsSeedManager::filtered_query_name("seed.example.org", ServiceFlags::from(0x409))
+            .as_deref(),
+        Some("x409.seed.example.org")
+    );
+    assert_eq!(
+        DnsSeedManager::filtered_query_name("seed.example.org", ServiceFlags::NONE),
+        None
+    );
+    assert_eq!(
+        DnsSeedManager::filtered_query_name("10.0.0.1", services),
+        None
+    );
+}
+
+#[tokio::test]
+async fn filtered_seed_lookup_returns_the_filtered_nodes() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_records("seed.example.org", ips(&["10.0.0.1", "10.0.0.2"]));
+    server.set_records("x9.seed.example.org", ips(&["10.0.0.2"]));
+    let mut settings = settings(&server, &["seed.example.org"]);
+    settings.services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+
+    let dsm = DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 0)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        dsm.active_nodes,
+        ["10.0.0.2:8333".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(dsm.source(&dsm.active_nodes[0]), Some("seed.example.org"));
+    assert!(server
+        .queries()
+        .iter()
+        .all(|name| name == "x9.seed.example.org"));
+}
+
+#[tokio::test]
+async fn filtered_seed_lookup_falls_back_to_the_unfiltered_name() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_records("plain.example.org", ips(&["10.0.0.1"]));
+    server.set_records("empty.example.org", ips(&["10.0.0.2"]));
+    server.set_records("x1.empty.example.org", Vec::new());
+    server.set_records("slow.example.org", ips(&["10.0.0.3"]));
+    server.set_silent("x1.slow.example.org");
+    let mut settings = settings(
+        &server,
+        &["plain.example.org", "empty.example.org", "slow.example.org"],
+    );
+    settings.services = ServiceFlags::NETWORK;
+    settings.timeout = Duration::from_millis(200);
+
+    let dsm = DnsSeedManager::new_with_all_dns(Network::Bitcoin, &settings).await;
+
+    let mut nodes: Vec<_> = dsm
+        .active_nodes
+        .iter()
+        .map(|node| node.to_string())
+        .collect();
+    nodes.sort();
+    assert_eq!(nodes, ["10.0.0.1:8333", "10.0.0.2:8333", "10.0.0.3:8333"]);
+    let queries = server.queries();
+    for name in [
+        "x1.plain.example.org",
+        "plain.example.org",
+        "x1.empty.example.org",
+        "x1.slow.example.org",
+    ] {
+        assert!(
+            queries.iter().any(|query| query == name),
+            "{name} in {queries:?}"
+        );
+    }
+}
+
+#[test]
+fn dns_options_configure_the_seed_settings() {
+    let config = Config::try_parse_from(["p2p-node-handshake", "seeds", "list"]).unwrap();
+    assert_eq!(
+        config.dns_settings().unwrap(),
+        DnsSeedSettings::new(Network::Bitcoin)
+    );
+    assert!(!DnsSeedSettings::new(Network::Bitcoin).seeds.is_empty());
+
+    let path = std::env::temp_dir().join(format!("dns_seeds_{}.txt", std::process::id()));
+    std::fs::write(
+        &path,
+        "# local seeds\nthree.example.org\n\n  four.example.org  \n",
+    )
+    .unwrap();
+    let config = Config::try_parse_from([
+        "p2p-node-handshake",
+        "scan",
+        "all",
+        "--dns-seed",
+        "one.example.org",
+        "--dns-seed",
+        "two.example.org",
+        "--dns-seeds-file",
+        path.to_str().unwrap(),
+        "--nameserver",
+        "127.0.0.1",
+        "--dns-timeout",
+        "250",
+        "--seed-services",
+        "network,witness",
+    ])
+    .unwrap();
+    let settings = config.dns_settings().unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(
+        settings.seeds,
+        [
+            "one.example.org",
+            "two.example.org",
+            "three.example.org",
+            "four.example.org"
+        ]
+    );
+    assert_eq!(settings.nameserver, Some("127.0.0.1:53".parse().unwrap()));
+    assert_eq!(settings.timeout, Duration::from_millis(250));
+    assert_eq!(
+        settings.services,
+        ServiceFlags::NETWORK | ServiceFlags::WITNESS
+    );
+
+    let config = Config::try_parse_from([
+        "p2p-node-handshake",
+        "seeds",
+        "list",
+        "--nameserver",
+        "[::1]:5353",
+    ])
+    .unwrap();
+    assert_eq!(
+        config.dns_settings().unwrap().nameserver,
+        Some("[::1]:5353".parse().unwrap())
+    );
+    assert!(Config::try_parse_from([
+        "p2p-node-handshake",
+        "seeds",
+        "list",
+        "--nameserver",
+        "dns.example.org"
+    ])
+    .is_err());
+}
+
+#[tokio::test]
+async fn resolve_all_queries_the_seeds_concurrently_and_records_every_source() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_records("one.example.org", ips(&["10.0.0.1", "10.0.0.2"]));
+    server.set_records("two.example.org", ips(&["10.0.0.2", "2001:db8::3"]));
+    for seed in [
+        "slow1.example.org",
+        "slow2.example.org",
+        "slow3.example.org",
+    ] {
+        server.set_silent(seed);
+    }
+    let mut settings = settings(
+        &server,
+        &[
+            "slow1.example.org",
+            "one.example.org",
+            "slow2.example.org",
+            "missing.example.org",
+            "two.example.org",
+            "slow3.example.org",
+        ],
+    );
+    settings.timeout = Duration::from_millis(300);
+
+    let started = Instant::now();
+    let (dsm, lookups) = DnsSeedManager::resolve_all(Network::Bitcoin, &settings).await;
+    assert!(
+        started.elapsed() < Duration::from_millis(800),
+        "{:?}",
+        started.elapsed()
+    );
+
+    let seeds: Vec<_> = lookups.iter().map(|lookup| lookup.seed.as_str()).collect();
+    assert_eq!(seeds, settings.seeds);
+    let failed: Vec<_> = lookups
+        .iter()
+        .filter(|lookup| lookup.result.is_err())
+        .map(|lookup| lookup.seed.as_str())
+        .collect();
+    assert_eq!(
+        failed,
+        [
+            "slow1.example.org",
+            "slow2.example.org",
+            "missing.example.org",
+            "slow3.example.org"
+        ]
+    );
+    assert!(lookups[0].latency >= Duration::from_millis(300));
+    assert_eq!(lookups[1].result.as_ref().map(Vec::len).ok(), Some(2));
+
+    let shared = "10.0.0.2:8333".parse().unwrap();
+    assert_eq!(dsm.active_nodes.len(), 3);
+    assert_eq!(
+        dsm.sources_of(&shared),
+        ["one.example.org", "two.example.org"]
+    );
+    assert_eq!(dsm.source(&shared), Some("one.example.org"));
+    assert_eq!(
+        dsm.sources_of(&"[2001:db8::3]:8333".parse().unwrap()),
+        ["two.example.org"]
+    );
+    assert!(dsm.sources_of(&"10.0.0.9:8333".parse().unwrap()).is_empty());
+}
@@ -0,0 +1,817 @@
+use std::time::Duration;
+
+use bitcoin::{
+    network::{constants::ServiceFlags, message::NetworkMessage},
+    Network,
+};
+use p2p_node_handshake::{
+    ConnectionDirection, HandshakeError, HandshakeErrorKind, HandshakeFeatures, HandshakeManager,
+    HandshakePolicy, HandshakeStatus, HandshakeTimeouts, MockBehaviour, MockPeer, VersionParams,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+async fn start_mock_peer(behaviour: MockBehaviour) -> MockPeer {
+    MockPeer::start(Network::Bitcoin, behaviour)
+        .await
+        .expect("mock peer should start")
+}
+
+fn short_timeouts() -> HandshakeTimeouts {
+    HandshakeTimeouts {
+        connect: Duration::from_millis(500),
+        version: Duration::from_millis(300),
+        verack: Duration::from_millis(300),
+    }
+}
+
+async fn handshake_error_kind(behaviour: MockBehaviour) -> HandshakeErrorKind {
+    let peer = start_mock_peer(behaviour).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_timeouts(short_timeouts());
+
+    let report = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect_err("handshake should fail");
+    HandshakeError::kind(&report)
+}
+
+#[tokio::test]
+async fn handshake_completes_with_mock_peer() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    assert_eq!(outcome.remote_addr, peer.addr());
+    assert_eq!(outcome.direction, ConnectionDirection::Outbound);
+    assert_eq!(outcome.peer.version, MockPeer::PROTOCOL_VERSION);
+    assert_eq!(outcome.peer.user_agent, MockPeer::USER_AGENT);
+    assert_eq!(outcome.peer.start_height, MockPeer::START_HEIGHT);
+    assert_eq!(outcome.peer.nonce, MockPeer::NONCE);
+    assert_eq!(outcome.peer.sender, Some(peer.addr()));
+    assert_eq!(
+        outcome.negotiated_version,
+        outcome.local_version.min(MockPeer::PROTOCOL_VERSION)
+    );
+    assert!(handshake_manager
+        .status(&peer.addr())
+        .is_some_and(HandshakeStatus::is_completed));
+}
+
+#[tokio::test]
+async fn mock_peer_receives_version_and_verack() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let received = peer.received_messages();
+    assert!(matches!(received.first(), Some(NetworkMessage::Version(_))));
+    assert!(received.contains(&NetworkMessage::Verack));
+}
+
+#[tokio::test]
+async fn mock_peer_receives_configured_version_fields() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let version_params = VersionParams::new()
+        .with_protocol_version(70016)
+        .with_services(ServiceFlags::NETWORK | ServiceFlags::WITNESS)
+        .with_user_agent("/test-node:1.2.3(offline)/")
+        .expect("user agent should be valid")
+        .with_start_height(123_456)
+        .with_relay(true);
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_version_params(version_params);
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(outcome.local_version, 70016);
+    let Some(NetworkMessage::Version(version)) = peer.received_messages().first().cloned() else {
+        panic!("mock peer should receive version first");
+    };
+    assert_eq!(version.version, 70016);
+    assert_eq!(
+        version.services,
+        ServiceFlags::NETWORK | ServiceFlags::WITNESS
+    );
+    assert_eq!(version.user_agent, "/test-node:1.2.3(offline)/");
+    assert_eq!(version.start_height, 123_456);
+    assert!(version.relay);
+}
+
+#[test]
+fn version_params_reject_non_bip14_user_agent() {
+    for user_agent in [
+        "plain-agent",
+        "/no-version/",
+        "/name:1.0",
+        "/name:1.0(open/",
+        "//",
+    ] {
+        assert!(
+            VersionParams::new().with_user_agent(user_agent).is_err(),
+            "{user_agent:?} should be rejected"
+        );
+    }
+    assert!(VersionParams::new()
+        .with_user_agent("/BitcoinJ:0.2(iPad; U; CPU OS 3_2_1)/AndroidBuild:0.8/")
+        .is_ok());
+}
+
+#[tokio::test]
+async fn handshake_completes_on_non_mainnet_network() {
+    let peer = MockPeer::start(Network::Regtest, MockBehaviour::Handshake)
+        .await
+        .expect("mock peer should start");
+    let mut handshake_manager = HandshakeManager::new(Network::Regtest);
+
+    assert!(handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn handshake_fails_on_wrong_first_message() {
+    let peer = start_mock_peer(MockBehaviour::WrongFirstMessage).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    assert!(handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .is_err());
+    assert_eq!(
+        handshake_manager.status(&peer.addr()),
+        Some(&HandshakeStatus::Failed(
+            HandshakeErrorKind::UnexpectedMessage
+        ))
+    );
+}
+
+#[tokio::test]
+async fn handshake_fails_on_missing_verack() {
+    assert_eq!(
+        handshake_error_kind(MockBehaviour::MissingVerack).await,
+        HandshakeErrorKind::ReadTimeout
+    );
+}
+
+#[tokio::test]
+async fn handshake_fails_on_wrong_magic() {
+    assert_eq!(
+        handshake_error_kind(MockBehaviour::WrongMagic).await,
+        HandshakeErrorKind::BadMagic
+    );
+}
+
+#[tokio::test]
+async fn handshake_fails_on_bad_checksum() {
+    assert_eq!(
+        handshake_error_kind(MockBehaviour::BadChecksum).await,
+        HandshakeErrorKind::ChecksumMismatch
+    );
+}
+
+#[tokio::test]
+async fn handshake_fails_when_connection_is_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_addr = listener.local_addr().unwrap();
+    drop(listener);
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let report = handshake_manager
+        .establish_handshake(closed_addr)
+        .await
+        .expect_err("handshake should fail");
+
+    assert_eq!(
+        HandshakeError::kind(&report),
+        HandshakeErrorKind::ConnectRefused
+    );
+}
+
+#[tokio::test]
+async fn handshake_fails_when_version_phase_exceeds_its_timeout() {
+    let peer = start_mock_peer(MockBehaviour::SlowResponse(Duration::from_millis(500))).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_timeouts(HandshakeTimeouts {
+        version: Duration::from_millis(200),
+        ..HandshakeTimeouts::default()
+    });
+
+    let report = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect_err("handshake should fail");
+
+    assert_eq!(
+        HandshakeError::kind(&report),
+        HandshakeErrorKind::ReadTimeout
+    );
+}
+
+#[tokio::test]
+async fn handshake_reports_duration_of_each_phase() {
+    let delay = Duration::from_millis(100);
+    let peer = start_mock_peer(MockBehaviour::SlowResponse(delay)).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    let timings = outcome.timings;
+    assert!(timings.version >= delay);
+    assert!(timings.total >= timings.connect + timings.version + timings.verack);
+}
+
+#[tokio::test]
+async fn handshake_tolerates_slow_responses_within_timeout() {
+    let delay = Duration::from_millis(200);
+    let peer = start_mock_peer(MockBehaviour::SlowResponse(delay)).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    assert!(outcome.timings.version >= delay);
+}
+
+async fn policy_error_kind(
+    behaviour: MockBehaviour,
+    policy: HandshakePolicy,
+) -> HandshakeErrorKind {
+    let peer = start_mock_peer(behaviour).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_policy(policy);
+
+    let report = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect_err("handshake should be rejected");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(
+        !peer.received_messages().contains(&NetworkMessage::Verack),
+        "verack must not be sent to a rejected peer"
+    );
+    HandshakeError::kind(&report)
+}
+
+#[tokio::test]
+async fn policy_rejects_old_protocol_version() {
+    let policy = HandshakePolicy {
+        min_protocol_version: MockPeer::PROTOCOL_VERSION + 1,
+        ..HandshakePolicy::default()
+    };
+    assert_eq!(
+        policy_error_kind(MockBehaviour::Handshake, policy).await,
+        HandshakeErrorKind::VersionTooOld
+    );
+}
+
+#[tokio::test]
+async fn policy_rejects_missing_services() {
+    let policy = HandshakePolicy {
+        required_services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+        ..HandshakePolicy::default()
+    };
+    assert_eq!(
+        policy_error_kind(MockBehaviour::Handshake, policy).await,
+        HandshakeErrorKind::MissingServices
+    );
+}
+
+#[tokio::test]
+async fn policy_rejects_clock_skew() {
+    let policy = HandshakePolicy {
+        max_clock_skew: Some(Duration::from_secs(60)),
+        disconnect_gracefully: true,
+        ..HandshakePolicy::default()
+    };
+    assert_eq!(
+        policy_error_kind(
+            MockBehaviour::SkewedClock(Duration::from_secs(3600)),
+            policy
+        )
+        .await,
+        HandshakeErrorKind::ClockSkew
+    );
+}
+
+#[tokio::test]
+async fn policy_rejects_self_connection() {
+    assert_eq!(
+        policy_error_kind(MockBehaviour::EchoVersion, HandshakePolicy::default()).await,
+        HandshakeErrorKind::SelfConnection
+    );
+}
+
+#[tokio::test]
+async fn manager_rejects_connection_to_its_own_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let mut outbound_manager = HandshakeManager::new(Network::Bitcoin);
+    let mut inbound_manager = outbound_manager.clone();
+    let (inbound, outbound) = tokio::join!(
+        inbound_manager.accept_handshake(&listener),
+        outbound_manager.establish_handshake(listen_addr)
+    );
+
+    let inbound = inbound.expect_err("inbound handshake should be rejected");
+    assert_eq!(
+        HandshakeError::kind(&inbound),
+        HandshakeErrorKind::SelfConnection
+    );
+    assert!(outbound.is_err());
+}
+
+#[tokio::test]
+async fn policy_accepts_peer_that_satisfies_it() {
+    let peer = start_mock_peer(MockBehaviour::SkewedClock(Duration::from_secs(10))).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_policy(HandshakePolicy {
+        min_protocol_version: MockPeer::PROTOCOL_VERSION,
+        required_services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+        max_clock_skew: Some(Duration::from_secs(60)),
+        ..HandshakePolicy::default()
+    });
+
+    assert!(handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .is_ok());
+}
+
+const ALL_FEATURES: HandshakeFeatures = HandshakeFeatures {
+    wtxid_relay: true,
+    addr_v2: true,
+};
+
+fn feature_handshake_manager() -> HandshakeManager {
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_version_params(VersionParams::new().with_protocol_version(70016));
+    handshake_manager.set_features(ALL_FEATURES);
+    handshake_manager
+}
+
+#[tokio::test]
+async fn handshake_records_features_announced_by_peer() {
+    let peer = start_mock_peer(MockBehaviour::NegotiateFeatures).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_features(HandshakeFeatures::default());
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    assert_eq!(outcome.peer_features, ALL_FEATURES);
+    assert_eq!(outcome.local_features, HandshakeFeatures::default());
+    assert_eq!(outcome.negotiated_features, HandshakeFeatures::default());
+}
+
+#[tokio::test]
+async fn handshake_announces_sendaddrv2_by_default() {
+    let peer = start_mock_peer(MockBehaviour::NegotiateFeatures).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let addr_v2 = HandshakeFeatures {
+        wtxid_relay: false,
+        addr_v2: true,
+    };
+    assert_eq!(outcome.negotiated_version, 70016);
+    assert_eq!(outcome.local_features, addr_v2);
+    assert_eq!(outcome.negotiated_features, addr_v2);
+    let received = peer.received_messages();
+    assert!(matches!(received.first(), Some(NetworkMessage::Version(v)) if v.version == 70016));
+    assert!(received.contains(&NetworkMessage::SendAddrV2));
+    assert!(!received.contains(&NetworkMessage::WtxidRelay));
+}
+
+#[tokio::test]
+async fn handshake_negotiates_features_with_peer() {
+    let peer = start_mock_peer(MockBehaviour::NegotiateFeatures).await;
+    let mut handshake_manager = feature_handshake_manager();
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(outcome.negotiated_features, ALL_FEATURES);
+    let received = peer.received_messages();
+    let position = |message: &NetworkMessage| received.iter().position(|m| m == message);
+    let verack = position(&NetworkMessage::Verack).expect("verack should be received");
+    assert!(position(&NetworkMessage::WtxidRelay).is_some_and(|i| i < verack));
+    assert!(position(&NetworkMessage::SendAddrV2).is_some_and(|i| i < verack));
+}
+
+#[tokio::test]
+async fn handshake_does_not_announce_features_below_protocol_70016() {
+    let peer = start_mock_peer(MockBehaviour::NegotiateFeatures).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_version_params(VersionParams::new().with_protocol_version(70015));
+    handshake_manager.set_features(ALL_FEATURES);
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(outcome.local_features, HandshakeFeatures::default());
+    assert!(!peer
+        .received_messages()
+        .contains(&NetworkMessage::WtxidRelay));
+}
+
+#[tokio::test]
+async fn inbound_handshake_negotiates_features() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let mut inbound_manager = feature_handshake_manager();
+    let mut outbound_manager = feature_handshake_manager();
+    let (inbound, outbound) = tokio::join!(
+        inbound_manager.accept_handshake(&listener),
+        outbound_manager.establish_handshake(listen_addr)
+    );
+
+    let inbound = inbound.expect("inbound handshake should complete");
+    let outbound = outbound.expect("outbound handshake should complete");
+    assert_eq!(inbound.negotiated_features, ALL_FEATURES);
+    assert_eq!(outbound.negotiated_features, ALL_FEATURES);
+}
+
+#[tokio::test]
+async fn handshake_fails_when_peer_disconnects_mid_stream() {
+    assert_eq!(
+        handshake_error_kind(MockBehaviour::DisconnectMidStream).await,
+        HandshakeErrorKind::PeerDisconnected
+    );
+}
+
+#[tokio::test]
+async fn handshakes_run_concurrently_with_many_peers() {
+    let mut peers = Vec::new();
+    for _ in 0..4 {
+        peers.push(start_mock_peer(MockBehaviour::Handshake).await);
+    }
+    peers.push(start_mock_peer(MockBehaviour::WrongFirstMessage).await);
+    let remotes = peers.iter().map(MockPeer::addr).collect();
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let results = handshake_manager.establish_handshakes(remotes, 2).await;
+
+    assert_eq!(results.len(), 5);
+    assert_eq!(
+        results.iter().filter(|(_, result)| result.is_ok()).count(),
+        4
+    );
+    assert_eq!(handshake_manager.statuses().len(), 5);
+}
+
+#[tokio::test]
+async fn inbound_handshake_completes_with_outbound_manager() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let mut inbound_manager = HandshakeManager::new(Network::Bitcoin);
+    let mut outbound_manager = HandshakeManager::new(Network::Bitcoin);
+    let (inbound, outbound) = tokio::join!(
+        inbound_manager.accept_handshake(&listener),
+        outbound_manager.establish_handshake(listen_addr)
+    );
+
+    let inbound = inbound.expect("inbound handshake should complete");
+    let outbound = outbound.expect("outbound handshake should complete");
+    assert_eq!(inbound.direction, ConnectionDirection::Inbound);
+    assert_eq!(inbound.remote_addr, outbound.local_addr);
+    assert!(inbound_manager
+        .status(&outbound.local_addr)
+        .is_some_and(HandshakeStatus::is_completed));
+}
+
+#[tokio::test]
+async fn inbound_handshakes_run_concurrently_with_a_stalled_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let mut inbound_manager = HandshakeManager::new(Network::Bitcoin);
+    inbound_manager.set_timeouts(HandshakeTimeouts {
+        version: Duration::from_secs(5),
+        ..HandshakeTimeouts::default()
+    });
+    let mut outbound_manager = HandshakeManager::new(Network::Bitcoin);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let accepting = inbound_manager.accept_handshakes(&listener, 2, |remote, result| {
+        sender.send((remote, result)).unwrap()
+    });
+    let outbound = tokio::select! {
+        _ = accepting => {
+            unreachable!("accepting handshakes never ends")
+        }
+        outbound = async {
+            // The first client connects and never sends its `version`
+            let _stalled = TcpStream::connect(listen_addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let outbound = tokio::time::timeout(
+                Duration::from_secs(2),
+                outbound_manager.establish_handshake(listen_addr),
+            )
+            .await
+            .expect("handshake should not wait for the stalled peer");
+            let inbound = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+                .await
+                .expect("inbound handshake should be reported in time");
+            (outbound, inbound)
+        } => outbound,
+    };
+
+    let (outbound, inbound) = outbound;
+    let outbound = outbound.expect("outbound handshake should complete");
+    let (remote, inbound) = inbound.expect("result should be reported");
+    let inbound = inbound.expect("inbound handshake should complete");
+    assert_eq!(remote, outbound.local_addr);
+    assert_eq!(inbound.remote_addr, outbound.local_addr);
+    assert!(receiver.try_recv().is_err());
+    assert!(inbound_manager
+        .status(&outbound.local_addr)
+        .is_some_and(HandshakeStatus::is_completed));
+}
+
+#[tokio::test]
+async fn failed_inbound_handshakes_are_reported_with_the_remote_address() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let mut inbound_manager = HandshakeManager::new(Network::Bitcoin);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let accepting = inbound_manager.accept_handshakes(&listener, 2, |remote, result| {
+        sender.send((remote, result)).unwrap()
+    });
+    let (client_addr, reported) = tokio::select! {
+        _ = accepting => {
+            unreachable!("accepting handshakes never ends")
+        }
+        reported = async {
+            // The client disconnects without sending its `version`
+            let client = TcpStream::connect(listen_addr).await.unwrap();
+            let client_addr = client.local_addr().unwrap();
+            drop(client);
+            let reported = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+                .await
+                .expect("inbound handshake should be reported in time");
+            (client_addr, reported)
+        } => reported,
+    };
+
+    let (remote, result) = reported.expect("result should be reported");
+    assert_eq!(remote, client_addr);
+    assert!(result.is_err());
+    assert!(inbound_manager
+        .status(&client_addr)
+        .is_some_and(|status| !status.is_completed()));
+}
+
+#[tokio::test]
+async fn session_answers_ping_and_receives_other_messages() {
+    let peer = start_mock_peer(MockBehaviour::PingAfterHandshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("message should be received in time")
+        .expect("session should be open");
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(message, NetworkMessage::SendHeaders);
+    assert!(peer
+        .received_messages()
+        .contains(&NetworkMessage::Pong(MockPeer::NONCE)));
+    assert!(handshake_manager
+        .status(&peer.addr())
+        .is_some_and(HandshakeStatus::is_completed));
+}
+
+#[tokio::test]
+async fn session_measures_latency_with_periodic_pings() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_ping_interval(Duration::from_millis(50));
+
+    let session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert!(session.latency().is_some());
+    let pings = peer
+        .received_messages()
+        .iter()
+        .filter(|message| matches!(message, NetworkMessage::Ping(_)))
+        .count();
+    assert!(pings >= 2, "expected periodic pings, received {pings}");
+}
+
+#[tokio::test]
+async fn session_reports_disconnect_when_ping_is_not_answered() {
+    let peer = start_mock_peer(MockBehaviour::IgnorePings).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_ping_interval(Duration::from_millis(50));
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    let report = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("unanswered ping should be reported in time")
+        .expect_err("session should be closed");
+
+    assert_eq!(
+        report.downcast_ref::<HandshakeErrorKind>(),
+        Some(&HandshakeErrorKind::PeerDisconnected)
+    );
+    assert!(session.latency().is_none());
+    assert!(session.recv().await.is_err());
+}
+
+#[tokio::test]
+async fn session_keeps_messages_beyond_a_full_buffer() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    peer.set_gossip(vec!["1.2.3.4:8333"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()]);
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_session_buffer_size(1);
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    for _ in 0..3 {
+        session
+            .send(NetworkMessage::GetAddr)
+            .await
+            .expect("message should be sent");
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..3 {
+        let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+            .await
+            .expect("message should be received in time")
+            .expect("session should be open");
+        assert!(matches!(message, NetworkMessage::AddrV2(addresses) if addresses.len() == 1));
+    }
+}
+
+#[tokio::test]
+async fn session_answers_pings_while_messages_wait_for_recv() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    peer.set_gossip(vec!["1.2.3.4:8333"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()]);
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_ping_interval(Duration::from_millis(100));
+    handshake_manager.set_session_buffer_size(4);
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    for _ in 0..2 {
+        session
+            .send(NetworkMessage::GetAddr)
+            .await
+            .expect("message should be sent");
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let pings = peer
+        .received_messages()
+        .iter()
+        .filter(|message| matches!(message, NetworkMessage::Ping(_)))
+        .count();
+    assert!(pings >= 3, "expected periodic pings, received {pings}");
+    assert!(session.latency().is_some());
+    for _ in 0..2 {
+        let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+            .await
+            .expect("message should be received in time")
+            .expect("session should be open");
+        assert!(matches!(message, NetworkMessage::AddrV2(_)));
+    }
+}
+
+#[tokio::test]
+async fn session_is_not_closed_while_the_buffer_is_full() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    peer.set_gossip(vec!["1.2.3.4:8333"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()]);
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_ping_interval(Duration::from_millis(100));
+    handshake_manager.set_session_buffer_size(1);
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    for _ in 0..3 {
+        session
+            .send(NetworkMessage::GetAddr)
+            .await
+            .expect("message should be sent");
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    for _ in 0..3 {
+        let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+            .await
+            .expect("message should be received in time")
+            .expect("session should be open");
+        assert!(matches!(message, NetworkMessage::AddrV2(_)));
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    session
+        .send(NetworkMessage::GetAddr)
+        .await
+        .expect("session should still be open");
+    let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("message should be received in time")
+        .expect("session should be open");
+    assert!(matches!(message, NetworkMessage::AddrV2(_)));
+}
+
+#[tokio::test]
+async fn session_sends_arbitrary_messages() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    session
+        .send(NetworkMessage::GetAddr)
+        .await
+        .expect("message should be sent");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(peer.received_messages().contains(&NetworkMessage::GetAddr));
+}
+
+#[tokio::test]
+async fn session_reports_disconnect_of_the_peer() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    drop(peer);
+
+    let result = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("disconnect should be reported in time");
+    assert!(result.is_err());
+}
@@ -0,0 +1,163 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use bitcoin::Network;
+use p2p_node_handshake::{
+    AddressBook, AddressBookRecord, AddressRecord, CrawlLimits, CrawlRecord, Crawler,
+    HandshakeManager, HandshakeRecord, MockBehaviour, MockPeer, OutputFormat, OutputRecord,
+    PeerSource, SeedHealth, SeedHealthRecord, SeedRecord,
+};
+use tokio::net::TcpListener;
+
+/// Returns the header line the csv crate derives from the serialised fields of the `record`
+fn csv_header<R: OutputRecord>(record: &R) -> String {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.serialize(record).unwrap();
+    let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    csv.lines().next().unwrap().to_owned()
+}
+
+fn assert_fields<R: OutputRecord>(record: &R) {
+    assert_eq!(csv_header(record), R::FIELDS.join(","));
+
+    let json = serde_json::to_value(record).unwrap();
+    let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+    let mut fields: Vec<_> = R::FIELDS.iter().map(|field| field.to_string()).collect();
+    keys.sort();
+    fields.sort();
+    assert_eq!(keys, fields);
+}
+
+async fn closed_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[test]
+fn output_format_is_parsed() {
+    assert_eq!("text".parse::<OutputFormat>().unwrap(), OutputFormat::Text);
+    assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+    assert_eq!("csv".parse::<OutputFormat>().unwrap(), OutputFormat::Csv);
+    assert!("yaml".parse::<OutputFormat>().is_err());
+    assert_eq!(OutputFormat::default(), OutputFormat::Text);
+}
+
+#[test]
+fn seed_and_address_records_match_their_fields() {
+    assert_fields(&SeedRecord {
+        index: 0,
+        seed: "seed.example.org".to_owned(),
+    });
+    assert_fields(&AddressRecord {
+        index: 0,
+        address: "10.0.0.1:8333".parse().unwrap(),
+        seed: "seed.example.org".to_owned(),
+    });
+}
+
+#[test]
+fn seed_health_records_match_their_fields() {
+    let health = SeedHealth {
+        seed: "seed.example.org".to_owned(),
+        lookup_latency: Duration::from_millis(42),
+        lookup_error: None,
+        addresses: 25,
+        ipv4: 20,
+        ipv6: 5,
+        sampled: 3,
+        reachable: 2,
+        median_latency: Some(Duration::from_millis(120)),
+        versions: BTreeMap::from([(70015, 1), (70016, 1)]),
+    };
+    let record = SeedHealthRecord::new(&health);
+    assert_fields(&record);
+    assert_eq!(record.reachable_percent, Some(66.7));
+    assert_eq!(record.median_latency_ms, Some(120));
+    assert_eq!(record.versions, "70016:1 70015:1");
+
+    let record = SeedHealthRecord::new(&SeedHealth {
+        lookup_error: Some("timed out".to_owned()),
+        addresses: 0,
+        ipv4: 0,
+        ipv6: 0,
+        sampled: 0,
+        reachable: 0,
+        median_latency: None,
+        versions: BTreeMap::new(),
+        ..health
+    });
+    assert_fields(&record);
+    assert_eq!((record.status, record.reachable_percent), ("failed", None));
+}
+
+#[tokio::test]
+async fn handshake_records_match_their_fields() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .expect("mock peer should start");
+    let unreachable = closed_addr().await;
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+
+    let result = manager.establish_handshake(peer.addr()).await;
+    let record = HandshakeRecord::new(peer.addr(), &result);
+    assert_fields(&record);
+    assert_eq!(record.status, "ok");
+    assert_eq!(record.error_kind, None);
+    assert_eq!(record.user_agent.as_deref(), Some(MockPeer::USER_AGENT));
+    assert!(record.total_ms.is_some());
+
+    let result = manager.establish_handshake(unreachable).await;
+    let record = HandshakeRecord::new(unreachable, &result);
+    assert_fields(&record);
+    assert_eq!(record.status, "failed");
+    assert_eq!(record.error_kind.as_deref(), Some("connect_refused"));
+    assert_eq!(record.protocol_version, None);
+
+    let json = serde_json::to_value(&record).unwrap();
+    assert_eq!(json["address"], unreachable.to_string());
+    assert!(json["total_ms"].is_null());
+}
+
+#[tokio::test]
+async fn crawl_and_address_book_records_match_their_fields() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .expect("mock peer should start");
+    let gossiped: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+    peer.set_gossip(vec![gossiped, closed_addr().await]);
+
+    let limits = CrawlLimits {
+        max_depth: 0,
+        ..CrawlLimits::default()
+    };
+    let mut crawler = Crawler::new(HandshakeManager::new(Network::Bitcoin), limits);
+    let map = crawler.crawl(vec![peer.addr()]).await;
+
+    let record = CrawlRecord::new(peer.addr(), &map.peers[&peer.addr()]);
+    assert_fields(&record);
+    assert_eq!(record.status, "ok");
+    assert_eq!(record.source, None);
+    assert_eq!(record.gossiped, 2);
+
+    let record = CrawlRecord::new(gossiped, &map.peers[&gossiped]);
+    assert_eq!(record.status, "not_attempted");
+    assert_eq!(record.source, Some(peer.addr()));
+
+    let path =
+        std::env::temp_dir().join(format!("output_address_book_{}.json", std::process::id()));
+    let mut book = AddressBook::load(&path, Network::Bitcoin).unwrap();
+    book.add(gossiped, PeerSource::Peer(peer.addr()));
+    book.record_statuses(crawler.handshake_manager().statuses());
+
+    let records = AddressBookRecord::list(&book);
+    assert_eq!(records.len(), 2);
+    for record in records.iter() {
+        assert_fields(record);
+    }
+    let record = records
+        .iter()
+        .find(|record| record.address == gossiped)
+        .unwrap();
+    assert_eq!(record.source_kind, "peer");
+    assert_eq!(record.source, Some(peer.addr().to_string()));
+    assert_eq!(record.last_attempt, None);
+}
@@ -0,0 +1,135 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bitcoin::{
+    network::{address::AddrV2, message::NetworkMessage},
+    Network,
+};
+use p2p_node_handshake::{
+    HandshakeError, HandshakeErrorKind, HandshakeManager, MockBehaviour, MockPeer, MockSocks5Proxy,
+    PeerAddr, ProxySettings, ProxyTarget,
+};
+
+// Test vectors of Bitcoin Core, src/test/net_tests.cpp
+const ONION: &str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+const ONION_PUBKEY: &str = "79bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f";
+const I2P: &str = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+const I2P_HASH: &str = "a2894dabaec08c0051a481a6dac88b64f98232ae42d4b6fd2fa81952dfe36a87";
+
+fn bytes(hex: &str) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    bytes
+}
+
+#[test]
+fn overlay_addresses_are_parsed_and_displayed() {
+    let onion: PeerAddr = format!("{ONION}:8333").parse().unwrap();
+    assert_eq!(
+        onion,
+        PeerAddr::TorV3 {
+            pubkey: bytes(ONION_PUBKEY),
+            port: 8333
+        }
+    );
+    assert_eq!(onion.to_string(), format!("{ONION}:8333"));
+    assert_eq!(onion.to_addr_v2(), Some(AddrV2::TorV3(bytes(ONION_PUBKEY))));
+    assert_eq!(onion.socket_addr(), None);
+    assert!(onion.is_overlay());
+
+    let i2p = PeerAddr::from_overlay_host(&I2P.to_uppercase(), 0).unwrap();
+    assert_eq!(
+        i2p,
+        PeerAddr::I2p {
+            hash: bytes(I2P_HASH),
+            port: 0
+        }
+    );
+    assert_eq!(i2p.host(), I2P);
+    assert_eq!(i2p.proxy_target(), ProxyTarget::Domain(I2P.to_owned(), 0));
+
+    let ip: PeerAddr = "[2001:db8::1]:8333".parse().unwrap();
+    assert_eq!(ip, "[2001:db8::1]:8333".parse::<SocketAddr>().unwrap());
+    assert!(!ip.is_overlay());
+}
+
+#[test]
+fn real_onion_services_are_accepted() {
+    // The onion services of the Tor Project and of DuckDuckGo
+    for host in [
+        "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion",
+        "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion",
+    ] {
+        let onion = PeerAddr::from_overlay_host(host, 443)
+            .unwrap_or_else(|| panic!("{host:?} should be a valid onion v3 address"));
+        assert!(matches!(onion, PeerAddr::TorV3 { port: 443, .. }));
+        assert_eq!(onion.host(), host);
+    }
+}
+
+#[test]
+fn invalid_overlay_addresses_are_rejected() {
+    // Wrong checksum, wrong version, truncated, not base32, v2 onion and no port
+    let bad_checksum = ONION.replacen("pg6", "pg7", 1);
+    let bad_version = ONION.replace("cryd.onion", "cryc.onion");
+    for s in [
+        format!("{bad_checksum}:8333"),
+        format!("{bad_version}:8333"),
+        format!("{}:8333", &ONION[1..]),
+        format!("{}:8333", I2P.replacen('u', "1", 1)),
+        "facebookcorewwwi.onion:8333".to_owned(),
+        ONION.to_owned(),
+        "node.example.org:8333".to_owned(),
+    ] {
+        assert!(s.parse::<PeerAddr>().is_err(), "{s:?} should be rejected");
+    }
+}
+
+#[tokio::test]
+async fn onion_peers_are_reached_through_the_proxy() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+    proxy.set_host(ONION, peer.addr());
+    let onion: PeerAddr = format!("{ONION}:8333").parse().unwrap();
+
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+    manager.set_proxy(Some(ProxySettings::new(proxy.addr())));
+    let outcome = manager.establish_handshake(onion.clone()).await.unwrap();
+    assert_eq!(outcome.remote_addr, onion);
+    assert!(outcome.local_features.addr_v2);
+    assert_eq!(
+        proxy.requests()[0].target,
+        ProxyTarget::Domain(ONION.to_owned(), 8333)
+    );
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The onion address does not fit into the version message: Bitcoin Core sends the unspecified address
+    let received = peer.received_messages();
+    let version = received
+        .iter()
+        .find_map(|message| match message {
+            NetworkMessage::Version(version) => Some(version),
+            _ => None,
+        })
+        .expect("the peer should receive version");
+    assert_eq!(
+        (version.receiver.address, version.receiver.port),
+        ([0; 8], 0)
+    );
+    assert!(version.version >= 70016);
+    assert!(received.contains(&NetworkMessage::SendAddrV2));
+}
+
+#[tokio::test]
+async fn overlay_peers_need_a_proxy() {
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+    let i2p: PeerAddr = format!("{I2P}:0").parse().unwrap();
+    let error = manager.establish_handshake(i2p).await.unwrap_err();
+    assert_eq!(
+        HandshakeError::kind(&error),
+        HandshakeErrorKind::ConnectFailed
+    );
+}
@@ -0,0 +1,329 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bitcoin::{
+    network::{constants::ServiceFlags, message::NetworkMessage},
+    Network,
+};
+use clap::Parser;
+use p2p_node_handshake::{
+    Config, DnsSeedManager, DnsSeedSettings, HandshakeError, HandshakeErrorKind, HandshakeManager,
+    HandshakeStatus, MockBehaviour, MockDnsServer, MockPeer, MockSocks5Proxy, PeerAddr,
+    ProxyCredentials, ProxyRequest, ProxySettings, ProxyTarget, Target,
+};
+use tokio::net::TcpListener;
+
+fn credentials(username: &str, password: &str) -> ProxyCredentials {
+    ProxyCredentials {
+        username: username.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+fn proxied_manager(proxy: ProxySettings) -> HandshakeManager {
+    let mut manager = HandshakeManager::new(Network::Bitcoin);
+    manager.set_proxy(Some(proxy));
+    manager
+}
+
+async fn closed_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn handshake_connects_through_the_proxy() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+    let mut manager = proxied_manager(ProxySettings::new(proxy.addr()));
+
+    let outcome = manager.establish_handshake(peer.addr()).await.unwrap();
+    assert_eq!(outcome.remote_addr, peer.addr());
+    assert_eq!(outcome.peer.version, MockPeer::PROTOCOL_VERSION);
+    // The local half of the connection to the proxy
+    assert!(outcome.local_addr.ip().is_loopback() && outcome.local_addr.port() != 0);
+    assert_eq!(
+        proxy.requests(),
+        [ProxyRequest {
+            target: ProxyTarget::Addr(peer.addr()),
+            resolve: false,
+            credentials: None,
+        }]
+    );
+
+    // The version message names the target, not the proxy, and does not reveal the local address
+    let version = peer
+        .received_messages()
+        .into_iter()
+        .find_map(|message| match message {
+            NetworkMessage::Version(version) => Some(version),
+            _ => None,
+        })
+        .expect("the peer should receive version");
+    assert_eq!(version.receiver.socket_addr().unwrap(), peer.addr());
+    assert_eq!(
+        version.sender.socket_addr().unwrap(),
+        "0.0.0.0:0".parse().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn proxy_credentials_are_checked() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let proxy = MockSocks5Proxy::start(Some(credentials("alice", "secret")))
+        .await
+        .unwrap();
+
+    let mut settings = ProxySettings::new(proxy.addr());
+    settings.credentials = Some(credentials("alice", "secret"));
+    let mut manager = proxied_manager(settings.clone());
+    manager.establish_handshake(peer.addr()).await.unwrap();
+    assert_eq!(
+        proxy.requests()[0].credentials,
+        Some(credentials("alice", "secret"))
+    );
+
+    settings.credentials = Some(credentials("alice", "wrong"));
+    let mut manager = proxied_manager(settings);
+    let error = manager.establish_handshake(peer.addr()).await.unwrap_err();
+    assert_eq!(
+        HandshakeError::kind(&error),
+        HandshakeErrorKind::ProxyFailed
+    );
+
+    // Without credentials the proxy accepts none of the offered methods
+    let mut manager = proxied_manager(ProxySettings::new(proxy.addr()));
+    let error = manager.establish_handshake(peer.addr()).await.unwrap_err();
+    assert_eq!(
+        HandshakeError::kind(&error),
+        HandshakeErrorKind::ProxyFailed
+    );
+    assert_eq!(proxy.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn isolated_streams_use_random_credentials_for_every_connection() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+
+    let mut settings = ProxySettings::new(proxy.addr());
+    settings.isolate_streams = true;
+    let mut manager = proxied_manager(settings.clone());
+    manager.establish_handshake(peer.addr()).await.unwrap();
+    manager.establish_handshake(peer.addr()).await.unwrap();
+
+    settings.credentials = Some(credentials("crawler", "secret"));
+    let mut manager = proxied_manager(settings);
+    manager.establish_handshake(peer.addr()).await.unwrap();
+
+    let credentials: Vec<_> = proxy
+        .requests()
+        .into_iter()
+        .map(|request| request.credentials.unwrap())
+        .collect();
+    assert_eq!(credentials.len(), 3);
+    assert_ne!(credentials[0], credentials[1]);
+    assert!(
+        credentials[2].username.starts_with("crawler-"),
+        "{:?}",
+        credentials[2]
+    );
+    assert_eq!(credentials[2].password, "secret");
+}
+
+#[tokio::test]
+async fn proxy_failures_are_classified() {
+    let unreachable = closed_addr().await;
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+
+    let mut manager = proxied_manager(ProxySettings::new(proxy.addr()));
+    let error = manager.establish_handshake(unreachable).await.unwrap_err();
+    assert_eq!(
+        HandshakeError::kind(&error),
+        HandshakeErrorKind::ConnectRefused
+    );
+
+    let mut manager = proxied_manager(ProxySettings::new(closed_addr().await));
+    manager.establish_handshakes(vec![unreachable], 1).await;
+    assert_eq!(
+        manager.status(&unreachable),
+        Some(&HandshakeStatus::Failed(HandshakeErrorKind::ProxyFailed))
+    );
+    assert_eq!(HandshakeErrorKind::ProxyFailed.as_str(), "proxy_failed");
+}
+
+#[tokio::test]
+async fn seed_lookups_go_through_the_proxy() {
+    let server = MockDnsServer::start().await.unwrap();
+    server.set_records(
+        "seed.example.org",
+        vec!["10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+    );
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+    proxy.set_host("seed.example.org", "10.0.0.9:0".parse().unwrap());
+
+    // The nameserver is queried over TCP through the proxy
+    let mut settings = DnsSeedSettings {
+        seeds: vec!["seed.example.org".to_owned()],
+        nameserver: Some(server.addr()),
+        timeout: Duration::from_secs(1),
+        services: ServiceFlags::NONE,
+        proxy: Some(ProxySettings::new(proxy.addr())),
+    };
+    let dsm = DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 0)
+        .await
+        .unwrap();
+    let mut nodes = dsm.active_nodes;
+    nodes.sort();
+    assert_eq!(
+        nodes,
+        [
+            "10.0.0.1:8333".parse().unwrap(),
+            "[2001:db8::1]:8333".parse().unwrap()
+        ]
+    );
+    assert_eq!(proxy.requests()[0].target, ProxyTarget::Addr(server.addr()));
+
+    // Without a nameserver the proxy resolves the seed
+    settings.nameserver = None;
+    let dsm = DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        dsm.active_nodes,
+        ["10.0.0.9:8333".parse::<SocketAddr>().unwrap()]
+    );
+    let request = &proxy.requests()[1];
+    assert!(request.resolve);
+    assert_eq!(
+        request.target,
+        ProxyTarget::Domain("seed.example.org".to_owned(), 0)
+    );
+
+    settings.seeds = vec!["unknown.example.org".to_owned()];
+    assert!(
+        DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 0)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn target_names_are_resolved_by_the_proxy() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+    proxy.set_host("node.example.org", peer.addr());
+    let settings = ProxySettings::new(proxy.addr());
+
+    // Host names are not resolved locally but passed to the proxy
+    let target: Target = "node.example.org:18333".parse().unwrap();
+    let remote = target.resolve_peer(8333, Some(&settings)).await.unwrap();
+    assert_eq!(
+        remote,
+        PeerAddr::Name {
+            host: "node.example.org".to_owned(),
+            port: 18333
+        }
+    );
+    let target: Target = "10.0.0.8".parse().unwrap();
+    assert_eq!(
+        target.resolve_peer(8333, Some(&settings)).await.unwrap(),
+        "10.0.0.8:8333".parse::<SocketAddr>().unwrap()
+    );
+    assert!(proxy.requests().is_empty());
+
+    let outcome = proxied_manager(settings)
+        .establish_handshake(remote.clone())
+        .await
+        .unwrap();
+    assert_eq!(outcome.remote_addr, remote);
+    assert_eq!(
+        proxy.requests(),
+        [ProxyRequest {
+            target: ProxyTarget::Domain("node.example.org".to_owned(), 18333),
+            resolve: false,
+            credentials: None,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn resolve_is_reported_as_tor_only() {
+    let proxy = MockSocks5Proxy::start(None).await.unwrap();
+    proxy.set_host("node.example.org", "10.0.0.7:0".parse().unwrap());
+    let settings = ProxySettings::new(proxy.addr());
+
+    assert_eq!(
+        settings.resolve("node.example.org").await.unwrap(),
+        "10.0.0.7".parse::<IpAddr>().unwrap()
+    );
+    assert!(settings.resolve("unknown.example.org").await.is_err());
+
+    proxy.set_resolve_supported(false);
+    let report = settings.resolve("node.example.org").await.unwrap_err();
+    assert_eq!(
+        report.downcast_ref::<HandshakeErrorKind>(),
+        Some(&HandshakeErrorKind::ProxyFailed)
+    );
+    assert!(format!("{report:?}").contains("need Tor"));
+}
+
+#[test]
+fn proxy_options_configure_the_proxy_settings() {
+    let parse = |args: &[&str]| {
+        Config::try_parse_from(std::iter::once("p2p-node-handshake").chain(args.iter().copied()))
+    };
+
+    let config = parse(&["seeds", "list"]).unwrap();
+    assert_eq!(config.proxy.proxy_settings(), None);
+    assert_eq!(config.dns_settings().unwrap().proxy, None);
+
+    let config = parse(&[
+        "--proxy",
+        "127.0.0.1",
+        "scan",
+        "all",
+        "--proxy-user",
+        "alice",
+        "--proxy-password",
+        "secret",
+    ])
+    .unwrap();
+    let settings = config.proxy.proxy_settings().unwrap();
+    assert_eq!(settings.addr, "127.0.0.1:9050".parse().unwrap());
+    assert_eq!(settings.credentials, Some(credentials("alice", "secret")));
+    assert!(!settings.isolate_streams);
+    assert_eq!(config.dns_settings().unwrap().proxy, Some(settings));
+
+    let config = parse(&["scan", "all", "--proxy", "[::1]:1080", "--proxy-isolate"]).unwrap();
+    let settings = config.proxy.proxy_settings().unwrap();
+    assert_eq!(settings.addr, "[::1]:1080".parse().unwrap());
+    assert_eq!(settings.credentials, None);
+    assert!(settings.isolate_streams);
+
+    let config = parse(&[
+        "scan",
+        "all",
+        "--proxy",
+        "127.0.0.1:9150",
+        "--proxy-password",
+        "secret",
+    ])
+    .unwrap();
+    assert_eq!(config.proxy.proxy_settings().unwrap().credentials, None);
+    let config = parse(&["scan", "all", "--proxy-user", "alice"]).unwrap();
+    assert_eq!(config.proxy.proxy_settings(), None);
+
+    let error = parse(&["scan", "all", "--proxy", "localhost"]).unwrap_err();
+    assert!(error.to_string().contains("--proxy"), "{error}");
+}
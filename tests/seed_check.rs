@@ -0,0 +1,120 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bitcoin::Network;
+use error_stack::Report;
+use p2p_node_handshake::{
+    DnsLookupError, HandshakeManager, MockBehaviour, MockPeer, OutputRecord, SeedChecker,
+    SeedHealthRecord, SeedLookup,
+};
+use tokio::net::TcpListener;
+
+async fn closed_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn lookup(seed: &str, nodes: Vec<SocketAddr>) -> SeedLookup {
+    SeedLookup {
+        seed: seed.to_owned(),
+        latency: Duration::from_millis(20),
+        result: Ok(nodes),
+    }
+}
+
+#[tokio::test]
+async fn check_reports_addresses_reachability_latency_and_versions() {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let other_peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    let unreachable = closed_addr().await;
+    let ipv6 = "[::1]:1".parse().unwrap();
+
+    let lookups = vec![
+        lookup("good.example.org", vec![peer.addr(), other_peer.addr()]),
+        lookup("mixed.example.org", vec![peer.addr(), unreachable, ipv6]),
+        SeedLookup {
+            seed: "down.example.org".to_owned(),
+            latency: Duration::from_millis(500),
+            result: Err(Report::new(DnsLookupError)
+                .attach_printable("lookup of down.example.org timed out")),
+        },
+        lookup("empty.example.org", Vec::new()),
+    ];
+    let mut checker = SeedChecker::new(HandshakeManager::new(Network::Bitcoin), 10, 4);
+    let health = checker.check(&lookups).await;
+
+    let seeds: Vec<_> = health.iter().map(|health| health.seed.as_str()).collect();
+    assert_eq!(
+        seeds,
+        [
+            "good.example.org",
+            "mixed.example.org",
+            "down.example.org",
+            "empty.example.org"
+        ]
+    );
+
+    let good = &health[0];
+    assert_eq!((good.addresses, good.ipv4, good.ipv6), (2, 2, 0));
+    assert_eq!((good.sampled, good.reachable), (2, 2));
+    assert_eq!(good.reachable_percent(), Some(100.0));
+    assert!(good.median_latency.is_some());
+    assert_eq!(good.versions.get(&MockPeer::PROTOCOL_VERSION), Some(&2));
+    assert_eq!(good.lookup_error, None);
+
+    let mixed = &health[1];
+    assert_eq!((mixed.addresses, mixed.ipv4, mixed.ipv6), (3, 2, 1));
+    assert_eq!((mixed.sampled, mixed.reachable), (3, 1));
+    assert_eq!(mixed.versions.values().sum::<usize>(), 1);
+
+    let down = &health[2];
+    assert_eq!(
+        down.lookup_error.as_deref(),
+        Some("lookup of down.example.org timed out")
+    );
+    assert_eq!(
+        (down.addresses, down.sampled, down.reachable_percent()),
+        (0, 0, None)
+    );
+    assert_eq!(down.median_latency, None);
+
+    assert_eq!(health[3].lookup_error, None);
+    assert_eq!(health[3].reachable_percent(), None);
+
+    // The peer returned by two seeds is handshaked once
+    assert_eq!(checker.handshake_manager().statuses().len(), 4);
+
+    let record = SeedHealthRecord::new(mixed);
+    assert_eq!(record.status, "ok");
+    assert_eq!(record.reachable_percent, Some(33.3));
+    assert_eq!(record.versions, format!("{}:1", MockPeer::PROTOCOL_VERSION));
+    assert!(record.text_row().starts_with("mixed.example.org"));
+    let record = SeedHealthRecord::new(down);
+    assert_eq!(record.status, "failed");
+    assert!(record.text_row().ends_with("timed out"));
+}
+
+#[tokio::test]
+async fn check_handshakes_with_a_sample_of_every_seed() {
+    let mut peers = Vec::new();
+    for _ in 0..5 {
+        peers.push(
+            MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+                .await
+                .unwrap(),
+        );
+    }
+    let nodes: Vec<_> = peers.iter().map(MockPeer::addr).collect();
+
+    let mut checker = SeedChecker::new(HandshakeManager::new(Network::Bitcoin), 2, 4);
+    let health = checker.check(&[lookup("seed.example.org", nodes)]).await;
+
+    assert_eq!(
+        (health[0].addresses, health[0].sampled, health[0].reachable),
+        (5, 2, 2)
+    );
+    assert_eq!(checker.handshake_manager().statuses().len(), 2);
+}
@@ -0,0 +1,239 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bitcoin::{network::constants::ServiceFlags, Network};
+use p2p_node_handshake::{
+    DnsLookupError, DnsSeedManager, DnsSeedSettings, DnsSeeder, HandshakeErrorKind,
+    HandshakeManager, HandshakeOutcome, HandshakeStatus, MockBehaviour, MockPeer, SeederSettings,
+};
+
+const ZONE: &str = "seed.example.org";
+
+/// Returns the outcome of a handshake with a mock peer, recorded by the tests under other addresses
+async fn mock_outcome() -> HandshakeOutcome {
+    let peer = MockPeer::start(Network::Bitcoin, MockBehaviour::Handshake)
+        .await
+        .unwrap();
+    HandshakeManager::new(Network::Bitcoin)
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake with the mock peer should complete")
+}
+
+fn completed(outcome: &HandshakeOutcome, services: ServiceFlags) -> HandshakeStatus {
+    let mut outcome = outcome.clone();
+    outcome.peer.services = services;
+    HandshakeStatus::Completed(Box::new(outcome))
+}
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+async fn start_seeder(max_age: Duration) -> DnsSeeder {
+    let settings = SeederSettings {
+        port: Some(8333),
+        max_age,
+        ..SeederSettings::new(ZONE, addr("127.0.0.1:0"))
+    };
+    DnsSeeder::start(settings)
+        .await
+        .expect("seeder should start")
+}
+
+/// Resolves `name` at the seeder like a DNS seed
+async fn query(
+    seeder: &DnsSeeder,
+    name: &str,
+) -> error_stack::Result<Vec<SocketAddr>, DnsLookupError> {
+    let settings = DnsSeedSettings {
+        seeds: vec![name.to_owned()],
+        nameserver: Some(seeder.addr()),
+        timeout: Duration::from_secs(1),
+        services: ServiceFlags::NONE,
+        proxy: None,
+    };
+    DnsSeedManager::new_with_dns(Network::Bitcoin, &settings, name)
+        .await
+        .map(|dsm| {
+            let mut nodes = dsm.active_nodes;
+            nodes.sort();
+            nodes
+        })
+}
+
+#[tokio::test]
+async fn seeder_serves_the_verified_nodes_of_the_zone() {
+    let outcome = mock_outcome().await;
+    let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+    let seeder = start_seeder(Duration::from_secs(60)).await;
+    seeder.record_status(addr("10.0.0.1:8333"), &completed(&outcome, services));
+    seeder.record_status(
+        addr("10.0.0.2:8333"),
+        &completed(&outcome, ServiceFlags::NETWORK),
+    );
+    seeder.record_status(addr("[2001:db8::1]:8333"), &completed(&outcome, services));
+    // Not the default port of the network, can not be served over DNS
+    seeder.record_status(addr("10.0.0.3:18333"), &completed(&outcome, services));
+    seeder.record_status(
+        addr("10.0.0.4:8333"),
+        &HandshakeStatus::Failed(HandshakeErrorKind::ConnectRefused),
+    );
+
+    assert_eq!(
+        seeder.good_nodes(),
+        [
+            addr("10.0.0.1:8333"),
+            addr("10.0.0.2:8333"),
+            addr("[2001:db8::1]:8333")
+        ]
+    );
+    assert_eq!(query(&seeder, ZONE).await.unwrap(), seeder.good_nodes());
+    assert_eq!(query(&seeder, "SEED.example.org.").await.unwrap().len(), 3);
+
+    // A node that fails the next handshake is not served any more
+    seeder.record_status(
+        addr("10.0.0.2:8333"),
+        &HandshakeStatus::Failed(HandshakeErrorKind::ConnectTimeout),
+    );
+    assert_eq!(
+        query(&seeder, ZONE).await.unwrap(),
+        [addr("10.0.0.1:8333"), addr("[2001:db8::1]:8333")]
+    );
+}
+
+#[tokio::test]
+async fn seeder_filters_the_nodes_by_the_requested_services() {
+    let outcome = mock_outcome().await;
+    let seeder = start_seeder(Duration::from_secs(60)).await;
+    seeder.record_status(
+        addr("10.0.0.1:8333"),
+        &completed(&outcome, ServiceFlags::NETWORK | ServiceFlags::WITNESS),
+    );
+    seeder.record_status(
+        addr("10.0.0.2:8333"),
+        &completed(&outcome, ServiceFlags::NETWORK),
+    );
+
+    assert_eq!(
+        query(&seeder, "x1.seed.example.org").await.unwrap().len(),
+        2
+    );
+    assert_eq!(
+        query(&seeder, "x9.seed.example.org").await.unwrap(),
+        [addr("10.0.0.1:8333")]
+    );
+    assert!(query(&seeder, "x400.seed.example.org")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // The services of the DNS seed settings are asked for with the same names
+    let settings = DnsSeedSettings {
+        seeds: vec![ZONE.to_owned()],
+        nameserver: Some(seeder.addr()),
+        timeout: Duration::from_secs(1),
+        services: ServiceFlags::WITNESS,
+        proxy: None,
+    };
+    let dsm = DnsSeedManager::new_with_dns_index(Network::Bitcoin, &settings, 0)
+        .await
+        .unwrap();
+    assert_eq!(dsm.active_nodes, [addr("10.0.0.1:8333")]);
+}
+
+#[tokio::test]
+async fn seeder_rejects_unknown_names() {
+    let seeder = start_seeder(Duration::from_secs(60)).await;
+
+    let error = query(&seeder, "www.seed.example.org").await.unwrap_err();
+    assert!(
+        format!("{error:?}").contains("response code 3"),
+        "{error:?}"
+    );
+    let error = query(&seeder, "xz.seed.example.org").await.unwrap_err();
+    assert!(
+        format!("{error:?}").contains("response code 3"),
+        "{error:?}"
+    );
+    let error = query(&seeder, "other.example.org").await.unwrap_err();
+    assert!(
+        format!("{error:?}").contains("response code 5"),
+        "{error:?}"
+    );
+    let error = query(&seeder, "example.org").await.unwrap_err();
+    assert!(
+        format!("{error:?}").contains("response code 5"),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn seeder_stops_serving_nodes_that_were_not_verified_recently() {
+    let outcome = mock_outcome().await;
+    let seeder = start_seeder(Duration::from_millis(200)).await;
+    seeder.record_status(
+        addr("10.0.0.1:8333"),
+        &completed(&outcome, ServiceFlags::NETWORK),
+    );
+    assert_eq!(query(&seeder, ZONE).await.unwrap(), [addr("10.0.0.1:8333")]);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(seeder.good_nodes().is_empty());
+    assert!(query(&seeder, ZONE).await.unwrap().is_empty());
+
+    seeder.record_status(
+        addr("10.0.0.1:8333"),
+        &completed(&outcome, ServiceFlags::NETWORK),
+    );
+    assert_eq!(seeder.good_nodes(), [addr("10.0.0.1:8333")]);
+}
+
+#[tokio::test]
+async fn seeder_serves_the_peers_verified_by_a_handshake_manager() {
+    let peers = [
+        MockPeer::start(Network::Regtest, MockBehaviour::Handshake)
+            .await
+            .unwrap(),
+        MockPeer::start(Network::Regtest, MockBehaviour::WrongMagic)
+            .await
+            .unwrap(),
+    ];
+    let seeder = DnsSeeder::start(SeederSettings::new(ZONE, addr("127.0.0.1:0")))
+        .await
+        .unwrap();
+
+    let mut manager = HandshakeManager::new(Network::Regtest);
+    manager
+        .establish_handshakes(peers.iter().map(MockPeer::addr).collect(), 2)
+        .await;
+    seeder.record_statuses(manager.statuses());
+
+    assert_eq!(seeder.good_nodes(), [peers[0].addr()]);
+}
+
+#[tokio::test]
+async fn seeder_keeps_the_most_recently_verified_nodes() {
+    let outcome = mock_outcome().await;
+    let settings = SeederSettings {
+        max_nodes: 2,
+        ..SeederSettings::new(ZONE, addr("127.0.0.1:0"))
+    };
+    let seeder = DnsSeeder::start(settings).await.unwrap();
+    let status = completed(&outcome, ServiceFlags::NETWORK);
+    for node in ["10.0.0.1:8333", "10.0.0.2:8333", "10.0.0.3:8333"] {
+        seeder.record_status(addr(node), &status);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        seeder.good_nodes(),
+        [addr("10.0.0.2:8333"), addr("10.0.0.3:8333")]
+    );
+
+    // Verifying a known node again does not drop another one
+    seeder.record_status(addr("10.0.0.2:8333"), &status);
+    seeder.record_status(addr("10.0.0.1:8333"), &status);
+    assert_eq!(
+        seeder.good_nodes(),
+        [addr("10.0.0.1:8333"), addr("10.0.0.2:8333")]
+    );
+}
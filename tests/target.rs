@@ -0,0 +1,134 @@
+use std::net::{IpAddr, SocketAddr};
+
+use p2p_node_handshake::{ProxySettings, Target, TargetHost};
+
+fn target(s: &str) -> Target {
+    s.parse()
+        .unwrap_or_else(|e| panic!("{s:?} should be a valid target: {e:?}"))
+}
+
+fn ip(s: &str) -> TargetHost {
+    TargetHost::Ip(s.parse::<IpAddr>().unwrap())
+}
+
+#[test]
+fn targets_are_parsed() {
+    let cases = [
+        ("1.2.3.4", ip("1.2.3.4"), None),
+        ("1.2.3.4:18333", ip("1.2.3.4"), Some(18333)),
+        ("2001:db8::1", ip("2001:db8::1"), None),
+        ("[2001:db8::1]", ip("2001:db8::1"), None),
+        ("[2001:db8::1]:8333", ip("2001:db8::1"), Some(8333)),
+        ("::1", ip("::1"), None),
+        ("localhost", TargetHost::Name("localhost".to_owned()), None),
+        (
+            "node.example.org:8333",
+            TargetHost::Name("node.example.org".to_owned()),
+            Some(8333),
+        ),
+    ];
+    for (s, host, port) in cases {
+        assert_eq!(target(s), Target { host, port }, "{s:?}");
+    }
+}
+
+#[test]
+fn invalid_targets_are_rejected() {
+    for s in [
+        "",
+        ":8333",
+        "node.example.org:",
+        "node.example.org:70000",
+        "1.2.3.4:port",
+        "node example",
+        "[::1",
+    ] {
+        assert!(s.parse::<Target>().is_err(), "{s:?} should be rejected");
+    }
+}
+
+#[test]
+fn targets_are_displayed_as_parsed() {
+    for s in [
+        "1.2.3.4",
+        "1.2.3.4:8333",
+        "2001:db8::1",
+        "[2001:db8::1]:8333",
+        "localhost",
+        "localhost:8333",
+    ] {
+        assert_eq!(target(s).to_string(), s);
+    }
+}
+
+#[tokio::test]
+async fn targets_without_port_use_the_default_port() {
+    let default_port = 18444;
+    assert_eq!(
+        target("1.2.3.4").resolve(default_port).await.unwrap(),
+        "1.2.3.4:18444".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(
+        target("2001:db8::1").socket_addr(default_port),
+        Some("[2001:db8::1]:18444".parse().unwrap())
+    );
+    assert_eq!(target("localhost").socket_addr(default_port), None);
+
+    let resolved = target("localhost:1234")
+        .resolve(default_port)
+        .await
+        .unwrap();
+    assert!(resolved.ip().is_loopback());
+    assert_eq!(resolved.port(), 1234);
+}
+
+#[test]
+fn targets_file_skips_comments_and_reports_bad_lines() {
+    let path = std::env::temp_dir().join(format!("targets_{}.txt", std::process::id()));
+
+    std::fs::write(&path, "# seeds\n1.2.3.4\n\n  [::1]:8333  \nlocalhost\n").unwrap();
+    let targets = Target::read_file(&path).unwrap();
+    assert_eq!(
+        targets,
+        vec![target("1.2.3.4"), target("[::1]:8333"), target("localhost")]
+    );
+
+    std::fs::write(&path, "1.2.3.4\nnot a target\n").unwrap();
+    let error = Target::read_file(&path).unwrap_err();
+    assert!(format!("{error:?}").contains(&format!("{}:2", path.display())));
+
+    let _ = std::fs::remove_file(&path);
+    assert!(Target::read_file(&path).is_err());
+}
+
+#[tokio::test]
+async fn overlay_targets_are_parsed_and_reached_only_through_a_proxy() {
+    let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+    let i2p = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+    for s in [onion.to_owned(), format!("{i2p}:0")] {
+        assert_eq!(target(&s).to_string(), s);
+    }
+    assert!(matches!(target(onion).host, TargetHost::TorV3(_)));
+    assert!(matches!(target(i2p).host, TargetHost::I2p(_)));
+    assert!(format!("{}.onion", &onion[1..56])
+        .parse::<Target>()
+        .is_err());
+    assert!("example.i2p".parse::<Target>().is_err());
+
+    let peer = target(onion).peer_addr(8333).unwrap();
+    assert_eq!(peer.to_string(), format!("{onion}:8333"));
+    assert!(target(onion).resolve(8333).await.is_err());
+    assert!(target(onion).resolve_peer(8333, None).await.is_err());
+    let proxy = ProxySettings::new("127.0.0.1:9050".parse().unwrap());
+    assert_eq!(
+        target(onion)
+            .resolve_peer(8333, Some(&proxy))
+            .await
+            .unwrap(),
+        peer
+    );
+    assert_eq!(
+        target("1.2.3.4").resolve_peer(8333, None).await.unwrap(),
+        "1.2.3.4:8333".parse::<SocketAddr>().unwrap()
+    );
+}
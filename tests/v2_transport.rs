@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use bitcoin::{network::message::NetworkMessage, Network};
+use clap::Parser;
+use p2p_node_handshake::{
+    Config, HandshakeFeatures, HandshakeManager, HandshakeRecord, MockBehaviour, MockPeer,
+    TransportVersion, VersionParams,
+};
+use tokio::net::TcpListener;
+
+async fn start_mock_peer(behaviour: MockBehaviour) -> MockPeer {
+    MockPeer::start(Network::Bitcoin, behaviour)
+        .await
+        .expect("mock peer should start")
+}
+
+fn v2_handshake_manager() -> HandshakeManager {
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    handshake_manager.set_v2_transport(true);
+    handshake_manager
+}
+
+#[tokio::test]
+async fn handshake_uses_v2_transport_with_mock_peer() {
+    let peer = start_mock_peer(MockBehaviour::NegotiateFeatures).await;
+    let mut handshake_manager = v2_handshake_manager();
+    handshake_manager.set_version_params(VersionParams::new().with_protocol_version(70016));
+    handshake_manager.set_features(HandshakeFeatures {
+        wtxid_relay: true,
+        addr_v2: true,
+    });
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(outcome.transport, TransportVersion::V2);
+    assert_eq!(outcome.peer.user_agent, MockPeer::USER_AGENT);
+    assert!(outcome.negotiated_features.wtxid_relay && outcome.negotiated_features.addr_v2);
+    let received = peer.received_messages();
+    assert!(matches!(received.first(), Some(NetworkMessage::Version(_))));
+    assert!(received.contains(&NetworkMessage::SendAddrV2));
+    assert!(received.contains(&NetworkMessage::Verack));
+    assert_eq!(HandshakeRecord::completed(&outcome).transport, Some("v2"));
+}
+
+#[tokio::test]
+async fn handshake_falls_back_to_v1_when_peer_does_not_support_v2() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    peer.set_v2_transport(false);
+    let mut handshake_manager = v2_handshake_manager();
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete over v1");
+
+    assert_eq!(outcome.transport, TransportVersion::V1);
+    assert_eq!(outcome.peer.nonce, MockPeer::NONCE);
+    assert_eq!(HandshakeRecord::completed(&outcome).transport, Some("v1"));
+}
+
+#[tokio::test]
+async fn handshake_uses_v1_transport_by_default() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Bitcoin);
+    assert!(!handshake_manager.v2_transport());
+
+    let outcome = handshake_manager
+        .establish_handshake(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    assert_eq!(outcome.transport, TransportVersion::V1);
+}
+
+#[tokio::test]
+async fn v2_handshake_fails_on_wrong_network() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    let mut handshake_manager = HandshakeManager::new(Network::Testnet);
+    handshake_manager.set_v2_transport(true);
+    let mut timeouts = handshake_manager.timeouts();
+    timeouts.connect = Duration::from_millis(500);
+    handshake_manager.set_timeouts(timeouts);
+
+    // The network magic is a part of the key derivation, the garbage terminators never match
+    let result = handshake_manager.establish_handshake(peer.addr()).await;
+
+    assert!(result.is_err());
+    assert!(peer.received_messages().is_empty());
+}
+
+#[tokio::test]
+async fn session_exchanges_messages_over_v2_transport() {
+    let peer = start_mock_peer(MockBehaviour::PingAfterHandshake).await;
+    peer.set_gossip(vec!["1.2.3.4:8333"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()]);
+    let mut handshake_manager = v2_handshake_manager();
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    assert_eq!(session.outcome().transport, TransportVersion::V2);
+
+    // `sendheaders` has no short message ID and is sent with its full command
+    let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("message should be received in time")
+        .expect("session should be open");
+    assert_eq!(message, NetworkMessage::SendHeaders);
+
+    session
+        .send(NetworkMessage::GetAddr)
+        .await
+        .expect("message should be sent");
+    let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("message should be received in time")
+        .expect("session should be open");
+    assert!(matches!(message, NetworkMessage::AddrV2(addresses) if addresses.len() == 1));
+    assert!(peer
+        .received_messages()
+        .contains(&NetworkMessage::Pong(MockPeer::NONCE)));
+}
+
+#[tokio::test]
+async fn session_skips_messages_with_unknown_short_ids() {
+    let peer = start_mock_peer(MockBehaviour::UnknownMessageAfterHandshake).await;
+    let mut handshake_manager = v2_handshake_manager();
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+    assert_eq!(session.outcome().transport, TransportVersion::V2);
+
+    let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+        .await
+        .expect("message should be received in time")
+        .expect("session should stay open");
+    assert_eq!(message, NetworkMessage::SendHeaders);
+}
+
+#[tokio::test]
+async fn session_keeps_exchanging_messages_after_the_rekey() {
+    let peer = start_mock_peer(MockBehaviour::Handshake).await;
+    peer.set_gossip(vec!["1.2.3.4:8333"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()]);
+    let mut handshake_manager = v2_handshake_manager();
+
+    let mut session = handshake_manager
+        .establish_session(peer.addr())
+        .await
+        .expect("handshake should complete");
+
+    // Both directions rekey their ciphers after 224 packets
+    for _ in 0..300 {
+        session
+            .send(NetworkMessage::GetAddr)
+            .await
+            .expect("message should be sent");
+        let message = tokio::time::timeout(Duration::from_secs(1), session.recv())
+            .await
+            .expect("message should be received in time")
+            .expect("session should be open");
+        assert!(matches!(message, NetworkMessage::AddrV2(addresses) if addresses.len() == 1));
+    }
+}
+
+#[tokio::test]
+async fn inbound_handshake_detects_the_transport() {
+    for (outbound_v2, transport) in [(true, TransportVersion::V2), (false, TransportVersion::V1)] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let mut inbound_manager = v2_handshake_manager();
+        let mut outbound_manager = HandshakeManager::new(Network::Bitcoin);
+        outbound_manager.set_v2_transport(outbound_v2);
+        let (inbound, outbound) = tokio::join!(
+            inbound_manager.accept_handshake(&listener),
+            outbound_manager.establish_handshake(listen_addr)
+        );
+
+        let inbound = inbound.expect("inbound handshake should complete");
+        let outbound = outbound.expect("outbound handshake should complete");
+        assert_eq!(inbound.transport, transport);
+        assert_eq!(outbound.transport, transport);
+    }
+}
+
+#[test]
+fn v2_transport_option_configures_the_handshake_manager() {
+    let parse = |args: &[&str]| {
+        Config::try_parse_from(std::iter::once("p2p-node-handshake").chain(args.iter().copied()))
+            .expect("arguments should be valid")
+    };
+
+    let config = parse(&["scan", "all", "--v2-transport"]);
+    assert!(config
+        .handshake
+        .handshake_manager(config.network)
+        .v2_transport());
+    let config = parse(&["scan", "all"]);
+    assert!(!config
+        .handshake
+        .handshake_manager(config.network)
+        .v2_transport());
+}